@@ -0,0 +1,214 @@
+//dense voting grid for the light location: every cluster fit votes for its center,
+//the smoothed grid's peak is refined to sub-pixel precision
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeakRefinement{
+    Parabolic,
+    Centroid
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AccumulatorParams{
+    pub bin_size: f32,
+    //gaussian kernel sigma, in bins; 0 turns smoothing off
+    pub smoothing: f32,
+    pub refinement: PeakRefinement
+}
+
+impl AccumulatorParams{
+    pub fn init() -> Self{
+        return AccumulatorParams{
+            bin_size: 1.0,
+            smoothing: 1.0,
+            refinement: PeakRefinement::Parabolic
+        };
+    }
+}
+
+//the coarse pass looks for the peak region over the whole extent, the fine one only around it
+static COARSE_FACTOR: f64 = 16.0;
+static FINE_WINDOW: f64 = 2.0;
+
+
+struct Grid{
+    bins: ndarray::Array2::<f64>,
+    origin: (f64, f64),
+    bin_size: f64
+}
+
+impl Grid{
+    fn init(min: (f64, f64), max: (f64, f64), bin_size: f64) -> Self{
+        let nx = ((max.0 - min.0) / bin_size).ceil().max(1.0) as usize + 1;
+        let ny = ((max.1 - min.1) / bin_size).ceil().max(1.0) as usize + 1;
+        return Grid{
+            bins: ndarray::Array2::<f64>::zeros((nx, ny)),
+            origin: min,
+            bin_size
+        };
+    }
+
+    //bilinear splat, so a vote between bin centers isn't rounded away
+    fn vote(&mut self, point: (f64, f64), weight: f64){
+        let fx = (point.0 - self.origin.0) / self.bin_size;
+        let fy = (point.1 - self.origin.1) / self.bin_size;
+        if fx < 0.0 || fy < 0.0{
+            return;
+        }
+        let (ix, iy) = (fx.floor() as usize, fy.floor() as usize);
+        let (tx, ty) = (fx - ix as f64, fy - iy as f64);
+        let shape = self.bins.shape().to_vec();
+        for (dx, wx) in [(0, 1.0 - tx), (1, tx)]{
+            for (dy, wy) in [(0, 1.0 - ty), (1, ty)]{
+                if ix + dx < shape[0] && iy + dy < shape[1]{
+                    self.bins[[ix + dx, iy + dy]] += weight * wx * wy;
+                }
+            }
+        }
+    }
+
+    //separable gaussian blur
+    fn smooth(&mut self, sigma: f64){
+        if sigma <= 0.0{
+            return;
+        }
+        let radius = (3.0 * sigma).ceil() as i64;
+        let kernel: Vec<f64> = (-radius..=radius).map(|i| (-(i * i) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+        for axis in 0..2{
+            let src = self.bins.clone();
+            let shape = src.shape().to_vec();
+            for ((i, j), v) in self.bins.indexed_iter_mut(){
+                let mut acc = 0.0;
+                for (k, w) in kernel.iter().enumerate(){
+                    let off = k as i64 - radius;
+                    let (si, sj) = if axis == 0 {(i as i64 + off, j as i64)} else {(i as i64, j as i64 + off)};
+                    if si >= 0 && sj >= 0 && (si as usize) < shape[0] && (sj as usize) < shape[1]{
+                        acc += w * src[[si as usize, sj as usize]];
+                    }
+                }
+                *v = acc;
+            }
+        }
+    }
+
+    fn argmax(&self) -> Option<(usize, usize)>{
+        let mut best = None;
+        let mut best_v = 0.0;
+        for ((i, j), v) in self.bins.indexed_iter(){
+            if *v > best_v{
+                best_v = *v;
+                best = Some((i, j));
+            }
+        }
+        return best;
+    }
+
+    fn bin_center(&self, fx: f64, fy: f64) -> (f64, f64){
+        return (self.origin.0 + fx * self.bin_size, self.origin.1 + fy * self.bin_size);
+    }
+
+    fn get(&self, i: i64, j: i64) -> f64{
+        if i < 0 || j < 0 || i as usize >= self.bins.shape()[0] || j as usize >= self.bins.shape()[1]{
+            return 0.0;
+        }
+        return self.bins[[i as usize, j as usize]];
+    }
+
+    fn refine(&self, peak: (usize, usize), refinement: PeakRefinement, radius: i64) -> (f64, f64){
+        let (i, j) = (peak.0 as i64, peak.1 as i64);
+        match refinement{
+            PeakRefinement::Parabolic => {
+                let parabola = |l: f64, c: f64, r: f64| -> f64 {
+                    let down = l - 2.0 * c + r;
+                    if down.abs() < 1e-300 {0.0} else {(0.5 * (l - r) / down).clamp(-0.5, 0.5)}
+                };
+                let c = self.get(i, j);
+                let dx = parabola(self.get(i - 1, j), c, self.get(i + 1, j));
+                let dy = parabola(self.get(i, j - 1), c, self.get(i, j + 1));
+                return self.bin_center(i as f64 + dx, j as f64 + dy);
+            }
+            PeakRefinement::Centroid => {
+                let (mut sx, mut sy, mut sw) = (0.0, 0.0, 0.0);
+                for di in -radius..=radius{
+                    for dj in -radius..=radius{
+                        let w = self.get(i + di, j + dj);
+                        sx += w * (i + di) as f64;
+                        sy += w * (j + dj) as f64;
+                        sw += w;
+                    }
+                }
+                return self.bin_center(sx / sw, sy / sw);
+            }
+        }
+    }
+}
+
+
+//location of the densest vote region, None when nothing voted inside the extent
+pub fn find_peak(votes: &[((f64, f64), f64)], extent: ((f64, f64), (f64, f64)), params: &AccumulatorParams) -> Option<(f64, f64)>{
+    let votes: Vec<&((f64, f64), f64)> = votes.iter().filter(|v| v.1 > 0.0 && v.0.0.is_finite() && v.0.1.is_finite()).collect();
+    if votes.is_empty(){
+        return None;
+    }
+    let bin_size = (params.bin_size as f64).max(1e-3);
+    let sigma = params.smoothing as f64;
+    let coarse_bin = bin_size * COARSE_FACTOR;
+    let mut coarse = Grid::init(extent.0, extent.1, coarse_bin);
+    for v in votes.iter(){
+        coarse.vote(v.0, v.1);
+    }
+    coarse.smooth(sigma.max(1.0));
+    let coarse_peak = coarse.argmax()?;
+    let center = coarse.bin_center(coarse_peak.0 as f64, coarse_peak.1 as f64);
+    let half = coarse_bin * FINE_WINDOW;
+    let mut fine = Grid::init((center.0 - half, center.1 - half), (center.0 + half, center.1 + half), bin_size);
+    for v in votes.iter(){
+        fine.vote(v.0, v.1);
+    }
+    fine.smooth(sigma);
+    let fine_peak = fine.argmax()?;
+    return Some(fine.refine(fine_peak, params.refinement, sigma.ceil().max(1.0) as i64));
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //rings of votes around the centre, plus a heavier but scattered bunch elsewhere
+    fn votes(center: (f64, f64)) -> Vec<((f64, f64), f64)>{
+        let mut votes: Vec<((f64, f64), f64)> = (0..48).map(|i| {
+            let angle = (i % 12) as f64 / 12.0 * 2.0 * std::f64::consts::PI;
+            let r = 0.5 * (i / 12 + 1) as f64;
+            ((center.0 + r * angle.cos(), center.1 + r * angle.sin()), 1.0)
+        }).collect();
+        votes.extend((0..100).map(|i| (((i * 71 % 300) as f64, (i * 29 % 300) as f64), 3.0)));
+        return votes;
+    }
+
+    #[test]
+    fn find_peak_lands_on_the_planted_centre(){
+        let center = (123.4, 56.7);
+        for (refinement, tolerance) in [(PeakRefinement::Parabolic, 0.1), (PeakRefinement::Centroid, 0.5)]{
+            let params = AccumulatorParams{refinement, ..AccumulatorParams::init()};
+            let peak = find_peak(&votes(center), ((0.0, 0.0), (300.0, 300.0)), &params).unwrap();
+            //the centroid's window is centred on the best bin, which pulls it a little towards that bin
+            let distance = ((peak.0 - center.0).powi(2) + (peak.1 - center.1).powi(2)).sqrt();
+            assert!(distance < tolerance, "{:?} {:?} {}", refinement, peak, distance);
+        }
+    }
+
+    #[test]
+    fn find_peak_finds_a_centre_outside_the_frame_with_finer_bins(){
+        //a frame width to the left of a 900 pixel picture, searched over two frame widths around it
+        let center = (-850.2, 410.6);
+        let params = AccumulatorParams{bin_size: 0.5, smoothing: 2.0, refinement: PeakRefinement::Parabolic};
+        let peak = find_peak(&votes(center), ((-1800.0, -1800.0), (2700.0, 2700.0)), &params).unwrap();
+        assert!((peak.0 - center.0).abs() < 0.1 && (peak.1 - center.1).abs() < 0.1, "{:?}", peak);
+    }
+
+    #[test]
+    fn find_peak_needs_votes_inside(){
+        assert!(find_peak(&[], ((0.0, 0.0), (10.0, 10.0)), &AccumulatorParams::init()).is_none());
+    }
+}
@@ -0,0 +1,206 @@
+use crate::label_map::LabelMap;
+use crate::linalg::{invert, solve_linear_system};
+use crate::lm::light_falloff;
+use crate::noise_model::NoiseModel;
+
+
+//across a boundary the light is the same, so log(I / g) differs only by the log albedo ratio;
+//every boundary pixel pair measures it, the measurements of an edge of the patch graph are
+//cleaned of outliers, averaged by the noise model's weights and all edges are solved together by
+//weighted least squares
+//pixels are taken this far from the boundary, right at it the median filter mixes the patches
+static BOUNDARY_OFFSET: i32 = 2;
+static MIN_PAIRS: usize = 5;
+//in robust standard deviations (1.4826 MAD)
+static PAIR_REJECT: f64 = 3.0;
+static EDGE_REJECT: f64 = 4.0;
+//smallest standard deviation an edge is trusted to, in log albedo
+static EDGE_SIGMA_FLOOR: f64 = 1e-4;
+//log albedo standard deviation above which a patch counts as not tied to the others
+static UNCONSTRAINED_SIGMA: f32 = 1.0;
+
+
+pub struct AlbedoSolution{
+    //albedo / the largest albedo
+    pub albedo: Vec<f32>,
+    //standard deviation of each log albedo ratio to the largest, infinite for unconstrained patches;
+    //it comes from the pixel scatter only, errors of the light location and height are not in it
+    pub log_sigma: Vec<f32>,
+    pub edges: usize,
+    pub pairs: usize,
+    pub rejected_pairs: usize,
+    pub rejected_edges: usize
+}
+
+//one boundary pixel pair: log albedo difference and its variance
+struct Measurement{
+    diff: f64,
+    variance: f64
+}
+
+struct Edge{
+    a: usize,
+    b: usize,
+    //log albedo a - log albedo b
+    diff: f64,
+    sigma: f64
+}
+
+fn median(values: &mut [f64]) -> f64{
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    return if n % 2 == 1 {values[n / 2]} else {0.5 * (values[n / 2 - 1] + values[n / 2])};
+}
+
+//log albedo difference measurements of every pair of touching patches
+fn measure(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32), height: f32) -> std::collections::BTreeMap<(usize, usize), Vec<Measurement>>{
+    let (w, h) = labels.shape();
+    let saturated = scene_arr.iter().cloned().fold(0.0, f32::max) * 0.999;
+    let light = (location.0 as f64, location.1 as f64);
+    let height = (height as f64).max(1.0);
+    //log shading and its variance, var(ln x) ≈ var(x) / x²
+    let shading = |j: usize, k: usize| -> Option<(f64, f64)> {
+        let v = scene_arr[[j, k]];
+        if v >= saturated{
+            return None;
+        }
+        let signal = noise.signal(v as f64);
+        let g = light_falloff(light, height, (j as f64, k as f64)).0;
+        if signal <= 0.0 || g <= 0.0{
+            return None;
+        }
+        return Some(((signal / g).ln(), noise.variance() / (signal * signal)));
+    };
+    let mut measurements = std::collections::BTreeMap::new();
+    for ((j, k), step) in labels.boundary_pairs(){
+        let (a, b) = (labels.label(j, k), labels.label((j as i32 + step.0) as usize, (k as i32 + step.1) as usize));
+        //back into the first patch and forward into the second
+        let p = (j as i32 - (BOUNDARY_OFFSET - 1) * step.0, k as i32 - (BOUNDARY_OFFSET - 1) * step.1);
+        let q = (j as i32 + BOUNDARY_OFFSET * step.0, k as i32 + BOUNDARY_OFFSET * step.1);
+        if p.0 < 0 || p.1 < 0 || q.0 >= w as i32 || q.1 >= h as i32{
+            continue;
+        }
+        let (p, q) = ((p.0 as usize, p.1 as usize), (q.0 as usize, q.1 as usize));
+        if labels.label(p.0, p.1) != a || labels.label(q.0, q.1) != b{
+            continue;
+        }
+        if let (Some(sa), Some(sb)) = (shading(p.0, p.1), shading(q.0, q.1)){
+            let (key, diff) = if a < b {((a, b), sa.0 - sb.0)} else {((b, a), sb.0 - sa.0)};
+            measurements.entry(key).or_insert(vec!()).push(Measurement{diff, variance: sa.1 + sb.1});
+        }
+    }
+    return measurements;
+}
+
+//weighted least squares for the log albedos, the first one pinned to 0 by an extra row;
+//a tiny ridge keeps patches with no edges from making the system singular
+fn solve_log_albedo(edges: &[&Edge], n: usize) -> Option<(ndarray::Array2::<f64>, ndarray::Array1::<f64>)>{
+    let mut a = ndarray::Array2::<f64>::zeros((n, n));
+    let mut b = ndarray::Array1::<f64>::zeros(n);
+    for e in edges{
+        let w = 1.0 / (e.sigma * e.sigma);
+        a[[e.a, e.a]] += w;
+        a[[e.b, e.b]] += w;
+        a[[e.a, e.b]] -= w;
+        a[[e.b, e.a]] -= w;
+        b[e.a] += w * e.diff;
+        b[e.b] -= w * e.diff;
+    }
+    a[[0, 0]] += 1.0;
+    for i in 0..n{
+        a[[i, i]] += 1e-9;
+    }
+    let x = solve_linear_system(&a, &b)?;
+    return Some((a, x));
+}
+
+pub fn solve_albedo(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32), height: f32) -> Option<AlbedoSolution>{
+    let n = labels.count();
+    let mut pairs = 0;
+    let mut rejected_pairs = 0;
+    let mut edges = vec!();
+    for ((a, b), values) in measure(scene_arr, labels, noise, location, height){
+        if values.len() < MIN_PAIRS{
+            continue;
+        }
+        let mut diffs: Vec<f64> = values.iter().map(|v| v.diff).collect();
+        let center = median(&mut diffs);
+        let mut deviations: Vec<f64> = values.iter().map(|v| (v.diff - center).abs()).collect();
+        let spread = 1.4826 * median(&mut deviations);
+        let inliers: Vec<&Measurement> = values.iter().filter(|v| (v.diff - center).abs() <= PAIR_REJECT * spread.max(1e-12)).collect();
+        pairs += values.len();
+        rejected_pairs += values.len() - inliers.len();
+        //standard error of the weighted mean from the scatter, the weights only say which pairs are better
+        let m = inliers.len() as f64;
+        let sw: f64 = inliers.iter().map(|v| 1.0 / v.variance).sum();
+        let mean = inliers.iter().map(|v| v.diff / v.variance).sum::<f64>() / sw;
+        let var = inliers.iter().map(|v| (v.diff - mean).powi(2) / v.variance).sum::<f64>() / (m - 1.0).max(1.0) / sw;
+        edges.push(Edge{a, b, diff: mean, sigma: var.sqrt().max(EDGE_SIGMA_FLOOR)});
+    }
+    if edges.is_empty(){
+        return None;
+    }
+    //edges that disagree with the rest of the graph (a cycle that doesn't close) are dropped once
+    let all: Vec<&Edge> = edges.iter().collect();
+    let (_, x) = solve_log_albedo(&all, n)?;
+    let kept: Vec<&Edge> = edges.iter().filter(|e| ((x[e.a] - x[e.b] - e.diff) / e.sigma).abs() <= EDGE_REJECT * edge_scale(&edges, &x)).collect();
+    let kept = if kept.is_empty() {all} else {kept};
+    let (a, x) = solve_log_albedo(&kept, n)?;
+    //the brightest patch is the reference, its variance and covariances come from the inverse
+    let brightest = (0..n).max_by(|i, j| x[*i].total_cmp(&x[*j]))?;
+    let cov = invert(&a)?;
+    let log_sigma: Vec<f32> = (0..n).map(|i| {
+        let var = cov[[i, i]] + cov[[brightest, brightest]] - 2.0 * cov[[i, brightest]];
+        let s = var.max(0.0).sqrt() as f32;
+        if s > UNCONSTRAINED_SIGMA {f32::INFINITY} else {s}
+    }).collect();
+    return Some(AlbedoSolution{
+        albedo: (0..n).map(|i| (x[i] - x[brightest]).exp() as f32).collect(),
+        log_sigma,
+        edges: kept.len(),
+        pairs,
+        rejected_pairs,
+        rejected_edges: edges.len() - kept.len()
+    });
+}
+
+//robust scale of the normalized edge residuals, at least 1 so consistent graphs keep every edge
+fn edge_scale(edges: &[Edge], x: &ndarray::Array1::<f64>) -> f64{
+    let mut residuals: Vec<f64> = edges.iter().map(|e| ((x[e.a] - x[e.b] - e.diff) / e.sigma).abs()).collect();
+    return (1.4826 * median(&mut residuals)).max(1.0);
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.3, 0.6, 0.45, 0.9, 0.12, 0.75, 0.5, 0.25, 0.68];
+
+    #[test]
+    fn solve_albedo_recovers_the_ratios_under_a_light_outside_the_frame(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (-600.0, 450.0), 400.0, &ALBEDO, 0.0, 1);
+        let sol = solve_albedo(&arr, &labels, &NoiseModel::clean(), (-600.0, 450.0), 400.0).unwrap();
+        //every pair of neighbouring squares of the grid
+        assert_eq!(sol.edges, 12);
+        assert_eq!(sol.rejected_edges, 0);
+        for (a, truth) in sol.albedo.iter().zip(ALBEDO.iter()){
+            assert!((*a as f64 - truth / 0.9).abs() < 1e-3, "{:?}", sol.albedo);
+        }
+        assert_eq!(sol.log_sigma[3], 0.0);
+    }
+
+    #[test]
+    fn solve_albedo_recovers_the_ratios_of_a_noisy_picture(){
+        let labels = LabelMap::grid((1050, 750), 3, 3);
+        let arr = render(&labels, (560.0, 330.0), 250.0, &ALBEDO, 0.005, 6);
+        let sol = solve_albedo(&arr, &labels, &NoiseModel::init(0.0, 0.005), (560.0, 330.0), 250.0).unwrap();
+        for (i, (a, truth)) in sol.albedo.iter().zip(ALBEDO.iter()).enumerate(){
+            //the darkest squares far from the light are the least sure
+            let truth = truth / 0.9;
+            assert!(((*a as f64).ln() - truth.ln()).abs() < 4.0 * sol.log_sigma[i] as f64 + 0.01, "{} {:?} {:?}", i, sol.albedo, sol.log_sigma);
+        }
+    }
+}
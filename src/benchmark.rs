@@ -0,0 +1,249 @@
+use crate::dataset::{SceneSample, CSV_HEADER};
+use crate::{load_linear_image, location_quality, multi_light, LightSimApp};
+
+
+//solvers scored on a generated dataset: the location error in pixels, the height error over the
+//true height, the rms error of albedo over the largest albedo, every square of the 3x3 grid read
+//through the patch at its middle, and the time from the loaded picture to the answer. only the first
+//light of a scene is scored. a picture the diagnostics call unsolvable counts as failed and stays
+//out of the errors. the multi-light fit is timed on its own from every solver's answer, with the
+//number of lights it found
+
+
+//upper edges of the noise sigma groups, the last group is everything above
+static NOISE_EDGES: &[f64] = &[0.01, 0.02];
+//lights further than this many frame widths outside are far
+static NEAR_FRAMES: f32 = 0.5;
+static RESULTS_HEADER: &str = "name,solver,noise_sigma,outside,solved,loc_error,height_error,albedo_rmse,ms,lights,lights_ms";
+static SUMMARY_HEADER: &str = "table,group,solver,images,failed,loc_mean,loc_median,height_mean,height_median,albedo_rmse_mean,ms_mean,lights_ms_mean";
+
+
+//group of a result in one of the summary tables
+type Grouping = fn(&BenchmarkResult) -> String;
+
+struct BenchmarkResult{
+    name: String,
+    solver: String,
+    //0 for a picture without noise
+    noise_sigma: f64,
+    //distance of the true light from the frame, 0 inside, and the longer side of the frame
+    outside: f32,
+    side: f32,
+    solved: bool,
+    loc_error: f32,
+    height_error: f32,
+    albedo_rmse: f32,
+    ms: f32,
+    //lights the multi-light fit found, 0 if it found none, and its time
+    lights: usize,
+    lights_ms: f32
+}
+
+impl BenchmarkResult{
+    fn csv_row(&self) -> String{
+        return format!("{},{},{},{},{},{},{},{},{},{},{}", self.name, self.solver, self.noise_sigma, self.outside, self.solved as u8,
+                       self.loc_error, self.height_error, self.albedo_rmse, self.ms, self.lights, self.lights_ms);
+    }
+
+    fn noise_group(&self) -> String{
+        if self.noise_sigma <= 0.0{
+            return "clean".to_string();
+        }
+        return match NOISE_EDGES.iter().find(|e| self.noise_sigma <= **e){
+            Some(e) => format!("sigma <= {}", e),
+            None => format!("sigma > {}", NOISE_EDGES[NOISE_EDGES.len() - 1])
+        };
+    }
+
+    fn position_group(&self) -> String{
+        if self.outside <= 0.0{
+            return "inside".to_string();
+        }
+        if self.outside <= NEAR_FRAMES * self.side{
+            return "near".to_string();
+        }
+        return "far".to_string();
+    }
+}
+
+
+fn mean(values: &[f32]) -> f32{
+    if values.is_empty(){
+        return f32::NAN;
+    }
+    return values.iter().sum::<f32>() / values.len() as f32;
+}
+
+fn median(values: &[f32]) -> f32{
+    if values.is_empty(){
+        return f32::NAN;
+    }
+    let mut values = values.to_vec();
+    values.sort_by(|a, b| a.total_cmp(b));
+    return values[values.len() / 2];
+}
+
+//albedo of the middle of every square over the largest of them, image rows first like the dataset's
+fn grid_albedo(lsa: &LightSimApp) -> [f32; 9]{
+    let (w, h) = lsa.labels.shape();
+    let mut res = [0.0; 9];
+    for row in 0..3{
+        for col in 0..3{
+            let patch = lsa.labels.label((2 * col + 1) * w / 6, (2 * row + 1) * h / 6);
+            res[row * 3 + col] = lsa.revere_solution_albedo.get(patch).cloned().unwrap_or(0.0);
+        }
+    }
+    let max = res.iter().cloned().fold(0.0, f32::max);
+    if max > 0.0{
+        for a in res.iter_mut(){
+            *a /= max;
+        }
+    }
+    return res;
+}
+
+fn run(dir: &std::path::Path, sample: &SceneSample, solver: &str) -> BenchmarkResult{
+    let arr = load_linear_image(dir.join(sample.name.clone() + ".png").to_str().unwrap());
+    let frame = (arr.shape()[0] as f32, arr.shape()[1] as f32);
+    let start = std::time::Instant::now();
+    let mut lsa = LightSimApp::from_scene_arr(arr);
+    lsa.solver = solver.to_string();
+    lsa.update_no_pic();
+    let ms = start.elapsed().as_secs_f32() * 1000.0;
+    let truth = (sample.x as f32, sample.y as f32);
+    let mut res = BenchmarkResult{
+        name: sample.name.clone(),
+        solver: solver.to_string(),
+        noise_sigma: if sample.noise {sample.noise_sigma} else {0.0},
+        outside: location_quality::distance_to_frame(truth, frame),
+        side: frame.0.max(frame.1),
+        solved: lsa.is_solvable(),
+        loc_error: f32::NAN,
+        height_error: f32::NAN,
+        albedo_rmse: f32::NAN,
+        ms,
+        lights: 0,
+        lights_ms: f32::NAN
+    };
+    if !res.solved{
+        return res;
+    }
+    let start = std::time::Instant::now();
+    lsa.solve_multi_light(&multi_light::MultiLightParams::init());
+    res.lights_ms = start.elapsed().as_secs_f32() * 1000.0;
+    res.lights = lsa.multi_light.as_ref().map(|m| m.lights.len()).unwrap_or(0);
+    let location = lsa.frame.to_picture(lsa.reverse_solution_location);
+    res.loc_error = crate::eucl_dist_f32(&location, &truth);
+    res.height_error = (lsa.frame.length_to_picture(lsa.reverse_solution_height) - sample.height as f32).abs() / sample.height as f32;
+    let max = sample.albedo.iter().cloned().fold(0.0, f32::max);
+    let albedo = grid_albedo(&lsa);
+    res.albedo_rmse = (albedo.iter().zip(sample.albedo.iter()).map(|(a, t)| (a - t / max).powi(2)).sum::<f32>() / 9.0).sqrt();
+    return res;
+}
+
+//one line of a summary table, over the results of one solver in one group
+fn summary_row(table: &str, group: &str, solver: &str, results: &[&BenchmarkResult]) -> String{
+    let solved: Vec<&&BenchmarkResult> = results.iter().filter(|r| r.solved).collect();
+    let loc: Vec<f32> = solved.iter().map(|r| r.loc_error).collect();
+    let height: Vec<f32> = solved.iter().map(|r| r.height_error).collect();
+    let albedo: Vec<f32> = solved.iter().map(|r| r.albedo_rmse).collect();
+    let ms: Vec<f32> = results.iter().map(|r| r.ms).collect();
+    let lights_ms: Vec<f32> = solved.iter().map(|r| r.lights_ms).collect();
+    return format!("{},{},{},{},{},{:.3},{:.3},{:.4},{:.4},{:.4},{:.0},{:.0}", table, group, solver, results.len(), results.len() - solved.len(),
+                   mean(&loc), median(&loc), mean(&height), median(&height), mean(&albedo), mean(&ms), mean(&lights_ms));
+}
+
+//every solver over every picture of the dataset in dir, results.csv and summary.csv go to out_dir
+pub fn run_benchmark(dir: &str, out_dir: &str, solvers: &[String]){
+    let dir = std::path::Path::new(dir);
+    let contents = std::fs::read_to_string(dir.join("ground_truth.csv")).unwrap();
+    let mut lines = contents.lines();
+    let header = lines.next().unwrap();
+    assert!(CSV_HEADER.starts_with(header), "{} is not a dataset's ground truth", dir.display());
+    let samples: Vec<SceneSample> = lines.filter(|l| !l.trim().is_empty()).map(SceneSample::from_csv_row).collect();
+    let mut results = vec!();
+    for (i, sample) in samples.iter().enumerate(){
+        for solver in solvers{
+            let res = run(dir, sample, solver);
+            println!("{}/{}: {} {}: loc {:.2} px, height {:.4}, albedo {:.4}, {:.0} ms, {} lights in {:.0} ms{}", i + 1, samples.len(), res.name, solver,
+                     res.loc_error, res.height_error, res.albedo_rmse, res.ms, res.lights, res.lights_ms, if res.solved {""} else {" (unsolvable)"});
+            results.push(res);
+        }
+    }
+    std::fs::create_dir_all(out_dir).unwrap();
+    let out_dir = std::path::Path::new(out_dir);
+    let mut csv = vec!(RESULTS_HEADER.to_string());
+    csv.extend(results.iter().map(|r| r.csv_row()));
+    std::fs::write(out_dir.join("results.csv"), csv.join("\n") + "\n").unwrap();
+    //the same table over everything, broken down by noise and by where the light is
+    let tables: [(&str, Grouping); 3] = [
+        ("all", |_| "all".to_string()),
+        ("noise", BenchmarkResult::noise_group),
+        ("position", BenchmarkResult::position_group)
+    ];
+    let mut summary = vec!(SUMMARY_HEADER.to_string());
+    for (table, group_of) in tables.iter(){
+        let mut groups: Vec<String> = vec!();
+        for r in results.iter(){
+            let g = group_of(r);
+            if !groups.contains(&g){
+                groups.push(g);
+            }
+        }
+        groups.sort();
+        for group in groups.iter(){
+            for solver in solvers{
+                let members: Vec<&BenchmarkResult> = results.iter().filter(|r| &r.solver == solver && &group_of(r) == group).collect();
+                summary.push(summary_row(table, group, solver, &members));
+            }
+        }
+    }
+    std::fs::write(out_dir.join("summary.csv"), summary.join("\n") + "\n").unwrap();
+    for line in summary{
+        println!("{}", line.replace(',', "\t"));
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn result(noise_sigma: f64, outside: f32, solved: bool, loc_error: f32, ms: f32) -> BenchmarkResult{
+        return BenchmarkResult{name: "scene".to_string(), solver: "separate".to_string(), noise_sigma, outside, side: 900.0, solved,
+                               loc_error, height_error: loc_error / 100.0, albedo_rmse: loc_error / 1000.0, ms, lights: 1, lights_ms: 10.0 * ms};
+    }
+
+    #[test]
+    fn results_fall_into_their_groups(){
+        assert_eq!(result(0.0, 0.0, true, 1.0, 1.0).noise_group(), "clean");
+        assert_eq!(result(0.005, 0.0, true, 1.0, 1.0).noise_group(), "sigma <= 0.01");
+        assert_eq!(result(0.02, 0.0, true, 1.0, 1.0).noise_group(), "sigma <= 0.02");
+        assert_eq!(result(0.05, 0.0, true, 1.0, 1.0).noise_group(), "sigma > 0.02");
+        assert_eq!(result(0.0, 0.0, true, 1.0, 1.0).position_group(), "inside");
+        assert_eq!(result(0.0, 450.0, true, 1.0, 1.0).position_group(), "near");
+        assert_eq!(result(0.0, 451.0, true, 1.0, 1.0).position_group(), "far");
+    }
+
+    #[test]
+    fn summary_row_leaves_failed_pictures_out_of_the_errors(){
+        let results = [result(0.0, 0.0, true, 1.0, 10.0), result(0.0, 0.0, true, 4.0, 20.0), result(0.0, 0.0, true, 2.0, 30.0), result(0.0, 0.0, false, f32::NAN, 40.0)];
+        let refs: Vec<&BenchmarkResult> = results.iter().collect();
+        //the failed picture still took its time
+        assert_eq!(summary_row("all", "all", "separate", &refs), "all,all,separate,4,1,2.333,2.000,0.0233,0.0200,0.0023,25,200");
+        assert_eq!(summary_row("all", "all", "separate", &refs[3..]), "all,all,separate,1,1,NaN,NaN,NaN,NaN,NaN,40,NaN");
+    }
+
+    #[test]
+    fn ground_truth_rows_are_read_back(){
+        let row = "scene_0003,-120,455,300,1,0.01,0.02,77,0.1,0.2,0.3,0.4,0.5,0.6,0.7,0.8,0.9,600 200 150 0.5;10 20 30 0.25";
+        let sample = SceneSample::from_csv_row(row);
+        assert_eq!((sample.name.as_str(), sample.x, sample.y, sample.height), ("scene_0003", -120, 455, 300));
+        assert!(sample.noise && sample.noise_mean == 0.01 && sample.noise_sigma == 0.02 && sample.noise_seed == 77);
+        assert_eq!(sample.albedo, [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9]);
+        assert_eq!(sample.extra.len(), 2);
+        assert_eq!((sample.extra[1].coordinates, sample.extra[1].height, sample.extra[1].intensity), ((10, 20), 30, 0.25));
+        //single light datasets end at the albedo
+        assert!(SceneSample::from_csv_row(&row[..row.rfind(',').unwrap()]).extra.is_empty());
+    }
+}
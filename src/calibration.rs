@@ -0,0 +1,297 @@
+use crate::height_fit;
+use crate::label_map::LabelMap;
+use crate::scene_file::Reference;
+use crate::clinear_to_srgb;
+use ndarray::parallel::prelude::*;
+
+
+//prep_arr stretches the sRGB image so that its brightest pixel is 255, so what a picture decodes to is
+//S⁻¹(white * S(v)) / S⁻¹(white) times the real linear brightness only up to the sRGB curve; white, the
+//sRGB value of the brightest pixel before the stretch, is what has to be found to undo it.
+//with a reference of known reflectance albedo * luminosity is fixed, and white is the value for which
+//the decoded picture follows the cos³ falloff best; the stretch bends the falloff much like a change
+//of height does, so the height is fitted again with every white and the interval around the best
+//white matters as much as the value
+static WHITE_MIN: f64 = 0.02;
+static WHITE_STEPS: usize = 32;
+static GOLDEN_ITERATIONS: usize = 24;
+//the height is searched within this factor of the one found on the stretched picture
+static HEIGHT_RANGE: f64 = 4.0;
+//of the log height grid
+static HEIGHT_STEP: f64 = 0.18;
+static STRIDE: usize = 4;
+//without a reference the height is searched over its whole range, on fewer pixels
+static EXPOSURE_STRIDE: usize = 8;
+//above it a pixel may have been clipped
+static CLIPPED: f32 = 254.5 / 255.0;
+//relative width of the white interval above which the exposure counts as undetermined
+static DEGENERATE_WIDTH: f32 = 0.5;
+//relative cost increase still counted as fitting as well
+static WHITE_TOLERANCE: f64 = 0.05;
+
+
+pub struct Calibration{
+    pub luminosity: f32,
+    //absolute albedo of every patch
+    pub albedo: Vec<f32>,
+    //height that goes with the best white
+    pub height: f32,
+    pub white: f32,
+    //values of white the picture can't tell apart from the best one
+    pub white_interval: (f32, f32),
+    //part of the decoded picture the falloff doesn't explain, at the best white
+    pub residual: f64
+}
+
+//the white the decoded picture follows the cos³ falloff best with, whatever the albedo scale
+pub struct Exposure{
+    pub white: f32,
+    //values of white the picture can't tell apart from the best one
+    pub white_interval: (f32, f32),
+    //height that goes with the best white
+    pub height: f32,
+    //part of the decoded picture the falloff doesn't explain, at the best white
+    pub residual: f64
+}
+
+impl Exposure{
+    pub fn is_degenerate(&self) -> bool{
+        return (self.white_interval.1 - self.white_interval.0) / self.white > DEGENERATE_WIDTH;
+    }
+}
+
+impl Calibration{
+    //the factor prep_arr multiplied the sRGB values by
+    pub fn gain(&self) -> f32{
+        return 1.0 / self.white;
+    }
+
+    //linear brightness of the brightest pixel before the stretch
+    pub fn brightest(&self) -> f32{
+        return srgb_to_linear(self.white as f64) as f32;
+    }
+
+    pub fn is_degenerate(&self) -> bool{
+        return (self.white_interval.1 - self.white_interval.0) / self.white > DEGENERATE_WIDTH;
+    }
+}
+
+fn srgb_to_linear(s: f64) -> f64{
+    if s <= 0.04045{
+        return s / 12.92;
+    }
+    return ((s + 0.055) / 1.055).powf(2.4);
+}
+
+struct Sample{
+    srgb: f64,
+    r2: f64,
+    label: usize,
+    reference: bool
+}
+
+//per patch scales of the decoded picture against the falloff, with the reference's scale last,
+//and the relative residual
+fn fit_scales(samples: &[Sample], decoded: &[f64], n: usize, h: f64) -> (Vec<f64>, f64){
+    let mut gg = vec![0.0; n + 1];
+    let mut gv = vec![0.0; n + 1];
+    let mut vv = vec![0.0; n + 1];
+    let h2 = h * h;
+    let h3 = h2 * h;
+    for (s, v) in samples.iter().zip(decoded.iter()){
+        let d = s.r2 + h2;
+        let g = h3 / (d * d.sqrt());
+        gg[s.label] += g * g;
+        gv[s.label] += g * v;
+        vv[s.label] += v * v;
+        if s.reference{
+            gg[n] += g * g;
+            gv[n] += g * v;
+        }
+    }
+    let scales: Vec<f64> = (0..=n).map(|i| if gg[i] > 0.0 {gv[i] / gg[i]} else {0.0}).collect();
+    //the unexplained fraction, darker decodings would have smaller residuals just by being darker
+    let cost = (0..n).map(|i| if gg[i] > 0.0 {vv[i] - gv[i] * gv[i] / gg[i]} else {vv[i]}).sum::<f64>();
+    let total = vv[..n].iter().sum::<f64>();
+    return (scales, if total > 0.0 {cost.max(0.0) / total} else {0.0});
+}
+
+//log spaced grid over [lo, hi], then golden section around its best point
+fn minimize(lo: f64, hi: f64, steps: usize, f: impl Fn(f64) -> f64) -> (f64, f64){
+    let grid: Vec<f64> = (0..=steps).map(|i| lo + (hi - lo) * i as f64 / steps as f64).collect();
+    let costs: Vec<f64> = grid.iter().map(|t| f(*t)).collect();
+    let best = (0..costs.len()).min_by(|a, b| costs[*a].total_cmp(&costs[*b])).unwrap();
+    let mut a = grid[best.saturating_sub(1)];
+    let mut b = grid[(best + 1).min(steps)];
+    let ratio = (5.0f64.sqrt() - 1.0) / 2.0;
+    let mut c = b - ratio * (b - a);
+    let mut d = a + ratio * (b - a);
+    let mut fc = f(c);
+    let mut fd = f(d);
+    for _ in 0..GOLDEN_ITERATIONS{
+        if fc < fd{
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - ratio * (b - a);
+            fc = f(c);
+        }
+        else{
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + ratio * (b - a);
+            fd = f(d);
+        }
+    }
+    let (t, cost) = if fc < fd {(c, fc)} else {(d, fd)};
+    return if costs[best] < cost {(grid[best], costs[best])} else {(t, cost)};
+}
+
+//the stretch bends the falloff the same way a different height does, so for every white the height
+//is searched again, within heights (log)
+fn height_for_white(samples: &[Sample], n: usize, white: f64, heights: (f64, f64)) -> (f64, f64){
+    let decoded: Vec<f64> = samples.iter().map(|s| srgb_to_linear(white * s.srgb)).collect();
+    let steps = ((heights.1 - heights.0) / HEIGHT_STEP).ceil().max(1.0) as usize;
+    let (t, cost) = minimize(heights.0, heights.1, steps, |t| fit_scales(samples, &decoded, n, t.exp()).1);
+    return (t.exp(), cost);
+}
+
+//every stride-th pixel below the clipping, with the light at location
+fn sample(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, location: (f32, f32), reference: Option<&Reference>, stride: usize) -> Vec<Sample>{
+    let mut samples = vec!();
+    for j in (0..scene_arr.shape()[0]).step_by(stride){
+        for k in (0..scene_arr.shape()[1]).step_by(stride){
+            //the picture as saved, without the stretch undone
+            let srgb = clinear_to_srgb(scene_arr[[j, k]]);
+            if srgb >= CLIPPED{
+                continue;
+            }
+            samples.push(Sample{
+                srgb: srgb as f64,
+                r2: (j as f64 - location.0 as f64).powi(2) + (k as f64 - location.1 as f64).powi(2),
+                label: labels.label(j, k),
+                reference: reference.is_some_and(|r| r.contains(j, k))
+            });
+        }
+    }
+    return samples;
+}
+
+//the white the decoded picture follows the falloff best with, the height searched within heights (log)
+//for every white
+fn search_white(samples: &[Sample], n: usize, heights: (f64, f64)) -> Option<Exposure>{
+    if samples.is_empty(){
+        return None;
+    }
+    //the white grid is shared by the search and the interval, grid points in parallel
+    let grid: Vec<f64> = (0..=WHITE_STEPS).map(|i| (WHITE_MIN.ln() * (1.0 - i as f64 / WHITE_STEPS as f64)).exp()).collect();
+    let costs: Vec<f64> = grid.par_iter().map(|w| height_for_white(samples, n, *w, heights).1).collect();
+    let best = (0..costs.len()).min_by(|a, b| costs[*a].total_cmp(&costs[*b]))?;
+    let (lo, hi) = (grid[best.saturating_sub(1)].ln(), grid[(best + 1).min(WHITE_STEPS)].ln());
+    let (t, cost) = minimize(lo, hi, 2, |t| height_for_white(samples, n, t.exp(), heights).1);
+    let (white, cost) = if costs[best] < cost {(grid[best], costs[best])} else {(t.exp(), cost)};
+    //every white that, with its own height, fits almost as well as the best one
+    let threshold = cost * (1.0 + WHITE_TOLERANCE);
+    let within: Vec<f64> = grid.iter().zip(costs.iter()).filter(|(_, c)| **c <= threshold).map(|(w, _)| *w).collect();
+    let white_interval = (within.iter().cloned().fold(white, f64::min) as f32, within.iter().cloned().fold(white, f64::max) as f32);
+    let (height, _) = height_for_white(samples, n, white, heights);
+    return Some(Exposure{white: white as f32, white_interval, height: height as f32, residual: cost});
+}
+
+//the stretch of a picture without a reference, with the light at location and any height up to
+//MAX_HEIGHT_FRAMES frame widths
+pub fn find_exposure(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, location: (f32, f32)) -> Option<Exposure>{
+    let samples = sample(scene_arr, labels, location, None, EXPOSURE_STRIDE);
+    return search_white(&samples, labels.count(), (0.0, height_fit::max_height(scene_arr.dim()).ln()));
+}
+
+//the picture prep_arr would have saved had it not stretched it
+pub fn unstretch(scene_arr: &ndarray::Array2::<f32>, white: f32) -> ndarray::Array2::<f32>{
+    return scene_arr.mapv(|v| srgb_to_linear(white as f64 * clinear_to_srgb(v) as f64) as f32);
+}
+
+pub fn calibrate(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, location: (f32, f32), height: f32, reference: &Reference) -> Option<Calibration>{
+    let n = labels.count();
+    let height = (height as f64).max(1.0);
+    let samples = sample(scene_arr, labels, location, Some(reference), STRIDE);
+    if !samples.iter().any(|s| s.reference){
+        return None;
+    }
+    //the height found on the stretched picture only bounds the search
+    let exposure = search_white(&samples, n, ((height / HEIGHT_RANGE).max(1.0).ln(), (height * HEIGHT_RANGE).ln()))?;
+    let decoded: Vec<f64> = samples.iter().map(|s| srgb_to_linear(exposure.white as f64 * s.srgb)).collect();
+    let (scales, _) = fit_scales(&samples, &decoded, n, exposure.height as f64);
+    let luminosity = scales[n] / reference.albedo as f64;
+    if luminosity <= 0.0{
+        return None;
+    }
+    return Some(Calibration{
+        luminosity: luminosity as f32,
+        albedo: scales[..n].iter().map(|s| (s / luminosity) as f32).collect(),
+        height: exposure.height,
+        white: exposure.white,
+        white_interval: exposure.white_interval,
+        residual: exposure.residual
+    });
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.45, 0.7, 0.3, 0.55, 0.8, 0.25, 0.6, 0.4, 0.9];
+
+    //what a picture saved by the gui decodes to: sRGB stretched so that the brightest pixel is white
+    fn stretched(arr: &ndarray::Array2::<f32>) -> ndarray::Array2::<f32>{
+        let srgb = arr.mapv(clinear_to_srgb);
+        let max = srgb.iter().cloned().fold(0.0, f32::max);
+        return srgb.mapv(|s| srgb_to_linear((s / max) as f64) as f32);
+    }
+
+    #[test]
+    fn calibrate_recovers_absolute_albedo_and_luminosity(){
+        //luminosity 0.9, the brightest pixel is at 0.8 before the stretch
+        let luminosity = 0.9;
+        let albedo = ALBEDO.map(|a| a * luminosity);
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = stretched(&render(&labels, (430.0, 470.0), 350.0, &albedo, 0.0, 1));
+        let reference = Reference{min: (350, 350), max: (550, 550), albedo: ALBEDO[4] as f32};
+        let cal = calibrate(&arr, &labels, (430.0, 470.0), 350.0, &reference).unwrap();
+        assert!((cal.luminosity as f64 - luminosity).abs() < 0.02, "{}", cal.luminosity);
+        for (a, truth) in cal.albedo.iter().zip(ALBEDO.iter()){
+            assert!((*a as f64 - truth).abs() < 0.02, "{:?}", cal.albedo);
+        }
+        assert!((cal.height - 350.0).abs() < 5.0, "{}", cal.height);
+        assert!((cal.brightest() - 0.72).abs() < 0.02, "{}", cal.brightest());
+    }
+
+    #[test]
+    fn calibrate_needs_the_reference_in_the_picture(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (430.0, 470.0), 350.0, &ALBEDO, 0.0, 1);
+        let reference = Reference{min: (1000, 1000), max: (1100, 1100), albedo: 0.5};
+        assert!(calibrate(&arr, &labels, (430.0, 470.0), 350.0, &reference).is_none());
+    }
+
+    #[test]
+    fn the_stretch_is_undone_for_a_light_outside_the_picture(){
+        //a frame width left of the picture, saved in 8 bits the way the gui does
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (-900.0, 450.0), 300.0, &ALBEDO, 0.0, 1);
+        let white = clinear_to_srgb(arr.iter().cloned().fold(0.0, f32::max));
+        let saved = arr.mapv(|v| srgb_to_linear((clinear_to_srgb(v) / white * 255.0).round() as f64 / 255.0) as f32);
+        let noise = crate::noise_model::NoiseModel::clean();
+        //the stretch bends the falloff like a much lower light
+        let bent = height_fit::fit_height(&saved, &labels, &noise, (-900.0, 450.0)).unwrap();
+        assert!(bent.height < 150.0, "{}", bent.height);
+        let exposure = find_exposure(&saved, &labels, (-900.0, 450.0)).unwrap();
+        assert!(!exposure.is_degenerate(), "{:?}", exposure.white_interval);
+        assert!((exposure.white - white).abs() < 0.01, "{} {}", exposure.white, white);
+        let fit = height_fit::fit_height(&unstretch(&saved, exposure.white), &labels, &noise, (-900.0, 450.0)).unwrap();
+        assert!((fit.height - 300.0).abs() < 6.0, "{}", fit.height);
+        assert!(!height_fit::at_bound(fit.height, (900, 900)));
+    }
+}
@@ -0,0 +1,248 @@
+use probability::source::Source;
+
+
+//iso-brightness lines of a uniform patch are circle arcs around the foot of the light,
+//so each brightness cluster gets its own circle fitted to it
+static RANSAC_ITERATIONS: usize = 100;
+static RANSAC_THRESHOLD: f64 = 1.5;
+static MIN_CLUSTER_POINTS: usize = 10;
+//a cluster of noise speckle fits some circle too, but only with a small part of its points
+static MIN_INLIER_FRACTION: f64 = 0.15;
+static ARC_BINS: usize = 72;
+//distance in pixels at which a fit center counts half, and the refits of the combination
+static COMBINE_SCALE: f64 = 10.0;
+static COMBINE_ITERATIONS: usize = 10;
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct Circle{
+    pub center: (f64, f64),
+    pub radius: f64
+}
+
+//circle through three points, None when they are (almost) on one line
+pub fn circle_from_three(p1: (f64, f64), p2: (f64, f64), p3: (f64, f64)) -> Option<Circle>{
+    let down = 2.0 * (p1.0 * (p2.1 - p3.1) + p2.0 * (p3.1 - p1.1) + p3.0 * (p1.1 - p2.1));
+    if down.abs() < 1e-9{
+        return None;
+    }
+    let s1 = p1.0 * p1.0 + p1.1 * p1.1;
+    let s2 = p2.0 * p2.0 + p2.1 * p2.1;
+    let s3 = p3.0 * p3.0 + p3.1 * p3.1;
+    let x = (s1 * (p2.1 - p3.1) + s2 * (p3.1 - p1.1) + s3 * (p1.1 - p2.1)) / down;
+    let y = (s1 * (p3.0 - p2.0) + s2 * (p1.0 - p3.0) + s3 * (p2.0 - p1.0)) / down;
+    let radius = ((p1.0 - x) * (p1.0 - x) + (p1.1 - y) * (p1.1 - y)).sqrt();
+    return Some(Circle{center: (x, y), radius});
+}
+
+//Taubin's algebraic fit (Newton iterations on the characteristic polynomial, after Chernov)
+pub fn taubin_fit(points: &[(f64, f64)]) -> Option<Circle>{
+    if points.len() < 3{
+        return None;
+    }
+    let n = points.len() as f64;
+    let xm = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let ym = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let (mut mxx, mut myy, mut mxy, mut mxz, mut myz, mut mzz) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+    for p in points{
+        let xi = p.0 - xm;
+        let yi = p.1 - ym;
+        let zi = xi * xi + yi * yi;
+        mxy += xi * yi;
+        mxx += xi * xi;
+        myy += yi * yi;
+        mxz += xi * zi;
+        myz += yi * zi;
+        mzz += zi * zi;
+    }
+    mxx /= n;
+    myy /= n;
+    mxy /= n;
+    mxz /= n;
+    myz /= n;
+    mzz /= n;
+    let mz = mxx + myy;
+    let cov_xy = mxx * myy - mxy * mxy;
+    let var_z = mzz - mz * mz;
+    let a3 = 4.0 * mz;
+    let a2 = -3.0 * mz * mz - mzz;
+    let a1 = var_z * mz + 4.0 * cov_xy * mz - mxz * mxz - myz * myz;
+    let a0 = mxz * (mxz * myy - myz * mxy) + myz * (myz * mxx - mxz * mxy) - var_z * cov_xy;
+    let a22 = a2 + a2;
+    let a33 = a3 + a3 + a3;
+    let mut x = 0.0;
+    let mut y = a0;
+    for _ in 0..100{
+        let dy = a1 + x * (a22 + a33 * x);
+        let x_new = x - y / dy;
+        if x_new == x || !x_new.is_finite(){
+            break;
+        }
+        let y_new = a0 + x_new * (a1 + x_new * (a2 + x_new * a3));
+        if y_new.abs() >= y.abs(){
+            break;
+        }
+        x = x_new;
+        y = y_new;
+    }
+    let det = x * x - x * mz + cov_xy;
+    if det.abs() < 1e-12{
+        return None;
+    }
+    let xc = (mxz * (myy - x) - myz * mxy) / det / 2.0;
+    let yc = (myz * (mxx - x) - mxz * mxy) / det / 2.0;
+    let radius = (xc * xc + yc * yc + mz).sqrt();
+    if !radius.is_finite(){
+        return None;
+    }
+    return Some(Circle{center: (xc + xm, yc + ym), radius});
+}
+
+
+fn distance_to_circle(circle: &Circle, p: &(f64, f64)) -> f64{
+    let dx = p.0 - circle.center.0;
+    let dy = p.1 - circle.center.1;
+    return ((dx * dx + dy * dy).sqrt() - circle.radius).abs();
+}
+
+//angle (in radians) of the circle the points actually cover
+fn arc_coverage(circle: &Circle, points: &[(f64, f64)]) -> f64{
+    let mut bins = vec![false; ARC_BINS];
+    for p in points{
+        let angle = (p.1 - circle.center.1).atan2(p.0 - circle.center.0) + std::f64::consts::PI;
+        let bin = ((angle / (2.0 * std::f64::consts::PI)) * ARC_BINS as f64) as usize;
+        bins[bin.min(ARC_BINS - 1)] = true;
+    }
+    return bins.iter().filter(|b| **b).count() as f64 / ARC_BINS as f64 * 2.0 * std::f64::consts::PI;
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct ClusterFit{
+    pub circle: Circle,
+    pub rms: f64,
+    pub arc: f64,
+    pub inliers: usize,
+    pub points: usize
+}
+
+impl ClusterFit{
+    //inverse variance of the center: for an arc of angle θ it goes as rms / (θ² * sqrt(n)),
+    //so short arcs and thick rings pin the center down badly; clusters the circle explains
+    //only partly are trusted less
+    pub fn weight(&self) -> f64{
+        let fraction = self.inliers as f64 / self.points as f64;
+        return fraction * self.inliers as f64 * self.arc.powi(4) / (self.rms + 0.1).powi(2);
+    }
+}
+
+//weighted mean of the fit centers around start, fits further off than a few scales counting less and
+//less (cauchy weights, refitted a few times) so the ones that went astray don't drag it
+pub fn combine_centers(fits: &[ClusterFit], start: (f64, f64)) -> (f64, f64){
+    let mut center = start;
+    for _ in 0..COMBINE_ITERATIONS{
+        let (mut sx, mut sy, mut sw) = (0.0, 0.0, 0.0);
+        for f in fits{
+            let d2 = (f.circle.center.0 - center.0).powi(2) + (f.circle.center.1 - center.1).powi(2);
+            let w = f.weight() / (1.0 + d2 / (COMBINE_SCALE * COMBINE_SCALE));
+            sx += w * f.circle.center.0;
+            sy += w * f.circle.center.1;
+            sw += w;
+        }
+        if sw <= 0.0{
+            break;
+        }
+        center = (sx / sw, sy / sw);
+    }
+    return center;
+}
+
+//RANSAC over three-point circles, then Taubin on the consensus set
+pub fn fit_cluster(points: &[(i32, i32)], seed: u64) -> Option<ClusterFit>{
+    if points.len() < MIN_CLUSTER_POINTS{
+        return None;
+    }
+    let pts: Vec<(f64, f64)> = points.iter().map(|p| (p.0 as f64, p.1 as f64)).collect();
+    let mut source = crate::dataset::seeded_source(seed);
+    let mut best: Option<(Circle, usize)> = None;
+    for _ in 0..RANSAC_ITERATIONS{
+        let i1 = (source.read_u64() % pts.len() as u64) as usize;
+        let i2 = (source.read_u64() % pts.len() as u64) as usize;
+        let i3 = (source.read_u64() % pts.len() as u64) as usize;
+        if i1 == i2 || i2 == i3 || i1 == i3{
+            continue;
+        }
+        if let Some(circle) = circle_from_three(pts[i1], pts[i2], pts[i3]){
+            let inliers = pts.iter().filter(|p| distance_to_circle(&circle, p) < RANSAC_THRESHOLD).count();
+            if best.is_none() || inliers > best.unwrap().1{
+                best = Some((circle, inliers));
+            }
+        }
+    }
+    let (hypothesis, _) = best?;
+    let inliers: Vec<(f64, f64)> = pts.iter().filter(|p| distance_to_circle(&hypothesis, p) < RANSAC_THRESHOLD).cloned().collect();
+    if inliers.len() < MIN_CLUSTER_POINTS || (inliers.len() as f64) < MIN_INLIER_FRACTION * pts.len() as f64{
+        return None;
+    }
+    let circle = taubin_fit(&inliers).unwrap_or(hypothesis);
+    let rms = (inliers.iter().map(|p| distance_to_circle(&circle, p).powi(2)).sum::<f64>() / inliers.len() as f64).sqrt();
+    return Some(ClusterFit{
+        circle,
+        rms,
+        arc: arc_coverage(&circle, &inliers),
+        inliers: inliers.len(),
+        points: points.len()
+    });
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn circle_from_three_goes_through_the_points(){
+        let c = circle_from_three((10.0, 0.0), (0.0, 10.0), (-10.0, 0.0)).unwrap();
+        assert!(c.center.0.abs() < 1e-9 && c.center.1.abs() < 1e-9 && (c.radius - 10.0).abs() < 1e-9);
+        assert!(circle_from_three((0.0, 0.0), (1.0, 1.0), (2.0, 2.0)).is_none());
+    }
+
+    #[test]
+    fn taubin_fit_is_exact_on_a_short_arc(){
+        //a tenth of a circle far bigger than the arc, like the bands of a far light
+        let points: Vec<(f64, f64)> = (0..40).map(|i| {
+            let angle = 1.0 + i as f64 / 40.0 * 0.2 * std::f64::consts::PI;
+            (-1500.0 + 2000.0 * angle.cos(), 300.0 + 2000.0 * angle.sin())
+        }).collect();
+        let c = taubin_fit(&points).unwrap();
+        assert!((c.center.0 + 1500.0).abs() < 1e-3 && (c.center.1 - 300.0).abs() < 1e-3 && (c.radius - 2000.0).abs() < 1e-3, "{:?}", c);
+        assert!(taubin_fit(&points[..2]).is_none());
+    }
+
+    #[test]
+    fn fit_cluster_recovers_a_circle_among_outliers(){
+        let (center, radius) = ((40.3, -25.6), 60.0);
+        //three quarters of the circle on the pixel grid, and scattered pixels a third as many
+        let mut points: Vec<(i32, i32)> = (0..300).map(|i| {
+            let angle = i as f64 / 300.0 * 1.5 * std::f64::consts::PI;
+            ((center.0 + radius * angle.cos()).round() as i32, (center.1 + radius * angle.sin()).round() as i32)
+        }).collect();
+        points.extend((0..100).map(|i| ((i * 37 % 150) - 40, (i * 53 % 140) - 90)));
+        let fit = fit_cluster(&points, 7).unwrap();
+        assert!((fit.circle.center.0 - center.0).abs() < 0.3 && (fit.circle.center.1 - center.1).abs() < 0.3, "{:?}", fit.circle);
+        assert!((fit.circle.radius - radius).abs() < 0.3, "{:?}", fit.circle);
+        assert!(fit.inliers >= 300 && fit.inliers < 320, "{}", fit.inliers);
+        assert!((fit.arc - 1.5 * std::f64::consts::PI).abs() < 0.2, "{}", fit.arc);
+    }
+
+    #[test]
+    fn combine_centers_takes_the_weighted_mean_of_the_fits_that_agree(){
+        let fit = |center: (f64, f64), arc: f64| ClusterFit{circle: Circle{center, radius: 80.0}, rms: 0.4, arc, inliers: 150, points: 160};
+        //two long arcs a pixel apart, the first of twice the weight, and a short arc gone far astray
+        let fits = [fit((100.0, 50.0), 4.0), fit((101.0, 50.0), 4.0 / 2f64.powf(0.25)), fit((400.0, -300.0), 1.0)];
+        //from the accumulator's peak
+        let c = combine_centers(&fits, (100.0, 50.0));
+        assert!((c.0 - 100.333).abs() < 0.01 && (c.1 - 50.0).abs() < 0.01, "{:?}", c);
+        assert_eq!(combine_centers(&[], (3.0, 4.0)), (3.0, 4.0));
+    }
+}
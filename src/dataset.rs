@@ -0,0 +1,275 @@
+use probability::distribution::Sample;
+use probability::source::Source;
+
+use crate::{LightSource, Noise, PointLight, Scene, MEAN, SIGMA, SIZE};
+
+
+//distribution a single scene parameter is drawn from
+#[derive(Clone, Copy, Debug)]
+pub enum Distribution{
+    Constant(f64),
+    Uniform(f64, f64),
+    Normal(f64, f64)
+}
+
+impl Distribution{
+    fn parse(values: &[&str]) -> Distribution{
+        let nums: Vec<f64> = values[1..].iter().map(|v| v.parse::<f64>().unwrap()).collect();
+        return match values[0].to_uppercase().as_str(){
+            "CONSTANT" => Distribution::Constant(nums[0]),
+            "UNIFORM" => Distribution::Uniform(nums[0], nums[1]),
+            "NORMAL" => Distribution::Normal(nums[0], nums[1]),
+            other => panic!("unknown distribution {}", other)
+        };
+    }
+
+    pub fn sample(&self, source: &mut probability::source::Default) -> f64{
+        return match *self{
+            Distribution::Constant(v) => v,
+            Distribution::Uniform(a, b) => {
+                if b <= a{
+                    a
+                }
+                else{
+                    probability::distribution::Uniform::new(a, b).sample(source)
+                }
+            }
+            Distribution::Normal(mu, sigma) => {
+                if sigma <= 0.0{
+                    mu
+                }
+                else{
+                    probability::distribution::Gaussian::new(mu, sigma).sample(source)
+                }
+            }
+        };
+    }
+}
+
+
+//xorshift started from a small seed gives tiny numbers for a while, so skip them
+pub fn seeded_source(seed: u64) -> probability::source::Default{
+    let mut source = probability::source::default(seed);
+    for _ in 0..64{
+        source.read_u64();
+    }
+    return source;
+}
+
+
+//what the dataset generator reads from the params file
+pub struct DatasetParams{
+    pub count: usize,
+    pub seed: u64,
+    pub x: Distribution,
+    pub y: Distribution,
+    pub height: Distribution,
+    pub albedo: Distribution,
+    //consecutive scenes share the albedo in groups of this many, for photometric stereo
+    pub frames: usize,
+    //lamps in every scene; the further ones are drawn from x, y and height too, and are intensity
+    //times as bright as the first
+    pub lights: usize,
+    pub intensity: Distribution,
+    pub noise_probability: f64,
+    pub noise_mean: Distribution,
+    pub noise_sigma: Distribution
+}
+
+impl DatasetParams{
+    pub fn init() -> Self{
+        return DatasetParams{
+            count: 100,
+            seed: 1337,
+            x: Distribution::Uniform(0.0, (SIZE * 3 - 1) as f64),
+            y: Distribution::Uniform(0.0, (SIZE * 3 - 1) as f64),
+            height: Distribution::Uniform(50.0, 600.0),
+            albedo: Distribution::Uniform(0.1, 1.0),
+            frames: 1,
+            lights: 1,
+            intensity: Distribution::Constant(1.0),
+            noise_probability: 0.5,
+            noise_mean: Distribution::Constant(MEAN),
+            noise_sigma: Distribution::Constant(SIGMA)
+        };
+    }
+
+    //same format as params.nomad: "KEYWORD values", '#' starts a comment
+    pub fn read(path: &str) -> Self{
+        let mut params = DatasetParams::init();
+        let contents = std::fs::read_to_string(path).unwrap();
+        for line in contents.lines(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty(){
+                continue;
+            }
+            let splitted: Vec<&str> = line.split_whitespace().collect();
+            match splitted[0].to_uppercase().as_str(){
+                "COUNT" => params.count = splitted[1].parse::<usize>().unwrap(),
+                "SEED" => params.seed = splitted[1].parse::<u64>().unwrap(),
+                "X" => params.x = Distribution::parse(&splitted[1..]),
+                "Y" => params.y = Distribution::parse(&splitted[1..]),
+                "HEIGHT" => params.height = Distribution::parse(&splitted[1..]),
+                "ALBEDO" => params.albedo = Distribution::parse(&splitted[1..]),
+                "FRAMES" => params.frames = splitted[1].parse::<usize>().unwrap().max(1),
+                "LIGHTS" => params.lights = splitted[1].parse::<usize>().unwrap().max(1),
+                "INTENSITY" => params.intensity = Distribution::parse(&splitted[1..]),
+                "NOISE_PROBABILITY" => params.noise_probability = splitted[1].parse::<f64>().unwrap(),
+                "NOISE_MEAN" => params.noise_mean = Distribution::parse(&splitted[1..]),
+                "NOISE_SIGMA" => params.noise_sigma = Distribution::parse(&splitted[1..]),
+                other => panic!("unknown dataset parameter {}", other)
+            }
+        }
+        return params;
+    }
+}
+
+
+//ground truth of a single generated scene
+pub struct SceneSample{
+    pub name: String,
+    pub x: i32,
+    pub y: i32,
+    pub height: u32,
+    pub albedo: [f32; 9],
+    pub extra: Vec<PointLight>,
+    pub noise: bool,
+    pub noise_mean: f64,
+    pub noise_sigma: f64,
+    pub noise_seed: u64
+}
+
+impl SceneSample{
+    //shared is the albedo of the scene's group, None for the first scene of a group
+    fn draw(params: &DatasetParams, source: &mut probability::source::Default, idx: usize, shared: Option<[f32; 9]>) -> Self{
+        let x = params.x.sample(source).round() as i32;
+        let y = params.y.sample(source).round() as i32;
+        let height = params.height.sample(source).round().max(1.0) as u32;
+        let albedo = match shared{
+            Some(albedo) => albedo,
+            None => {
+                let mut albedo = [0.0; 9];
+                for a in albedo.iter_mut(){
+                    *a = params.albedo.sample(source).clamp(0.01, 1.0) as f32;
+                }
+                albedo
+            }
+        };
+        let noise = source.read_f64() < params.noise_probability;
+        let noise_mean = params.noise_mean.sample(source);
+        let noise_sigma = params.noise_sigma.sample(source).max(0.0);
+        let noise_seed = source.read_u64();
+        //drawn last, so scenes of a single light come out as they always did
+        let extra = (1..params.lights).map(|_| PointLight{
+            coordinates: (params.x.sample(source).round() as i32, params.y.sample(source).round() as i32),
+            height: params.height.sample(source).round().max(1.0) as u32,
+            intensity: params.intensity.sample(source).max(0.0) as f32
+        }).collect();
+        return SceneSample{
+            name: format!("scene_{:05}", idx),
+            x,
+            y,
+            height,
+            albedo,
+            extra,
+            //a zero sigma gaussian can't be built, so such a sample is just noiseless
+            noise: noise && noise_sigma > 0.0,
+            noise_mean,
+            noise_sigma,
+            noise_seed
+        };
+    }
+
+    //x y h albedo1 .. albedo9, same as the NOMAD blackbox input
+    fn solution_string(&self) -> String{
+        let albedo = self.albedo.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(" ");
+        return format!("{} {} {} {}", self.x, self.y, self.height, albedo);
+    }
+
+    //back from a ground_truth.csv row; datasets of a single light may lack the last column
+    pub fn from_csv_row(row: &str) -> Self{
+        let fields: Vec<&str> = row.split(',').collect();
+        let mut albedo = [0.0; 9];
+        for (i, a) in albedo.iter_mut().enumerate(){
+            *a = fields[8 + i].parse::<f32>().unwrap();
+        }
+        let extra = match fields.get(17){
+            Some(lights) if !lights.is_empty() => lights.split(';').map(|l| {
+                let v: Vec<&str> = l.split_whitespace().collect();
+                PointLight{
+                    coordinates: (v[0].parse::<i32>().unwrap(), v[1].parse::<i32>().unwrap()),
+                    height: v[2].parse::<u32>().unwrap(),
+                    intensity: v[3].parse::<f32>().unwrap()
+                }
+            }).collect(),
+            _ => vec!()
+        };
+        return SceneSample{
+            name: fields[0].to_string(),
+            x: fields[1].parse::<i32>().unwrap(),
+            y: fields[2].parse::<i32>().unwrap(),
+            height: fields[3].parse::<u32>().unwrap(),
+            albedo,
+            extra,
+            noise: fields[4] == "1",
+            noise_mean: fields[5].parse::<f64>().unwrap(),
+            noise_sigma: fields[6].parse::<f64>().unwrap(),
+            noise_seed: fields[7].parse::<u64>().unwrap()
+        };
+    }
+
+    //further lights as "x y h intensity", separated by ';'
+    fn csv_row(&self) -> String{
+        let albedo = self.albedo.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(",");
+        let extra = self.extra.iter().map(|l| format!("{} {} {} {}", l.coordinates.0, l.coordinates.1, l.height, l.intensity)).collect::<Vec<String>>().join(";");
+        return format!("{},{},{},{},{},{},{},{},{},{}", self.name, self.x, self.y, self.height,
+                       self.noise as u8, self.noise_mean, self.noise_sigma, self.noise_seed, albedo, extra);
+    }
+}
+
+pub static CSV_HEADER: &str = "name,x,y,height,noise,noise_mean,noise_sigma,noise_seed,\
+albedo1,albedo2,albedo3,albedo4,albedo5,albedo6,albedo7,albedo8,albedo9,extra_lights";
+
+
+fn render_sample(scene: &mut Scene, no_noise: &Noise, sample: &SceneSample) -> image::GrayImage{
+    let mut ls = LightSource::init(&sample.albedo);
+    ls.coordinates = (sample.x, sample.y);
+    ls.height = sample.height;
+    ls.extra = sample.extra.clone();
+    ls.is_on = true;
+    ls.generate_light_matrix();
+    if sample.noise{
+        let mut ns = Noise::from_params(sample.noise_mean, sample.noise_sigma, sample.noise_seed, (SIZE * 3, SIZE * 3));
+        ns.is_on = true;
+        scene.render(&ls, &ns);
+    }
+    else{
+        scene.render(&ls, no_noise);
+    }
+    return scene.scene_image.clone();
+}
+
+
+pub fn generate_dataset(params_path: &str, out_dir: &str){
+    let params = DatasetParams::read(params_path);
+    std::fs::create_dir_all(out_dir).unwrap();
+    let out_dir = std::path::Path::new(out_dir);
+    let mut source = seeded_source(params.seed);
+    let mut scene = Scene::new(SIZE);
+    let no_noise = Noise::init();
+    let mut csv = vec!(CSV_HEADER.to_string());
+    let mut shared = None;
+    for idx in 0..params.count{
+        if idx % params.frames == 0{
+            shared = None;
+        }
+        let sample = SceneSample::draw(&params, &mut source, idx, shared);
+        shared = Some(sample.albedo);
+        let img = render_sample(&mut scene, &no_noise, &sample);
+        img.save(out_dir.join(sample.name.clone() + ".png")).unwrap();
+        std::fs::write(out_dir.join(sample.name.clone() + ".txt"), sample.solution_string() + "\n").unwrap();
+        csv.push(sample.csv_row());
+        println!("{}/{}: {}", idx + 1, params.count, sample.solution_string());
+    }
+    std::fs::write(out_dir.join("ground_truth.csv"), csv.join("\n") + "\n").unwrap();
+}
@@ -0,0 +1,243 @@
+use crate::albedo_graph::AlbedoSolution;
+use crate::height_fit;
+use crate::label_map::LabelMap;
+use crate::noise_model::NoiseModel;
+use crate::uncertainty::Confidence;
+
+
+//whether a picture can be solved at all. the picture is checked before the solvers run: a light
+//shows as shading inside the patches, so with no patch brighter on one side than the other there is
+//nothing to solve, and clipped pixels follow no falloff. what the solvers leave is checked for
+//systems that were singular or close to it
+//box means the contrast is taken on, and the share of a patch's pixels left out at either end
+static CONTRAST_RADIUS: usize = 3;
+static CONTRAST_QUANTILE: f64 = 0.05;
+//a patch is flat below this contrast over its median brightness, or this many noise deviations
+static MIN_RELATIVE_CONTRAST: f32 = 0.02;
+static FLAT_DEVIATIONS: f32 = 4.0;
+//shares of the picture clipped at white or black
+static MAX_CLIPPED: f32 = 0.05;
+static UNSOLVABLE_CLIPPED: f32 = 0.5;
+//of the joint fit's JᵀJ with its diagonal scaled to 1
+static MAX_CONDITION: f64 = 1e8;
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Severity{
+    //an answer comes out, but some of it is barely determined
+    IllConditioned,
+    Unsolvable
+}
+
+#[derive(Clone, Debug)]
+pub enum Problem{
+    //no patch has any shading: the light is off or far too far away
+    NoLight,
+    //patches are counted from 0, describe shows them from 1 like the albedo names of mcmc
+    FlatPatch{patch: usize, contrast: f32},
+    //shares of the pixels at the brightest value and at 0
+    Clipped{white: f32, black: f32},
+    //patches the albedo graph doesn't tie to the others
+    UnconstrainedAlbedo{patch: usize},
+    Singular{system: &'static str},
+    IllConditioned{system: &'static str, condition: f64},
+    //the height ran into an end of its search, in pixels
+    HeightAtBound{height: f32},
+    NotConverged{system: &'static str}
+}
+
+impl Problem{
+    pub fn severity(&self) -> Severity{
+        return match self{
+            Problem::NoLight => Severity::Unsolvable,
+            Problem::Clipped{white, black} if white + black > UNSOLVABLE_CLIPPED => Severity::Unsolvable,
+            _ => Severity::IllConditioned
+        };
+    }
+
+    pub fn describe(&self) -> String{
+        return match self{
+            Problem::NoLight => "no patch is shaded, the light is off or too far away".to_string(),
+            Problem::FlatPatch{patch, contrast} => format!("patch {} is too flat to show the light (contrast {:.4})", patch + 1, contrast),
+            Problem::Clipped{white, black} => format!("{:.1}% of the picture is clipped at white and {:.1}% at black", white * 100.0, black * 100.0),
+            Problem::UnconstrainedAlbedo{patch} => format!("the albedo of patch {} is not tied to the others", patch + 1),
+            Problem::Singular{system} => format!("{} is singular", system),
+            Problem::IllConditioned{system, condition} => format!("{} is near singular (condition {:.1e})", system, condition),
+            Problem::HeightAtBound{height} => format!("the height ran into the end of its range at {:.1} pixels, the light is barely constrained", height),
+            Problem::NotConverged{system} => format!("{} stopped without converging", system)
+        };
+    }
+}
+
+pub struct Diagnostics{
+    pub problems: Vec<Problem>,
+    //patches with no shading to speak of
+    pub flat: Vec<bool>
+}
+
+impl Diagnostics{
+    //worst severity found, None when nothing was
+    pub fn verdict(&self) -> Option<Severity>{
+        return self.problems.iter().map(|p| p.severity()).max();
+    }
+
+    pub fn is_solvable(&self) -> bool{
+        return self.verdict() != Some(Severity::Unsolvable);
+    }
+
+    //before solving, from the picture alone
+    pub fn check_image(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel) -> Self{
+        let mut problems = vec!();
+        let contrast = patch_contrast(scene_arr, labels);
+        let medians = patch_quantile(scene_arr, labels, 0.5);
+        //the box mean of (2r + 1)² pixels has that much less noise
+        let noise_floor = FLAT_DEVIATIONS * noise.sd() as f32 / (2 * CONTRAST_RADIUS + 1) as f32;
+        let flat: Vec<bool> = (0..labels.count()).map(|p| contrast[p] < (MIN_RELATIVE_CONTRAST * medians[p]).max(noise_floor)).collect();
+        if flat.iter().all(|f| *f){
+            problems.push(Problem::NoLight);
+        }
+        else{
+            for p in (0..labels.count()).filter(|p| flat[*p]){
+                problems.push(Problem::FlatPatch{patch: p, contrast: contrast[p]});
+            }
+        }
+        //the same threshold the solvers leave clamped pixels out by
+        let saturated = scene_arr.iter().cloned().fold(0.0, f32::max) * 0.999;
+        let n = scene_arr.len() as f32;
+        let white = scene_arr.iter().filter(|v| **v >= saturated).count() as f32 / n;
+        let black = scene_arr.iter().filter(|v| **v <= 0.0).count() as f32 / n;
+        if white + black > MAX_CLIPPED{
+            problems.push(Problem::Clipped{white, black});
+        }
+        return Diagnostics{problems, flat};
+    }
+
+    //after solving; confidence is the one of the answer in solution
+    pub fn check_solution(&mut self, albedo: Option<&AlbedoSolution>, confidence: Option<&Confidence>){
+        match albedo{
+            Some(sol) => {
+                for (p, s) in sol.log_sigma.iter().enumerate(){
+                    if s.is_infinite(){
+                        self.problems.push(Problem::UnconstrainedAlbedo{patch: p});
+                    }
+                }
+            }
+            None => self.problems.push(Problem::Singular{system: "the albedo graph"})
+        }
+        let system = "the fit of location, height and albedo";
+        match confidence{
+            Some(c) if c.condition.is_infinite() => self.problems.push(Problem::Singular{system}),
+            Some(c) if c.condition > MAX_CONDITION => self.problems.push(Problem::IllConditioned{system, condition: c.condition}),
+            Some(_) => {}
+            None => self.problems.push(Problem::Singular{system})
+        }
+    }
+
+    //after solving, of the answer's height in a picture of that shape and whether its fit converged
+    pub fn check_fit(&mut self, system: &'static str, height: f32, shape: (usize, usize), converged: bool){
+        if height > 0.0 && height_fit::at_bound(height, shape){
+            self.problems.push(Problem::HeightAtBound{height});
+        }
+        if !converged{
+            self.problems.push(Problem::NotConverged{system});
+        }
+    }
+}
+
+//quantile of the pixels of every patch
+fn patch_quantile(arr: &ndarray::Array2::<f32>, labels: &LabelMap, q: f64) -> Vec<f32>{
+    let mut values = vec![vec!(); labels.count()];
+    for ((j, k), v) in arr.indexed_iter(){
+        values[labels.label(j, k)].push(*v);
+    }
+    return values.into_iter().map(|mut v| {
+        if v.is_empty(){
+            return 0.0;
+        }
+        let i = ((v.len() - 1) as f64 * q).round() as usize;
+        *v.select_nth_unstable_by(i, |a, b| a.total_cmp(b)).1
+    }).collect();
+}
+
+//how much the brightness changes within every patch: the spread of its box means, the ends left out
+//for what is left of the noise and the pixels the box mixes with a neighbour
+fn patch_contrast(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap) -> Vec<f32>{
+    let smoothed = crate::segmentation::local_mean(scene_arr, CONTRAST_RADIUS);
+    let lo = patch_quantile(&smoothed, labels, CONTRAST_QUANTILE);
+    let hi = patch_quantile(&smoothed, labels, 1.0 - CONTRAST_QUANTILE);
+    return lo.iter().zip(hi.iter()).map(|(l, h)| h - l).collect();
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.6, 0.25, 0.9, 0.4, 1.0, 0.15, 0.75, 0.5, 0.3];
+
+    #[test]
+    fn a_lit_picture_has_no_problems(){
+        let labels = LabelMap::grid((900, 600), 3, 3);
+        let arr = render(&labels, (380.0, 250.0), 400.0, &ALBEDO, 0.005, 2);
+        let diagnostics = Diagnostics::check_image(&arr, &labels, &NoiseModel::init(0.0, 0.005));
+        assert!(diagnostics.problems.is_empty(), "{:?}", diagnostics.problems);
+        assert_eq!(diagnostics.verdict(), None);
+        assert!(diagnostics.flat.iter().all(|f| !*f));
+    }
+
+    #[test]
+    fn an_unlit_picture_is_unsolvable(){
+        //every patch evenly bright, noise on top
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let flat = render(&labels, (450.0, 450.0), 1e7, &ALBEDO, 0.005, 3);
+        let diagnostics = Diagnostics::check_image(&flat, &labels, &NoiseModel::init(0.0, 0.005));
+        assert!(matches!(diagnostics.problems[..], [Problem::NoLight]), "{:?}", diagnostics.problems);
+        assert!(!diagnostics.is_solvable());
+        assert!(diagnostics.flat.iter().all(|f| *f));
+    }
+
+    #[test]
+    fn a_picture_far_out_of_one_patch_is_flat_there(){
+        //a low light in the corner leaves the far patch dark and even
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (100.0, 100.0), 40.0, &ALBEDO, 0.0, 1);
+        let diagnostics = Diagnostics::check_image(&arr, &labels, &NoiseModel::clean());
+        assert!(diagnostics.flat[8] && !diagnostics.flat[0], "{:?}", diagnostics.flat);
+        assert!(diagnostics.is_solvable());
+        assert_eq!(diagnostics.verdict(), Some(Severity::IllConditioned));
+    }
+
+    #[test]
+    fn a_picture_mostly_white_is_unsolvable(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (450.0, 450.0), 800.0, &ALBEDO, 0.0, 1).mapv(|v| (4.0 * v).min(1.0));
+        let diagnostics = Diagnostics::check_image(&arr, &labels, &NoiseModel::clean());
+        assert!(diagnostics.problems.iter().any(|p| matches!(p, Problem::Clipped{..})), "{:?}", diagnostics.problems);
+        assert!(!diagnostics.is_solvable());
+    }
+
+    #[test]
+    fn no_albedo_graph_and_no_fit_are_singular(){
+        let mut diagnostics = Diagnostics{problems: vec!(), flat: vec![false; 9]};
+        diagnostics.check_solution(None, None);
+        assert_eq!(diagnostics.problems.len(), 2);
+        assert!(diagnostics.problems.iter().all(|p| matches!(p, Problem::Singular{..})));
+        assert_eq!(diagnostics.verdict(), Some(Severity::IllConditioned));
+    }
+
+    #[test]
+    fn a_height_at_its_bound_or_a_stalled_fit_is_ill_conditioned(){
+        let mut diagnostics = Diagnostics{problems: vec!(), flat: vec![false; 9]};
+        diagnostics.check_fit("the joint fit", 300.0, (900, 900), true);
+        assert!(diagnostics.problems.is_empty(), "{:?}", diagnostics.problems);
+        //the bottom of the height search and the top, 10 frame widths up
+        diagnostics.check_fit("the joint fit", 1.0, (900, 900), true);
+        diagnostics.check_fit("the joint fit", 9000.0, (900, 900), true);
+        assert_eq!(diagnostics.problems.len(), 2);
+        assert!(diagnostics.problems.iter().all(|p| matches!(p, Problem::HeightAtBound{..})));
+        diagnostics.check_fit("the joint fit", 300.0, (900, 900), false);
+        assert!(matches!(diagnostics.problems[2], Problem::NotConverged{..}));
+        assert_eq!(diagnostics.verdict(), Some(Severity::IllConditioned));
+    }
+}
@@ -0,0 +1,142 @@
+use crate::scene_file::Reference;
+
+
+//the solvers are tuned on pictures SIZE * 3 pixels on a side: strides, box radii, cells and blocks
+//are all in those pixels. a larger picture of any width and height is shrunk so that its longer
+//side is that long, keeping its aspect, solved there, and the answers are scaled back; heights over
+//the diagonal come out the same either way. a smaller one is solved as it is, growing it would
+//spread every patch boundary over several pixels
+
+
+pub struct Frame{
+    //of the picture as it was loaded
+    pub original: (usize, usize),
+    //of the picture the solvers see
+    pub shape: (usize, usize),
+    //solver pixels per picture pixel
+    pub scale: f64
+}
+
+impl Frame{
+    //a picture that is solved as it is
+    pub fn identity(shape: (usize, usize)) -> Self{
+        return Frame{original: shape, shape, scale: 1.0};
+    }
+
+    //the longer side of the picture brought down to side pixels
+    pub fn fit(original: (usize, usize), side: usize) -> Self{
+        let scale = (side as f64 / original.0.max(original.1) as f64).min(1.0);
+        let shape = (((original.0 as f64 * scale).round() as usize).max(1), ((original.1 as f64 * scale).round() as usize).max(1));
+        return Frame{original, shape, scale};
+    }
+
+    pub fn is_identity(&self) -> bool{
+        return self.shape == self.original;
+    }
+
+    //linear brightness is averaged, the triangle filter widens with the shrinking so nothing aliases
+    pub fn resample(&self, arr: ndarray::Array2::<f32>) -> ndarray::Array2::<f32>{
+        if self.is_identity(){
+            return arr;
+        }
+        let img = image::ImageBuffer::<image::Luma<f32>, Vec<f32>>::from_fn(self.original.0 as u32, self.original.1 as u32, |x, y| image::Luma([arr[[x as usize, y as usize]]]));
+        let resized = image::imageops::resize(&img, self.shape.0 as u32, self.shape.1 as u32, image::imageops::FilterType::Triangle);
+        return ndarray::Array2::<f32>::from_shape_fn(self.shape, |(j, k)| resized.get_pixel(j as u32, k as u32).0[0]);
+    }
+
+    //what is left of independent pixel noise after resampling: shrinking averages about 1 / scale
+    //pixels each way under the triangle
+    pub fn noise_factor(&self) -> f64{
+        if self.scale >= 1.0{
+            return 1.0;
+        }
+        let r = 1.0 / self.scale;
+        let weights: Vec<f64> = (-(r.ceil() as i32)..=r.ceil() as i32).map(|i| (1.0 - i.abs() as f64 / r).max(0.0)).collect();
+        let sum: f64 = weights.iter().sum();
+        //the variance left along one axis, the same along the other, is the deviation left of both
+        return weights.iter().map(|w| w * w).sum::<f64>() / (sum * sum);
+    }
+
+    //pixel centers stay on pixel centers
+    pub fn to_picture(&self, p: (f32, f32)) -> (f32, f32){
+        let s = self.scale as f32;
+        return ((p.0 + 0.5) / s - 0.5, (p.1 + 0.5) / s - 0.5);
+    }
+
+    pub fn to_frame(&self, p: (f32, f32)) -> (f32, f32){
+        let s = self.scale as f32;
+        return ((p.0 + 0.5) * s - 0.5, (p.1 + 0.5) * s - 0.5);
+    }
+
+    pub fn length_to_picture(&self, l: f32) -> f32{
+        return l / self.scale as f32;
+    }
+
+    //of the frame the solvers see; heights and lengths are given over it
+    pub fn diagonal(&self) -> f32{
+        return ((self.shape.0 * self.shape.0 + self.shape.1 * self.shape.1) as f32).sqrt();
+    }
+
+    //a reference region of the picture, on the frame
+    pub fn reference(&self, r: &Reference) -> Reference{
+        let clamp = |p: (f32, f32)| ((p.0.round().max(0.0) as usize).min(self.shape.0 - 1), (p.1.round().max(0.0) as usize).min(self.shape.1 - 1));
+        return Reference{
+            min: clamp(self.to_frame((r.min.0 as f32, r.min.1 as f32))),
+            max: clamp(self.to_frame((r.max.0 as f32, r.max.1 as f32))),
+            albedo: r.albedo
+        };
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use probability::distribution::Sample;
+
+    #[test]
+    fn fit_shrinks_the_longer_side_and_keeps_the_aspect(){
+        let frame = Frame::fit((1800, 1200), 900);
+        assert_eq!(frame.shape, (900, 600));
+        assert_eq!(frame.scale, 0.5);
+        //a frame pixel covers two picture pixels each way
+        assert_eq!(frame.to_picture((0.0, 0.0)), (0.5, 0.5));
+        assert_eq!(frame.to_frame(frame.to_picture((312.0, 48.5))), (312.0, 48.5));
+        assert_eq!(frame.length_to_picture(100.0), 200.0);
+        let small = Frame::fit((600, 400), 900);
+        assert!(small.is_identity() && small.scale == 1.0);
+    }
+
+    #[test]
+    fn resample_keeps_a_ramp(){
+        let frame = Frame::fit((1800, 1200), 900);
+        let arr = ndarray::Array2::<f32>::from_shape_fn((1800, 1200), |(j, k)| 0.1 + 0.0002 * j as f32 + 0.0003 * k as f32);
+        let small = frame.resample(arr);
+        for (j, k) in [(10, 10), (450, 300), (880, 590)]{
+            let p = frame.to_picture((j as f32, k as f32));
+            let expected = 0.1 + 0.0002 * p.0 + 0.0003 * p.1;
+            assert!((small[[j, k]] - expected).abs() < 1e-4, "{} {} {} {}", j, k, small[[j, k]], expected);
+        }
+    }
+
+    #[test]
+    fn noise_factor_is_the_noise_left(){
+        let mut source = crate::dataset::seeded_source(3);
+        let gaussian = probability::distribution::Gaussian::new(0.0, 0.1);
+        let frame = Frame::fit((1800, 1800), 600);
+        let arr = ndarray::Array2::<f32>::from_shape_fn((1800, 1800), |_| 0.5 + gaussian.sample(&mut source) as f32);
+        let small = frame.resample(arr);
+        let mean = small.mean().unwrap();
+        let sd = (small.mapv(|v| (v - mean) * (v - mean)).mean().unwrap() as f64).sqrt();
+        assert!((sd / 0.1 / frame.noise_factor() - 1.0).abs() < 0.1, "{} {}", sd, frame.noise_factor());
+    }
+
+    #[test]
+    fn reference_is_moved_onto_the_frame(){
+        let frame = Frame::fit((1800, 1200), 900);
+        let r = frame.reference(&Reference{min: (400, 300), max: (2000, 601), albedo: 0.7});
+        assert_eq!(r.min, (200, 150));
+        assert_eq!(r.max, (899, 300));
+        assert_eq!(r.albedo, 0.7);
+    }
+}
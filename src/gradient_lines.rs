@@ -0,0 +1,205 @@
+use crate::label_map::LabelMap;
+use crate::linalg::solve_linear_system;
+
+
+//on a uniform patch lit by a point source the brightness gradient points along the ground towards
+//the foot of the light, so every pixel gives a line through it and the answer is the point closest
+//to all of them: Σ w (I - ddᵀ) q = Σ w (I - ddᵀ) p
+static REWEIGHT_PASSES: usize = 8;
+//1 - cos of the angle between a gradient and the direction to the estimate that still counts as
+//agreeing; noise gradients point anywhere and get cut off by it
+static ANGLE_SCALE: f64 = 0.05;
+
+
+pub struct GradientLocation{
+    pub location: (f32, f32),
+    //smaller over larger eigenvalue of Σ w (I - ddᵀ), near 0 when all the lines are parallel
+    pub condition: f32,
+    pub lines: usize,
+    //how well the gradients point at the location, see agreement
+    pub agreement: f32
+}
+
+struct GradientLine{
+    pos: (f64, f64),
+    dir: (f64, f64),
+    magnitude2: f64
+}
+
+//per pixel differences are mostly noise, so gradients are taken between box means STEP pixels apart
+static BOX_RADIUS: usize = 3;
+static STEP: usize = 4;
+static STRIDE: usize = 2;
+
+//box averaged gradients of pixels whose whole neighbourhood is in one patch
+fn gradient_lines(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap) -> Vec<GradientLine>{
+    let shape = scene_arr.shape();
+    //integral image, one row and column of padding
+    let mut sums = ndarray::Array2::<f64>::zeros((shape[0] + 1, shape[1] + 1));
+    for j in 0..shape[0]{
+        for k in 0..shape[1]{
+            sums[[j + 1, k + 1]] = scene_arr[[j, k]] as f64 + sums[[j, k + 1]] + sums[[j + 1, k]] - sums[[j, k]];
+        }
+    }
+    let box_mean = |j: usize, k: usize| -> f64 {
+        let (j0, k0, j1, k1) = (j - BOX_RADIUS, k - BOX_RADIUS, j + BOX_RADIUS + 1, k + BOX_RADIUS + 1);
+        return (sums[[j1, k1]] - sums[[j0, k1]] - sums[[j1, k0]] + sums[[j0, k0]]) / ((2 * BOX_RADIUS + 1) * (2 * BOX_RADIUS + 1)) as f64;
+    };
+    let reach = BOX_RADIUS + STEP;
+    let uniform = labels.uniform_boxes(reach);
+    let mut lines = vec!();
+    //a picture narrower than the neighbourhood has no lines
+    for j in (reach..shape[0].saturating_sub(reach)).step_by(STRIDE){
+        for k in (reach..shape[1].saturating_sub(reach)).step_by(STRIDE){
+            if !uniform[[j, k]]{
+                continue;
+            }
+            let gx = box_mean(j + STEP, k) - box_mean(j - STEP, k);
+            let gy = box_mean(j, k + STEP) - box_mean(j, k - STEP);
+            let magnitude2 = gx * gx + gy * gy;
+            if magnitude2 <= 0.0{
+                continue;
+            }
+            let magnitude = magnitude2.sqrt();
+            lines.push(GradientLine{
+                pos: (j as f64, k as f64),
+                dir: (gx / magnitude, gy / magnitude),
+                magnitude2
+            });
+        }
+    }
+    return lines;
+}
+
+fn intersect(lines: &[GradientLine], estimate: Option<(f64, f64)>) -> Option<((f64, f64), f64)>{
+    let mut a = ndarray::Array2::<f64>::zeros((2, 2));
+    let mut b = ndarray::Array1::<f64>::zeros(2);
+    for l in lines{
+        //the angular error of a gradient is ~ noise / |∇I|, so far from the light the line
+        //misses by r times that
+        let mut w = l.magnitude2;
+        if let Some(q) = estimate{
+            let r2 = (l.pos.0 - q.0).powi(2) + (l.pos.1 - q.1).powi(2);
+            w /= r2 + 1.0;
+            //the gradient has a sign too: brightness grows towards the light
+            let r = r2.sqrt().max(1e-6);
+            let cos = (l.dir.0 * (q.0 - l.pos.0) + l.dir.1 * (q.1 - l.pos.1)) / r;
+            let e = (1.0 - cos) / ANGLE_SCALE;
+            w /= 1.0 + e * e;
+        }
+        let m = [[1.0 - l.dir.0 * l.dir.0, -l.dir.0 * l.dir.1],
+                 [-l.dir.0 * l.dir.1, 1.0 - l.dir.1 * l.dir.1]];
+        for r in 0..2{
+            for c in 0..2{
+                a[[r, c]] += w * m[r][c];
+            }
+            b[r] += w * (m[r][0] * l.pos.0 + m[r][1] * l.pos.1);
+        }
+    }
+    let q = solve_linear_system(&a, &b)?;
+    //eigenvalues of the symmetric 2x2 system
+    let tr = a[[0, 0]] + a[[1, 1]];
+    let det = a[[0, 0]] * a[[1, 1]] - a[[0, 1]] * a[[1, 0]];
+    let disc = (tr * tr / 4.0 - det).max(0.0).sqrt();
+    let (small, large) = (tr / 2.0 - disc, tr / 2.0 + disc);
+    let condition = if large > 0.0 {small / large} else {0.0};
+    return Some(((q[0], q[1]), condition));
+}
+
+//|∇|² weighted mean cosine between the gradients and the directions to the point: close to 1 when
+//the brightness everywhere grows towards it, around 0 for noise or a point off to the side
+fn agreement_with(lines: &[GradientLine], point: (f64, f64)) -> f64{
+    let (mut sc, mut sw) = (0.0, 0.0);
+    for l in lines{
+        let r = ((point.0 - l.pos.0).powi(2) + (point.1 - l.pos.1).powi(2)).sqrt().max(1e-6);
+        sc += l.magnitude2 * (l.dir.0 * (point.0 - l.pos.0) + l.dir.1 * (point.1 - l.pos.1)) / r;
+        sw += l.magnitude2;
+    }
+    return if sw > 0.0 {sc / sw} else {0.0};
+}
+
+//agreement of the image gradients with each of the candidate locations
+pub fn agreement(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, points: &[(f32, f32)]) -> Vec<f32>{
+    let lines = gradient_lines(scene_arr, labels);
+    return points.iter().map(|p| agreement_with(&lines, (p.0 as f64, p.1 as f64)) as f32).collect();
+}
+
+//starts are other estimates to reweight from besides the plain least squares intersection, which
+//ignores the sign of the gradients and can settle on the wrong side; the most agreeing answer is kept
+pub fn solve_loc_gradient(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, starts: &[(f32, f32)]) -> Option<GradientLocation>{
+    let lines = gradient_lines(scene_arr, labels);
+    if lines.len() < 2{
+        return None;
+    }
+    let mut initial: Vec<Option<(f64, f64)>> = vec!(None);
+    initial.extend(starts.iter().map(|s| Some((s.0 as f64, s.1 as f64))));
+    let mut best: Option<GradientLocation> = None;
+    for start in initial{
+        let mut res = intersect(&lines, start);
+        for _ in 0..REWEIGHT_PASSES{
+            res = res.and_then(|r| intersect(&lines, Some(r.0)));
+        }
+        if let Some(r) = res{
+            let candidate = GradientLocation{
+                location: (r.0.0 as f32, r.0.1 as f32),
+                condition: r.1 as f32,
+                lines: lines.len(),
+                agreement: agreement_with(&lines, r.0) as f32
+            };
+            if best.as_ref().is_none_or(|b| candidate.agreement > b.agreement){
+                best = Some(candidate);
+            }
+        }
+    }
+    return best;
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.6, 0.25, 0.9, 0.4, 1.0, 0.15, 0.75, 0.5, 0.3];
+
+    #[test]
+    fn solve_loc_gradient_finds_the_light_of_a_noiseless_scene(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (312.4, 655.7), 250.0, &ALBEDO, 0.0, 1);
+        let sol = solve_loc_gradient(&arr, &labels, &[]).unwrap();
+        assert!((sol.location.0 - 312.4).abs() < 0.2 && (sol.location.1 - 655.7).abs() < 0.2, "{:?}", sol.location);
+        assert!(sol.condition > 0.3, "{}", sol.condition);
+    }
+
+    #[test]
+    fn solve_loc_gradient_finds_the_light_of_a_noisy_scene(){
+        //a frame taller than it is wide
+        let labels = LabelMap::grid((600, 1050), 3, 3);
+        let arr = render(&labels, (340.0, 610.0), 300.0, &ALBEDO, 0.005, 2);
+        let sol = solve_loc_gradient(&arr, &labels, &[]).unwrap();
+        assert!((sol.location.0 - 340.0).abs() < 3.0 && (sol.location.1 - 610.0).abs() < 3.0, "{:?}", sol.location);
+    }
+
+    #[test]
+    fn solve_loc_gradient_finds_a_light_outside_the_frame(){
+        //half a frame width to the left, the plain intersection may settle behind the picture
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (-450.0, 420.0), 300.0, &ALBEDO, 0.0, 1);
+        let sol = solve_loc_gradient(&arr, &labels, &[(-300.0, 300.0)]).unwrap();
+        assert!((sol.location.0 + 450.0).abs() < 2.0 && (sol.location.1 - 420.0).abs() < 2.0, "{:?}", sol.location);
+        assert!(sol.agreement > 0.9, "{}", sol.agreement);
+    }
+
+    #[test]
+    fn an_unlit_picture_has_no_gradient_lines(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        assert!(solve_loc_gradient(&ndarray::Array2::<f32>::zeros((900, 900)), &labels, &[]).is_none());
+    }
+
+    #[test]
+    fn a_picture_narrower_than_a_neighbourhood_has_no_gradient_lines(){
+        let labels = LabelMap::grid((5, 600), 3, 1);
+        let arr = render(&labels, (2.0, 300.0), 200.0, &ALBEDO, 0.0, 1);
+        assert!(solve_loc_gradient(&arr, &labels, &[]).is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::label_map::LabelMap;
+use crate::noise_model::NoiseModel;
+use ndarray::parallel::prelude::*;
+
+
+//with the location known every patch is a(p) * h³ / (r² + h²)^1.5; for a given h the best albedo of
+//a patch is Σ g I / Σ g², so only h is left to search for
+static COARSE_STEPS: usize = 48;
+static GOLDEN_ITERATIONS: usize = 30;
+pub static MIN_HEIGHT: f64 = 1.0;
+//in frame widths
+pub static MAX_HEIGHT_FRAMES: f64 = 10.0;
+//a height this close to either end of the search is taken to be stuck there
+pub static BOUND_MARGIN: f64 = 1.05;
+
+
+pub struct HeightFit{
+    pub height: f32,
+    //root mean square of the pixel residuals at that height
+    pub residual: f64
+}
+
+//squared radial distances and noiseless brightness of the pixels of each patch
+struct RadialProfile{
+    patches: Vec<Vec<(f64, f64)>>
+}
+
+impl RadialProfile{
+    fn init(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32)) -> Self{
+        let mut patches = vec![vec!(); labels.count()];
+        //clamped pixels near the light don't follow the profile
+        let saturated = scene_arr.iter().cloned().fold(0.0, f32::max) * 0.999;
+        for ((j, k), v) in scene_arr.indexed_iter(){
+            if *v >= saturated{
+                continue;
+            }
+            let r2 = (j as f64 - location.0 as f64).powi(2) + (k as f64 - location.1 as f64).powi(2);
+            patches[labels.label(j, k)].push((r2, noise.signal(*v as f64)));
+        }
+        return RadialProfile{patches};
+    }
+
+    fn pixels(&self) -> usize{
+        return self.patches.iter().map(|p| p.len()).sum();
+    }
+
+    //sum of squared residuals with every patch's albedo at its optimum, patches in parallel
+    fn cost(&self, h: f64) -> f64{
+        let h2 = h * h;
+        let h3 = h2 * h;
+        let costs: Vec<f64> = self.patches.par_iter().map(|patch| {
+            let (mut gg, mut gi, mut ii) = (0.0, 0.0, 0.0);
+            for (r2, i) in patch{
+                let s = r2 + h2;
+                let g = h3 / (s * s.sqrt());
+                gg += g * g;
+                gi += g * i;
+                ii += i * i;
+            }
+            return if gg > 0.0 {ii - gi * gi / gg} else {ii};
+        }).collect();
+        //summed in order, so the search takes the same steps every run
+        let cost: f64 = costs.iter().sum();
+        return cost.max(0.0);
+    }
+}
+
+pub fn fit_height(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32)) -> Option<HeightFit>{
+    let profile = RadialProfile::init(scene_arr, labels, noise, location);
+    let pixels = profile.pixels();
+    if pixels == 0{
+        return None;
+    }
+    //log spaced grid first, the cost has shallow valleys at the far end
+    let (lo, hi) = (MIN_HEIGHT.ln(), max_height(scene_arr.dim()).ln());
+    let grid: Vec<f64> = (0..=COARSE_STEPS).map(|i| lo + (hi - lo) * i as f64 / COARSE_STEPS as f64).collect();
+    let costs: Vec<f64> = grid.iter().map(|t| profile.cost(t.exp())).collect();
+    let best = (0..costs.len()).min_by(|a, b| costs[*a].total_cmp(&costs[*b]))?;
+    //golden section between the neighbours of the best grid point
+    let mut a = grid[best.saturating_sub(1)];
+    let mut b = grid[(best + 1).min(COARSE_STEPS)];
+    let ratio = (5.0f64.sqrt() - 1.0) / 2.0;
+    let mut c = b - ratio * (b - a);
+    let mut d = a + ratio * (b - a);
+    let mut fc = profile.cost(c.exp());
+    let mut fd = profile.cost(d.exp());
+    for _ in 0..GOLDEN_ITERATIONS{
+        if fc < fd{
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - ratio * (b - a);
+            fc = profile.cost(c.exp());
+        }
+        else{
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + ratio * (b - a);
+            fd = profile.cost(d.exp());
+        }
+    }
+    let (t, cost) = if fc < fd {(c, fc)} else {(d, fd)};
+    let (t, cost) = if costs[best] < cost {(grid[best], costs[best])} else {(t, cost)};
+    return Some(HeightFit{
+        height: t.exp() as f32,
+        residual: (cost / pixels as f64).sqrt()
+    });
+}
+
+//the top of the range fit_height searches
+pub fn max_height(shape: (usize, usize)) -> f64{
+    return MAX_HEIGHT_FRAMES * shape.0.max(shape.1) as f64;
+}
+
+//whether a height sits at either end of the range fit_height searches, where the cost was still
+//falling and the true height may be anywhere past it
+pub fn at_bound(height: f32, shape: (usize, usize)) -> bool{
+    let h = height as f64;
+    return h < MIN_HEIGHT * BOUND_MARGIN || h > max_height(shape) / BOUND_MARGIN;
+}
+
+//root mean square of the pixel residuals at a height found some other way
+pub fn residual_at(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32), height: f32) -> Option<f64>{
+    let profile = RadialProfile::init(scene_arr, labels, noise, location);
+    let pixels = profile.pixels();
+    if pixels == 0{
+        return None;
+    }
+    return Some((profile.cost(height as f64) / pixels as f64).sqrt());
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.5, 0.95, 0.2, 0.7, 0.35, 1.0, 0.15, 0.6, 0.8];
+
+    #[test]
+    fn fit_height_recovers_the_height_of_a_noiseless_render(){
+        //low, middle and high lights, the last over a wide frame
+        for (shape, light, h) in [((900, 900), (450.0, 450.0), 30.0), ((900, 900), (120.0, 700.0), 300.0), ((1500, 600), (1210.0, 240.0), 2500.0)]{
+            let labels = LabelMap::grid(shape, 3, 3);
+            let arr = render(&labels, light, h, &ALBEDO, 0.0, 1);
+            let fit = fit_height(&arr, &labels, &NoiseModel::clean(), (light.0 as f32, light.1 as f32)).unwrap();
+            assert!((fit.height as f64 - h).abs() < 1e-3 * h, "{} {}", h, fit.height);
+            assert!(fit.residual < 1e-5, "{}", fit.residual);
+        }
+    }
+
+    #[test]
+    fn fit_height_recovers_the_height_of_a_noisy_render(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (380.0, 520.0), 450.0, &ALBEDO, 0.005, 4);
+        let fit = fit_height(&arr, &labels, &NoiseModel::init(0.0, 0.005), (380.0, 520.0)).unwrap();
+        assert!((fit.height - 450.0).abs() < 2.0, "{}", fit.height);
+        //the residual is the noise
+        assert!((fit.residual - 0.005).abs() < 2e-4, "{}", fit.residual);
+        //the same residual when asked at that height, a larger one away from it
+        let at = residual_at(&arr, &labels, &NoiseModel::init(0.0, 0.005), (380.0, 520.0), fit.height).unwrap();
+        assert!((at - fit.residual).abs() < 1e-9, "{} {}", at, fit.residual);
+        assert!(residual_at(&arr, &labels, &NoiseModel::init(0.0, 0.005), (380.0, 520.0), 300.0).unwrap() > 2.0 * fit.residual);
+    }
+}
@@ -0,0 +1,126 @@
+//which patch every pixel belongs to, indexed [[j, k]] like the scene array;
+//patches are numbered 0 .. count, for the mondrian grid 3 * row + column
+#[derive(Clone)]
+pub struct LabelMap{
+    labels: ndarray::Array2::<usize>,
+    count: usize
+}
+
+impl LabelMap{
+    //rows x cols grid of equal cells over an image of the given shape
+    pub fn grid(shape: (usize, usize), rows: usize, cols: usize) -> Self{
+        let labels = ndarray::Array2::<usize>::from_shape_fn(shape, |(j, k)| {
+            let row = (k * rows / shape.1).min(rows - 1);
+            let col = (j * cols / shape.0).min(cols - 1);
+            cols * row + col
+        });
+        return LabelMap{labels, count: rows * cols};
+    }
+
+    //labels must already be 0 .. count
+    pub fn from_labels(labels: ndarray::Array2::<usize>, count: usize) -> Self{
+        return LabelMap{labels, count};
+    }
+
+    pub fn label(&self, j: usize, k: usize) -> usize{
+        return self.labels[[j, k]];
+    }
+
+    pub fn count(&self) -> usize{
+        return self.count;
+    }
+
+    pub fn shape(&self) -> (usize, usize){
+        return (self.labels.shape()[0], self.labels.shape()[1]);
+    }
+
+    //whether the (2 radius + 1)² box around every pixel is inside the image and inside one patch,
+    //from integral images of label changes between neighbours in j and in k
+    pub fn uniform_boxes(&self, radius: usize) -> ndarray::Array2::<bool>{
+        let (w, h) = self.shape();
+        let mut changes_j = ndarray::Array2::<i64>::zeros((w + 1, h + 1));
+        let mut changes_k = ndarray::Array2::<i64>::zeros((w + 1, h + 1));
+        for j in 0..w{
+            for k in 0..h{
+                let l = self.labels[[j, k]];
+                let cj = (j + 1 < w && self.labels[[j + 1, k]] != l) as i64;
+                let ck = (k + 1 < h && self.labels[[j, k + 1]] != l) as i64;
+                changes_j[[j + 1, k + 1]] = cj + changes_j[[j, k + 1]] + changes_j[[j + 1, k]] - changes_j[[j, k]];
+                changes_k[[j + 1, k + 1]] = ck + changes_k[[j, k + 1]] + changes_k[[j + 1, k]] - changes_k[[j, k]];
+            }
+        }
+        //over j0 .. j1 and k0 .. k1, ends exclusive
+        let sum = |s: &ndarray::Array2::<i64>, j0: usize, k0: usize, j1: usize, k1: usize| s[[j1, k1]] - s[[j0, k1]] - s[[j1, k0]] + s[[j0, k0]];
+        return ndarray::Array2::<bool>::from_shape_fn((w, h), |(j, k)| {
+            if j < radius || k < radius || j + radius >= w || k + radius >= h{
+                return false;
+            }
+            //a change counts when both of its pixels are in the box
+            return sum(&changes_j, j - radius, k - radius, j + radius, k + radius + 1) == 0
+                && sum(&changes_k, j - radius, k - radius, j + radius + 1, k + radius) == 0;
+        });
+    }
+
+    //the label of every second pixel both ways, for a picture of half the size
+    pub fn halved(&self) -> Self{
+        let (w, h) = self.shape();
+        let labels = ndarray::Array2::<usize>::from_shape_fn((w / 2, h / 2), |(j, k)| self.labels[[2 * j, 2 * k]]);
+        return LabelMap{labels, count: self.count};
+    }
+
+    //4-neighbour pixel pairs on patch boundaries, with the step from the first pixel to the second
+    pub fn boundary_pairs(&self) -> Vec<((usize, usize), (i32, i32))>{
+        let (w, h) = self.shape();
+        let mut pairs = vec!();
+        for j in 0..w{
+            for k in 0..h{
+                let l = self.labels[[j, k]];
+                if j + 1 < w && self.labels[[j + 1, k]] != l{
+                    pairs.push(((j, k), (1, 0)));
+                }
+                if k + 1 < h && self.labels[[j, k + 1]] != l{
+                    pairs.push(((j, k), (0, 1)));
+                }
+            }
+        }
+        return pairs;
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn grid_numbers_rows_first(){
+        let labels = LabelMap::grid((600, 300), 3, 2);
+        assert_eq!(labels.count(), 6);
+        assert_eq!(labels.label(0, 0), 0);
+        assert_eq!(labels.label(599, 0), 1);
+        assert_eq!(labels.label(0, 299), 4);
+        assert_eq!(labels.label(599, 299), 5);
+    }
+
+    #[test]
+    fn halved_keeps_the_patches(){
+        let labels = LabelMap::grid((601, 300), 3, 2);
+        let half = labels.halved();
+        assert_eq!(half.shape(), (300, 150));
+        assert_eq!(half.count(), 6);
+        assert!((0..300).all(|j| (0..150).all(|k| half.label(j, k) == labels.label(2 * j, 2 * k))));
+        //one boundary between the columns, two between the rows
+        assert_eq!(half.boundary_pairs().len(), 150 + 2 * 300);
+    }
+
+    #[test]
+    fn uniform_boxes_stay_inside_one_patch(){
+        let labels = LabelMap::grid((60, 40), 2, 3);
+        let uniform = labels.uniform_boxes(3);
+        for ((j, k), u) in uniform.indexed_iter(){
+            let inside = j >= 3 && k >= 3 && j + 3 < 60 && k + 3 < 40;
+            let one = inside && (j - 3..=j + 3).all(|jj| (k - 3..=k + 3).all(|kk| labels.label(jj, kk) == labels.label(j, k)));
+            assert_eq!(*u, one, "{} {}", j, k);
+        }
+    }
+}
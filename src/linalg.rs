@@ -0,0 +1,146 @@
+//small dense linear algebra, enough for the normal equations of the solvers
+
+
+//gaussian elimination with partial pivoting, None if the matrix is (numerically) singular
+pub fn solve_linear_system(a: &ndarray::Array2::<f64>, b: &ndarray::Array1::<f64>) -> Option<ndarray::Array1::<f64>>{
+    let n = b.len();
+    let mut m = a.clone();
+    let mut x = b.clone();
+    let scale = m.iter().fold(0.0_f64, |acc, v| acc.max(v.abs()));
+    if scale == 0.0 || !scale.is_finite(){
+        return None;
+    }
+    for col in 0..n{
+        let mut pivot = col;
+        for row in col + 1..n{
+            if m[[row, col]].abs() > m[[pivot, col]].abs(){
+                pivot = row;
+            }
+        }
+        if m[[pivot, col]].abs() < scale * 1e-13{
+            return None;
+        }
+        if pivot != col{
+            for k in 0..n{
+                m.swap([col, k], [pivot, k]);
+            }
+            x.swap(col, pivot);
+        }
+        for row in col + 1..n{
+            let factor = m[[row, col]] / m[[col, col]];
+            if factor == 0.0{
+                continue;
+            }
+            for k in col..n{
+                m[[row, k]] -= factor * m[[col, k]];
+            }
+            x[row] -= factor * x[col];
+        }
+    }
+    for col in (0..n).rev(){
+        let mut acc = x[col];
+        for k in col + 1..n{
+            acc -= m[[col, k]] * x[k];
+        }
+        x[col] = acc / m[[col, col]];
+    }
+    return Some(x);
+}
+
+//inverse by solving for the columns of the identity, None if singular
+pub fn invert(a: &ndarray::Array2::<f64>) -> Option<ndarray::Array2::<f64>>{
+    let n = a.shape()[0];
+    let mut inv = ndarray::Array2::<f64>::zeros((n, n));
+    for i in 0..n{
+        let mut e = ndarray::Array1::<f64>::zeros(n);
+        e[i] = 1.0;
+        let column = solve_linear_system(a, &e)?;
+        for r in 0..n{
+            inv[[r, i]] = column[r];
+        }
+    }
+    return Some(inv);
+}
+
+//lower triangular l with l lᵀ = a, None if a isn't positive definite
+pub fn cholesky(a: &ndarray::Array2::<f64>) -> Option<ndarray::Array2::<f64>>{
+    let n = a.shape()[0];
+    let mut l = ndarray::Array2::<f64>::zeros((n, n));
+    for i in 0..n{
+        for j in 0..=i{
+            let mut acc = a[[i, j]];
+            for k in 0..j{
+                acc -= l[[i, k]] * l[[j, k]];
+            }
+            if i == j{
+                if acc <= 0.0{
+                    return None;
+                }
+                l[[i, i]] = acc.sqrt();
+            }
+            else{
+                l[[i, j]] = acc / l[[j, j]];
+            }
+        }
+    }
+    return Some(l);
+}
+
+//1-norm condition number of a symmetric positive semidefinite matrix once its diagonal is scaled to
+//1, so that parameters in different units don't count as ill-conditioning; None if singular
+pub fn condition_number(a: &ndarray::Array2::<f64>) -> Option<f64>{
+    let n = a.shape()[0];
+    let d: Vec<f64> = (0..n).map(|i| a[[i, i]].max(0.0).sqrt()).collect();
+    if d.contains(&0.0){
+        return None;
+    }
+    let scaled = ndarray::Array2::<f64>::from_shape_fn((n, n), |(i, j)| a[[i, j]] / (d[i] * d[j]));
+    let inv = invert(&scaled)?;
+    let norm = |m: &ndarray::Array2::<f64>| (0..n).map(|j| (0..n).map(|i| m[[i, j]].abs()).sum::<f64>()).fold(0.0, f64::max);
+    return Some(norm(&scaled) * norm(&inv));
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn solve_linear_system_finds_the_known_solution(){
+        //needs a row swap to keep the pivots away from 0
+        let a = ndarray::arr2(&[[1e-9, 2.0, 1.0], [3.0, -1.0, 0.5], [0.5, 4.0, -2.0]]);
+        let x = ndarray::arr1(&[1.0, -2.0, 0.5]);
+        let solved = solve_linear_system(&a, &a.dot(&x)).unwrap();
+        assert!(solved.iter().zip(x.iter()).all(|(s, t)| (s - t).abs() < 1e-12), "{:?}", solved);
+    }
+
+    #[test]
+    fn solve_linear_system_refuses_a_singular_matrix(){
+        assert!(solve_linear_system(&ndarray::arr2(&[[1.0, 2.0], [2.0, 4.0]]), &ndarray::arr1(&[1.0, 2.0])).is_none());
+        assert!(solve_linear_system(&ndarray::Array2::<f64>::zeros((2, 2)), &ndarray::arr1(&[1.0, 2.0])).is_none());
+    }
+
+    #[test]
+    fn cholesky_factor_multiplies_back(){
+        let a = ndarray::arr2(&[[4.0, 2.0, 0.6], [2.0, 5.0, 1.0], [0.6, 1.0, 3.0]]);
+        let l = cholesky(&a).unwrap();
+        assert!(l[[0, 1]] == 0.0 && l[[0, 2]] == 0.0 && l[[1, 2]] == 0.0);
+        let back = l.dot(&l.t());
+        assert!(back.iter().zip(a.iter()).all(|(b, t)| (b - t).abs() < 1e-12), "{:?}", back);
+    }
+
+    #[test]
+    fn cholesky_refuses_a_matrix_that_is_not_positive_definite(){
+        assert!(cholesky(&ndarray::arr2(&[[1.0, 2.0], [2.0, 1.0]])).is_none());
+    }
+
+    #[test]
+    fn condition_number_ignores_the_units_of_the_parameters(){
+        let a = ndarray::arr2(&[[1e6, 0.0], [0.0, 1.0]]);
+        assert!((condition_number(&a).unwrap() - 1.0).abs() < 1e-12);
+        //two parameters that nearly do the same
+        let b = ndarray::arr2(&[[4.0, 0.999 * 2.0 * 3.0], [0.999 * 2.0 * 3.0, 9.0]]);
+        assert!((condition_number(&b).unwrap() - 1.999 * 1.999 / 0.001999).abs() < 1e-6, "{:?}", condition_number(&b));
+        assert!(condition_number(&ndarray::arr2(&[[1.0, 0.0], [0.0, 0.0]])).is_none());
+    }
+}
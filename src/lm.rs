@@ -0,0 +1,333 @@
+use crate::label_map::LabelMap;
+use crate::linalg::solve_linear_system;
+use crate::noise_model::NoiseModel;
+
+
+//JᵀJ and Jᵀr of a least-squares problem at some point, cost is 0.5 * Σr²
+pub struct NormalEquations{
+    pub cost: f64,
+    pub jtj: ndarray::Array2::<f64>,
+    pub jtr: ndarray::Array1::<f64>
+}
+
+impl NormalEquations{
+    pub fn init(n: usize) -> Self{
+        return NormalEquations{
+            cost: 0.0,
+            jtj: ndarray::Array2::<f64>::zeros((n, n)),
+            jtr: ndarray::Array1::<f64>::zeros(n)
+        };
+    }
+
+    //adds one residual with its sparse jacobian row (param index, derivative)
+    pub fn add(&mut self, residual: f64, row: &[(usize, f64)]){
+        self.cost += 0.5 * residual * residual;
+        for &(i, di) in row{
+            self.jtr[i] += di * residual;
+            for &(j, dj) in row{
+                self.jtj[[i, j]] += di * dj;
+            }
+        }
+    }
+
+    //adds the residuals of other, equations built over a part of the pixels
+    pub fn merge(&mut self, other: &NormalEquations){
+        self.cost += other.cost;
+        self.jtj += &other.jtj;
+        self.jtr += &other.jtr;
+    }
+}
+
+pub struct LmResult{
+    pub params: Vec<f64>,
+    pub cost: f64,
+    pub iterations: usize,
+    //the cost or the parameters stopped changing; not when lambda blew up
+    pub converged: bool
+}
+
+//model(params, with_jacobian) gives the normal equations (only cost is needed without jacobian),
+//project clamps params back into their bounds after each step
+pub fn levenberg_marquardt<F, P>(init: &[f64], model: F, project: P, max_iter: usize) -> LmResult
+where F: Fn(&[f64], bool) -> NormalEquations, P: Fn(&mut [f64]){
+    let n = init.len();
+    let mut params = init.to_vec();
+    project(&mut params);
+    let mut ne = model(&params, true);
+    let mut lambda = 1e-3;
+    let mut converged = false;
+    let mut iterations = 0;
+    while iterations < max_iter{
+        iterations += 1;
+        let mut a = ne.jtj.clone();
+        for i in 0..n{
+            a[[i, i]] += lambda * ne.jtj[[i, i]].max(1e-12);
+        }
+        let step = match solve_linear_system(&a, &(-&ne.jtr)){
+            Some(step) => step,
+            None => {
+                lambda *= 10.0;
+                if lambda > 1e12{
+                    break;
+                }
+                continue;
+            }
+        };
+        let mut candidate = params.clone();
+        for i in 0..n{
+            candidate[i] += step[i];
+        }
+        project(&mut candidate);
+        let candidate_cost = model(&candidate, false).cost;
+        if candidate_cost < ne.cost{
+            let decrease = (ne.cost - candidate_cost) / ne.cost.max(1e-300);
+            let moved = params.iter().zip(candidate.iter()).map(|(p, c)| (p - c).abs() / (p.abs() + 1e-6)).fold(0.0, f64::max);
+            params = candidate;
+            ne = model(&params, true);
+            lambda = (lambda / 3.0).max(1e-12);
+            if decrease < 1e-10 || moved < 1e-8{
+                converged = true;
+                break;
+            }
+        }
+        else{
+            lambda *= 4.0;
+            //no step along the gradient helps anymore; that may be a minimum just as well as a
+            //bad or non-smooth model, so it isn't reported as converged
+            if lambda > 1e12{
+                break;
+            }
+        }
+    }
+    return LmResult{
+        cost: ne.cost,
+        params,
+        iterations,
+        converged
+    };
+}
+
+
+//observed pixel of a linear image: absolute position, patch index and brightness
+#[derive(Clone, Copy)]
+pub struct PixelSample{
+    pub pos: (f64, f64),
+    pub patch: usize,
+    pub value: f64
+}
+
+pub fn sample_pixels(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, stride: usize) -> Vec<PixelSample>{
+    let mut samples = vec!();
+    for j in (0..scene_arr.shape()[0]).step_by(stride){
+        for k in (0..scene_arr.shape()[1]).step_by(stride){
+            samples.push(PixelSample{
+                pos: (j as f64, k as f64),
+                patch: labels.label(j, k),
+                value: scene_arr[[j, k]] as f64
+            });
+        }
+    }
+    return samples;
+}
+
+//cos³(atan(r/h)) with its derivatives over light x, y and h
+pub fn light_falloff(light: (f64, f64), h: f64, pos: (f64, f64)) -> (f64, f64, f64, f64){
+    let dx = pos.0 - light.0;
+    let dy = pos.1 - light.1;
+    let r2 = dx * dx + dy * dy;
+    let s = r2 + h * h;
+    let s_52 = s.powf(-2.5);
+    let g = h * h * h * s.powf(-1.5);
+    let dg_dx = 3.0 * h * h * h * dx * s_52;
+    let dg_dy = 3.0 * h * h * h * dy * s_52;
+    let dg_dh = 3.0 * h * h * r2 * s_52;
+    return (g, dg_dx, dg_dy, dg_dh);
+}
+
+
+//params are x, y, h and the albedo of every patch; albedo absorbs the light luminosity.
+//residuals are in noise standard deviations
+fn single_light_normal_equations(samples: &[PixelSample], noise: &NoiseModel, params: &[f64], with_jacobian: bool) -> NormalEquations{
+    let mut ne = NormalEquations::init(params.len());
+    let sd = noise.sd();
+    for px in samples{
+        let a = params[3 + px.patch];
+        let (g, dg_dx, dg_dy, dg_dh) = light_falloff((params[0], params[1]), params[2], px.pos);
+        let (expected, slope) = noise.expected(a * g);
+        let residual = (expected - px.value) / sd;
+        if with_jacobian{
+            let d = slope / sd;
+            ne.add(residual, &[(0, d * a * dg_dx), (1, d * a * dg_dy), (2, d * a * dg_dh), (3 + px.patch, d * g)]);
+        }
+        else{
+            ne.cost += 0.5 * residual * residual;
+        }
+    }
+    return ne;
+}
+
+fn project_single_light(params: &mut [f64]){
+    params[2] = params[2].max(1.0);
+    for a in params[3..].iter_mut(){
+        *a = a.max(0.0);
+    }
+}
+
+
+#[derive(Clone)]
+pub struct LmSolution{
+    pub location: (f32, f32),
+    pub height: f32,
+    pub albedo: Vec<f32>,
+    //half the sum of squared residuals in noise standard deviations
+    pub cost: f64,
+    pub iterations: usize,
+    pub converged: bool
+}
+
+impl LmSolution{
+    //albedo / maximum albedo, the same way the gui shows it
+    pub fn relative_albedo(&self) -> Vec<f32>{
+        let max = self.albedo.iter().cloned().fold(f32::MIN, f32::max);
+        let mut res = self.albedo.clone();
+        if max > 0.0{
+            for a in res.iter_mut(){
+                *a /= max;
+            }
+        }
+        return res;
+    }
+}
+
+//best albedo scale for a fixed light, used to turn relative albedo into a starting point
+pub fn fit_albedo_scale(samples: &[PixelSample], light: (f64, f64), h: f64, albedo: &[f32]) -> f64{
+    let mut up = 0.0;
+    let mut down = 0.0;
+    for px in samples{
+        let model = albedo[px.patch] as f64 * light_falloff(light, h, px.pos).0;
+        up += model * px.value;
+        down += model * model;
+    }
+    if down <= 0.0{
+        return 1.0;
+    }
+    return up / down;
+}
+
+//height to start from when solve_height found nothing: a third of the frame, like the gui's squares
+pub fn fallback_height(scene_arr: &ndarray::Array2::<f32>) -> f64{
+    return scene_arr.shape()[0].max(scene_arr.shape()[1]) as f64 / 3.0;
+}
+
+//joint fit of light position, height and albedo, starting from the separate solvers' answers
+pub fn fit_single_light(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32), height: f32, albedo: &[f32]) -> LmSolution{
+    let samples = sample_pixels(scene_arr, labels, 3);
+    let light = (location.0 as f64, location.1 as f64);
+    //solve_height leaves 0 when no ray worked out
+    let mut h = height as f64;
+    if h < 1.0{
+        h = fallback_height(scene_arr);
+    }
+    let albedo: Vec<f32> = (0..labels.count()).map(|p| match albedo.get(p){
+        Some(a) if a.is_finite() && *a > 0.0 => *a,
+        _ => 1.0
+    }).collect();
+    let scale = fit_albedo_scale(&samples, light, h, &albedo);
+    let mut init = vec![light.0, light.1, h];
+    for a in albedo{
+        init.push(a as f64 * scale);
+    }
+    let res = levenberg_marquardt(&init,
+                                  |p, with_jacobian| single_light_normal_equations(&samples, noise, p, with_jacobian),
+                                  project_single_light,
+                                  200);
+    return LmSolution{
+        location: (res.params[0] as f32, res.params[1] as f32),
+        height: res.params[2] as f32,
+        albedo: res.params[3..].iter().map(|p| *p as f32).collect(),
+        cost: res.cost,
+        iterations: res.iterations,
+        converged: res.converged
+    };
+}
+
+
+//the patches of a label map under one light as the solvers model them, with gaussian noise on top
+//when sigma isn't 0, for their tests
+#[cfg(test)]
+pub fn render(labels: &LabelMap, light: (f64, f64), h: f64, albedo: &[f64], sigma: f64, seed: u64) -> ndarray::Array2::<f32>{
+    use probability::distribution::Sample;
+    let mut source = crate::dataset::seeded_source(seed);
+    let gaussian = probability::distribution::Gaussian::new(0.0, sigma.max(1e-12));
+    return ndarray::Array2::<f32>::from_shape_fn(labels.shape(), |(j, k)| {
+        let noise = if sigma > 0.0 {gaussian.sample(&mut source)} else {0.0};
+        return (albedo[labels.label(j, k)] * light_falloff(light, h, (j as f64, k as f64)).0 + noise) as f32;
+    });
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    //y = a exp(b x) through points on it, from a start well off
+    #[test]
+    fn levenberg_marquardt_fits_an_exponential(){
+        let xs: Vec<f64> = (0..20).map(|i| i as f64 / 4.0).collect();
+        let model = |p: &[f64], _with_jacobian: bool| -> NormalEquations {
+            let mut ne = NormalEquations::init(2);
+            for x in xs.iter(){
+                let e = (p[1] * x).exp();
+                ne.add(p[0] * e - 2.5 * (-0.7 * x).exp(), &[(0, e), (1, p[0] * x * e)]);
+            }
+            return ne;
+        };
+        let res = levenberg_marquardt(&[1.0, 0.0], model, |_| {}, 100);
+        assert!(res.converged);
+        assert!((res.params[0] - 2.5).abs() < 1e-6 && (res.params[1] + 0.7).abs() < 1e-6, "{:?}", res.params);
+    }
+
+    //a jacobian of the wrong sign, no step it proposes lowers the cost
+    #[test]
+    fn levenberg_marquardt_does_not_call_a_stall_converged(){
+        let model = |p: &[f64], _with_jacobian: bool| -> NormalEquations {
+            let mut ne = NormalEquations::init(1);
+            ne.add(p[0] - 3.0, &[(0, -1.0)]);
+            return ne;
+        };
+        let res = levenberg_marquardt(&[1.0], model, |_| {}, 100);
+        assert!(!res.converged);
+        assert_eq!(res.params[0], 1.0);
+    }
+
+    #[test]
+    fn fit_single_light_converges_from_a_perturbed_start(){
+        let albedo = [0.35, 0.8, 0.55, 1.0, 0.2, 0.65, 0.45, 0.9, 0.3];
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (612.0, 205.0), 420.0, &albedo, 0.004, 3);
+        let start: Vec<f32> = albedo.iter().enumerate().map(|(i, a)| *a as f32 * if i % 2 == 0 {0.6} else {1.5}).collect();
+        let sol = fit_single_light(&arr, &labels, &NoiseModel::init(0.0, 0.004), (480.0, 330.0), 300.0, &start);
+        assert!(sol.converged);
+        assert!((sol.location.0 - 612.0).abs() < 1.0 && (sol.location.1 - 205.0).abs() < 1.0, "{:?}", sol.location);
+        assert!((sol.height - 420.0).abs() < 1.0, "{}", sol.height);
+        for (a, truth) in sol.albedo.iter().zip(albedo.iter()){
+            assert!((*a as f64 - truth).abs() < 0.005, "{:?}", sol.albedo);
+        }
+    }
+
+    #[test]
+    fn fit_single_light_takes_any_number_of_patches(){
+        //a wide picture cut into 2 x 4 patches
+        let albedo = [0.7, 0.3, 1.0, 0.5, 0.85, 0.4, 0.6, 0.2];
+        let labels = LabelMap::grid((1200, 600), 2, 4);
+        let arr = render(&labels, (830.0, 260.0), 350.0, &albedo, 0.004, 8);
+        let sol = fit_single_light(&arr, &labels, &NoiseModel::init(0.0, 0.004), (800.0, 300.0), 300.0, &[1.0; 8]);
+        assert!(sol.converged);
+        assert!((sol.location.0 - 830.0).abs() < 1.0 && (sol.location.1 - 260.0).abs() < 1.0, "{:?}", sol.location);
+        let relative = sol.relative_albedo();
+        assert_eq!(relative.len(), 8);
+        for (a, truth) in relative.iter().zip(albedo.iter()){
+            assert!((*a as f64 - truth).abs() < 0.005, "{:?}", relative);
+        }
+    }
+}
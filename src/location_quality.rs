@@ -0,0 +1,137 @@
+use crate::circle_fit::ClusterFit;
+use crate::gradient_lines::GradientLocation;
+
+
+//how far outside the frame lights are searched for, in frame widths
+pub static OUTSIDE_FRAMES: f64 = 4.0;
+
+//from far away the iso-brightness arcs get flat and the gradient lines almost parallel: the
+//direction to the light is still known, the distance along it is not
+static MIN_CONDITION: f32 = 0.01;
+static HEIGHT_CONDITION: f32 = 0.05;
+static MIN_ARC: f64 = 0.35;
+//disagreement of the two location solvers, relative to the light's distance from the frame center
+static MAX_DISAGREEMENT: f32 = 0.05;
+static MIN_DISAGREEMENT: f32 = 5.0;
+//mean cosine between the gradients and the directions to the answer, noise gives about 0
+static MIN_AGREEMENT: f32 = 0.5;
+
+
+pub struct LocationQuality{
+    //distance from the light to the frame, 0 inside
+    pub outside: f32,
+    //the longest arc any cluster fit covers, radians
+    pub best_arc: f64,
+    //weighted rms distance of the cluster fit centers from the answer
+    pub spread: f32,
+    pub reasons: Vec<String>
+}
+
+impl LocationQuality{
+    pub fn is_weak(&self) -> bool{
+        return !self.reasons.is_empty();
+    }
+}
+
+pub fn distance_to_frame(p: (f32, f32), frame: (f32, f32)) -> f32{
+    let dx = (-p.0).max(p.0 - (frame.0 - 1.0)).max(0.0);
+    let dy = (-p.1).max(p.1 - (frame.1 - 1.0)).max(0.0);
+    return (dx * dx + dy * dy).sqrt();
+}
+
+//location is the answer kept, voted the one of the cluster voting if there was any; frame is the
+//width and height of the picture
+pub fn assess(location: (f32, f32), voted: Option<(f32, f32)>, fits: &[ClusterFit], gradient: Option<&GradientLocation>, frame: (f32, f32)) -> LocationQuality{
+    let mut reasons = vec!();
+    if voted.is_none() && gradient.is_none(){
+        reasons.push("no estimate of the location".to_string());
+    }
+    else if voted.is_none(){
+        reasons.push("no cluster votes, location from gradient lines only".to_string());
+    }
+    let outside = distance_to_frame(location, frame);
+    let best_arc = fits.iter().map(|f| f.arc).fold(0.0, f64::max);
+    let (mut sd, mut sw) = (0.0, 0.0);
+    for f in fits{
+        let w = f.weight();
+        sd += w * ((f.circle.center.0 - location.0 as f64).powi(2) + (f.circle.center.1 - location.1 as f64).powi(2));
+        sw += w;
+    }
+    let spread = if sw > 0.0 {(sd / sw).sqrt() as f32} else {0.0};
+    if best_arc < MIN_ARC{
+        reasons.push(format!("iso-brightness arcs are too flat (longest {:.0}°)", best_arc.to_degrees()));
+    }
+    if let Some(g) = gradient{
+        if g.agreement < MIN_AGREEMENT{
+            reasons.push(format!("gradients do not point to a common location (agreement {:.2})", g.agreement));
+        }
+        else if g.condition < MIN_CONDITION{
+            reasons.push(format!("gradient lines are nearly parallel (conditioning {:.4})", g.condition));
+        }
+        //once r >> h the falloff is ~ r⁻³ whatever h is, so height trades off with the distance
+        if outside > 0.0 && g.condition < HEIGHT_CONDITION{
+            reasons.push("from this far the brightness falloff barely depends on the height".to_string());
+        }
+        if let Some(v) = voted{
+            let d = ((g.location.0 - v.0).powi(2) + (g.location.1 - v.1).powi(2)).sqrt();
+            let center = ((frame.0 - 1.0) / 2.0, (frame.1 - 1.0) / 2.0);
+            let far = ((location.0 - center.0).powi(2) + (location.1 - center.1).powi(2)).sqrt();
+            if d > (MAX_DISAGREEMENT * far).max(MIN_DISAGREEMENT){
+                reasons.push(format!("voting and gradient lines disagree by {:.0} px", d));
+            }
+        }
+    }
+    return LocationQuality{
+        outside,
+        best_arc,
+        spread,
+        reasons
+    };
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::circle_fit::Circle;
+
+    fn fit(center: (f64, f64), arc: f64) -> ClusterFit{
+        return ClusterFit{circle: Circle{center, radius: 100.0}, rms: 0.5, arc, inliers: 200, points: 220};
+    }
+
+    fn gradient(location: (f32, f32), condition: f32, agreement: f32) -> GradientLocation{
+        return GradientLocation{location, condition, lines: 10000, agreement};
+    }
+
+    #[test]
+    fn a_light_inside_with_full_arcs_is_not_weak(){
+        let fits = vec!(fit((450.0, 300.0), 6.0), fit((451.0, 299.0), 4.0));
+        let q = assess((450.0, 300.0), Some((450.0, 300.0)), &fits, Some(&gradient((450.5, 300.5), 0.5, 0.99)), (900.0, 900.0));
+        assert!(!q.is_weak(), "{:?}", q.reasons);
+        assert_eq!(q.outside, 0.0);
+        assert!((q.best_arc - 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn a_far_light_with_flat_arcs_and_parallel_lines_is_weak(){
+        //two frame widths to the left, the voting went elsewhere
+        let fits = vec!(fit((-1700.0, 450.0), 0.2));
+        let q = assess((-1800.0, 450.0), Some((-1500.0, 450.0)), &fits, Some(&gradient((-1800.0, 450.0), 0.005, 0.95)), (900.0, 900.0));
+        assert!((q.outside - 1800.0).abs() < 1e-3, "{}", q.outside);
+        assert_eq!(q.reasons.len(), 4, "{:?}", q.reasons);
+    }
+
+    #[test]
+    fn no_estimate_is_weak(){
+        let q = assess((0.0, 0.0), None, &[], None, (900.0, 900.0));
+        assert!(q.is_weak());
+    }
+
+    #[test]
+    fn a_light_over_a_wide_picture_is_inside(){
+        let fits = vec!(fit((1000.0, 200.0), 5.0));
+        let q = assess((1000.0, 200.0), Some((1000.0, 200.0)), &fits, Some(&gradient((1000.0, 200.0), 0.4, 0.99)), (1200.0, 600.0));
+        assert_eq!(q.outside, 0.0);
+        assert!(!q.is_weak(), "{:?}", q.reasons);
+    }
+}
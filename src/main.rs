@@ -1,54 +1,151 @@
-use itertools::Itertools;
 use num::{traits::Pow, clamp};
 
+mod accumulator;
+mod albedo_graph;
+mod benchmark;
+mod calibration;
+mod circle_fit;
+mod dataset;
+mod diagnostics;
+mod frame;
+mod gradient_lines;
+mod height_fit;
+mod label_map;
+mod linalg;
+mod lm;
+mod location_quality;
+mod mcmc;
+mod multi_light;
+mod noise_model;
+mod optimizer;
+mod progress;
+mod scene_file;
+mod segmentation;
+mod solver;
+mod stereo;
+mod uncertainty;
+
 
 //static IMAGE_PATH: String = "A".to_string();
 static MEAN: f64 = 0.01;
 static SIGMA: f64 = 0.005;
 static SEED: u64 = 1337;
-static MEDIAN_SIZE: usize = 3;
-static COLORS: &'static [f32] =
+//noise the gui's median filter should leave, and the largest window it may take for it. the command
+//line solves don't filter: the solvers weigh the pixels by the noise model, and a window this wide
+//merges patches and bends the falloff near the light
+static MEDIAN_NOISE: f64 = 0.003;
+static MAX_MEDIAN_SIZE: usize = 9;
+static COLORS: &[f32] =
  &[1.0, 1.0, 1.0, 
   1.0, 1.0, 1.0, 
   1.0, 1.0, 1.0];
-static ALBEDO: &'static [f32; 9] = 
+static ALBEDO: &[f32; 9] = 
 &[0.5710, 0.6840, 0.8936, 
   0.7646, 0.8089, 0.6404, 
   1.0000, 0.6245, 0.7684];
 static SIZE: usize = 300;
 static LIGHT_LUMINOSITY: f32 = 1.0;
 static DIAG: f32 = (SIZE * 9 * SIZE + SIZE* 9 * SIZE) as f32;
-static ITER_TAKE: usize = 5000;
-const NTHREADS: usize = 12;
-static LOCATIONS: &'static [(usize, usize)] = 
-&[(0, 0), 
-  (0, 1), 
-  (0, 2), 
-  (1, 0), 
-  (1, 1), 
-  (1, 2), 
-  (2, 0), 
-  (2, 1),
-  (2, 2)];
-static DIRECTIONS: &'static [(i32, i32)] = &[
- (-1, 1), 
- (1, 1),
- (1, 0), 
- (0, 1), 
- (-1, 0), 
- (0, -1), 
- (-1, -1)
-];
+//known reference of the gui scene, for the absolute albedo and luminosity
+static SCENE_FILE: &str = "scene.params";
 
 fn main(){
-    if std::env::args().len() == 1{
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 1{
         let sim_app = LightSimApp::init(SIZE, ALBEDO);
         let options = eframe::NativeOptions::default();
         eframe::run_native("LightSim", options, Box::new(|_cc| Box::new(sim_app)));
+        return;
     }
-    else{
-        let (x_, y_, h_, albedo) = parse_args(&std::env::args().nth(1).unwrap());
-        reverse_solve_nomad(x_, y_, h_, &albedo);
+    match args[1].as_str(){
+        "generate" => {
+            if args.len() < 4{
+                println!("usage: techvision generate <dataset params> <output dir>");
+                return;
+            }
+            dataset::generate_dataset(&args[2], &args[3]);
+        }
+        "solve" => {
+            if args.len() < 3{
+                println!("usage: techvision solve <image> [solver]");
+                return;
+            }
+            let name = args.get(3).map(|s| s.as_str()).unwrap_or(solver::DEFAULT_SOLVER);
+            if solver::by_name(name).is_none(){
+                println!("unknown solver {}, see techvision solvers", name);
+                return;
+            }
+            reverse_solve_task(&args[2], name);
+        }
+        "solvers" => {
+            for s in solver::registry(){
+                println!("{}: {}", s.name(), s.describe());
+            }
+        }
+        "lm" => {
+            if args.len() < 3{
+                println!("usage: techvision lm <image> [scene file]");
+                return;
+            }
+            reverse_solve_lm(&args[2], args.get(3).map(|s| s.as_str()));
+        }
+        "mcmc" => {
+            if args.len() < 3{
+                println!("usage: techvision mcmc <image> [iterations] [samples csv]");
+                return;
+            }
+            let mut params = mcmc::ChainParams::init();
+            if let Some(iterations) = args.get(3){
+                params.iterations = iterations.parse::<usize>().unwrap();
+            }
+            reverse_solve_mcmc(&args[2], &params, args.get(4).map(|s| s.as_str()));
+        }
+        "lights" => {
+            if args.len() < 3{
+                println!("usage: techvision lights <image> [max lights]");
+                return;
+            }
+            let mut params = multi_light::MultiLightParams::init();
+            if let Some(max_lights) = args.get(3){
+                params.max_lights = max_lights.parse::<usize>().unwrap().max(1);
+            }
+            reverse_solve_lights(&args[2], &params);
+        }
+        "stereo" => {
+            if args.len() < 4{
+                println!("usage: techvision stereo <image> <image> [image ...]");
+                return;
+            }
+            reverse_solve_stereo(&args[2..]);
+        }
+        "benchmark" => {
+            if args.len() < 4{
+                println!("usage: techvision benchmark <dataset dir> <output dir> [solver ...]");
+                return;
+            }
+            let mut solvers: Vec<String> = args[4..].to_vec();
+            if solvers.is_empty(){
+                solvers = solver::registry().iter().map(|s| s.name().to_string()).collect();
+            }
+            if let Some(name) = solvers.iter().find(|s| solver::by_name(s).is_none()){
+                println!("unknown solver {}, see techvision solvers", name);
+                return;
+            }
+            benchmark::run_benchmark(&args[2], &args[3], &solvers);
+        }
+        "optimize" => {
+            if args.len() < 3{
+                println!("usage: techvision optimize <params.nomad> [target image]");
+                return;
+            }
+            let target = if args.len() > 3 {args[3].as_str()} else {"mondrian_albedo_estimation_frame_3.png"};
+            optimizer::optimize(&args[2], target);
+        }
+        //anything else is a blackbox input file from NOMAD
+        _ => {
+            let (x_, y_, h_, albedo) = parse_args(&args[1]);
+            reverse_solve_nomad(x_, y_, h_, &albedo);
+        }
     }
 }
 
@@ -78,131 +175,38 @@ fn parse_args(arg: &str) -> (i32, i32, u32, [f32;9]){
 
 fn reverse_solve_nomad(x_: i32, y_: i32, h_: u32, albedo: &[f32; 9])
 {
-    let mut lightsimapp = LightSimApp::init(SIZE, &albedo);
-    lightsimapp.light_source.coordinates.0 = x_;
-    lightsimapp.light_source.coordinates.1 = y_;
-    lightsimapp.light_source.height = h_;
-    lightsimapp.light_source.is_on = true;
-    lightsimapp.update_no_reverse_solve();
-    //load 2 images and count distance
-    let mut diff = 0.0;
-    let img_generated = image::open("scene.png").unwrap().grayscale();
-    let img_gen = img_generated.as_luma8().unwrap();
-    let img_original = image::open("mondrian_albedo_estimation_frame_3.png").unwrap().grayscale();
-    let img_orig = img_original.as_luma8().unwrap();
-    for i in 0..SIZE*3{
-        for j in 0..SIZE*3{
-            diff += (srgb_to_clinear(img_gen.get_pixel(i as u32, j as u32).0[0] as usize) - srgb_to_clinear(img_orig.get_pixel(i as u32, j as u32).0[0] as usize)).pow(2) as f64;
-        }
-    }
+    let mut objective = optimizer::BlackboxObjective::init("mondrian_albedo_estimation_frame_3.png");
+    let diff = objective.evaluate(x_, y_, h_, albedo);
     println!("{}", diff);
 }
 
 fn eucl_dist(a: &(i32, i32), b: &(i32, i32)) -> f32{
-    let a_ = (a.0 as f32, a.1 as f32);
-    let b_ = (b.0 as f32, b.1 as f32);
+    return eucl_dist_f32(&(a.0 as f32, a.1 as f32), &(b.0 as f32, b.1 as f32));
+}
+
+fn eucl_dist_f32(a_: &(f32, f32), b_: &(f32, f32)) -> f32{
     let mut dist = (b_.0 - a_.0) * (b_.0 - a_.0) + (b_.1 - a_.1) * (b_.1 - a_.1);
     dist = dist.sqrt();
     return dist;
 }
 
-fn get_circle_center(x1: &(i32, i32), x2: &(i32, i32), x3: &(i32, i32)) -> (bool, (i32, i32)){
-    //x0
-    //count y_bracket once, multiply in up 
-    let y1bracket = (x2.0 * x2.0 + x2.1 * x2.1) as i32 - (x3.0 * x3.0) as i32 - (x3.1 * x3.1) as i32;
-    let y2bracket = (x3.0 * x3.0 + x3.1 * x3.1) as i32 - (x1.0 * x1.0) as i32 - (x1.1 * x1.1) as i32;
-    let y3bracket = (x1.0 * x1.0 + x1.1 * x1.1) as i32 - (x2.0 * x2.0) as i32 - (x2.1 * x2.1) as i32;
-    let up = (x1.1 as i32) * y1bracket + (x2.1 as i32) * y2bracket + (x3.1 as i32) * y3bracket;
-    let down = x1.0 as i32 * (x2.1 as i32 - x3.1 as i32) + x2.0 as i32*(x3.1 as i32 - x1.1 as i32) + x3.0 as i32*(x1.1 as i32 - x2.1 as i32);
-    let mut x = (up as f32) / (down as f32);
-    x *= -0.5;
-    let up = (x1.0 as i32) *  y1bracket + (x2.0 as i32) * y2bracket + (x3.0 as i32) * y3bracket;
-    let mut y = (up as f32) / (down as f32);
-    y *= 0.5;
-    let mut approved = true;
-    if down == 0{
-        approved = false;
-    }
-    //if x <= -0.5 || y <= -0.5 || x >= ((SIZE * 3) as f32 - 0.5) || y >= ((SIZE * 3) as f32 - 0.5) {
-    //    approved = false;
-    //}
-    let x_ = x.round() as i32;
-    let y_ = y.round() as i32;
-    return (approved, (x_, y_));
-}
-
-fn process_patch(cluster: std::collections::HashMap<usize, Vec<(i32, i32)>>) -> std::collections::HashMap<(i32, i32), usize>{
-    let mut children = vec!();
-    let mut answers: std::collections::HashMap<(i32, i32), usize> = std::collections::HashMap::new();
-    //mapreduce
-    for cl in cluster{
-        children.push(std::thread::spawn(move || -> std::collections::HashMap<(i32, i32), usize> {
-            let mut cur_answers: std::collections::HashMap<(i32, i32), usize> = std::collections::HashMap::new();
-            let points = cl.1;
-            let it = points.iter().combinations(3).take(ITER_TAKE);
-            it.for_each(|i|{
-                let cur_ans = get_circle_center(i[0], i[1], i[2]);
-                if cur_ans.0 == true{
-                    if cur_answers.contains_key(&cur_ans.1){
-                        *cur_answers.get_mut(&cur_ans.1).unwrap() += 1;
-                    }
-                    else{
-                        cur_answers.insert(cur_ans.1, 1);
-                    }
-                }
-            });
-            return cur_answers;
-        }));
-    }
-    let final_result = 
-    children.into_iter().map(|c| c.join().unwrap());
-    for a in final_result{
-        for k in a.keys(){
-            if answers.contains_key(k){
-                *answers.get_mut(k).unwrap() += 1;
-            }
-            else{
-                answers.insert(*k, *a.get(k).unwrap());
-            }
-        }
-    }
-    return answers;
-}
-
-
 fn filter_single_value(patch: &ndarray::Array2::<f32>) -> f32{
-    let mut arr: Vec<f32> = vec!();
-    for i in 0..MEDIAN_SIZE{
-        for j in 0..MEDIAN_SIZE{
-            arr.push(patch[[i, j]]);
-        }
-    }
+    let mut arr: Vec<f32> = patch.iter().cloned().collect();
     arr.sort_by(|a, b| a.partial_cmp(b).unwrap());
 
-    return arr[(arr.len() + 1) /2];
+    return arr[arr.len() / 2];
 }
 
-fn median_filter_image(array: &ndarray::Array2::<f32>) -> ndarray::Array2::<f32>{
+//size by size window centered on every pixel, clamped at the borders
+fn median_filter_image(array: &ndarray::Array2::<f32>, size: usize) -> ndarray::Array2::<f32>{
     let mut arr = ndarray::Array2::<f32>::default((array.shape()[0], array.shape()[1]));
     ndarray::Zip::indexed(arr.outer_iter_mut()).par_for_each(|j, mut row| {
+        let mut curr_patch = ndarray::Array2::<f32>::default((size, size));
         for (k, col) in row.iter_mut().enumerate(){
-            let mut curr_patch = ndarray::Array2::<f32>::default((MEDIAN_SIZE, MEDIAN_SIZE));
-            for l in 0..MEDIAN_SIZE{
-                let mut l_i = (j  as i32) - ((MEDIAN_SIZE+1) / 2 + l) as i32;
-                if l_i < 0{
-                    l_i = 0;
-                }
-                if l_i > (SIZE * 3 - 1) as i32{
-                    l_i = (SIZE * 3  - 1) as i32;
-                }
-                for m in 0..MEDIAN_SIZE{
-                    let mut m_j = (k as i32) - ((MEDIAN_SIZE+1) / 2 + l) as i32;
-                    if m_j < 0{
-                        m_j = 0;
-                    }
-                    if m_j > (SIZE * 3 - 1) as i32{
-                        m_j = (SIZE * 3 - 1) as i32;
-                    }
+            for l in 0..size{
+                let l_i = ((j + l) as i32 - (size / 2) as i32).clamp(0, (array.shape()[0] - 1) as i32);
+                for m in 0..size{
+                    let m_j = ((k + m) as i32 - (size / 2) as i32).clamp(0, (array.shape()[1] - 1) as i32);
                     curr_patch[[l, m]] = array[[l_i as usize, m_j as usize]];
                 }
             }
@@ -213,385 +217,554 @@ fn median_filter_image(array: &ndarray::Array2::<f32>) -> ndarray::Array2::<f32>
 }
 
 
-fn launch_ray(reverse_solution_location: &(i32, i32), direction: &(i32, i32), scene_arr: &ndarray::Array2::<f32>) -> (f32, f32){
-    //fix me
-    let mut res = (0.0, 0.0); //height, diff
-        let mut curr_pos = (reverse_solution_location.0, reverse_solution_location.1);
-        let mut mov_pos = curr_pos;
-        let pic_center = ((SIZE + SIZE / 2) as i32, (SIZE + SIZE / 2) as i32);
-        let check_dist = eucl_dist(&pic_center, &mov_pos);
-        mov_pos = (mov_pos.0 + direction.0, mov_pos.1 + direction.1);
-        let check_dist_2 = eucl_dist(&pic_center, &mov_pos);
-        let mut clicks = 0;
-        if check_dist_2 < check_dist{
-            while !within_bound(mov_pos) && clicks < 1000{
-                mov_pos = (mov_pos.0 + direction.0, mov_pos.1 + direction.1);
-                clicks +=1;
-            }
-            while within_bound(mov_pos){
-                let new_pos = (mov_pos.0 + direction.0, mov_pos.1 + direction.1);
-                if within_bound(new_pos){
-                    if get_patch(new_pos.0 as usize, new_pos.1 as usize) == 
-                    get_patch(curr_pos.0 as usize, curr_pos.1 as usize){
-                        let cur_h = solve_eq(reverse_solution_location, (curr_pos.0 as usize, curr_pos.1 as usize),
-                                                        (new_pos.0 as usize, new_pos.1 as usize), 
-                                                        scene_arr);
-                        if cur_h.1 > res.1{
-                            res = cur_h;
-                        }
-                    }
-                    else{
-                        curr_pos = new_pos;
-                    }
-                    mov_pos = (mov_pos.0 + direction.0, mov_pos.1 + direction.1);
-                }
-                else{
-                    break;
-                }
-            }        
-        }
-        return res;
-}
-
 struct LightSimApp{
     light_source: LightSource,
     scene: Scene,
     noise: Noise,
+    //what the solvers are told of the noise; the median filter is optional on top of it
+    noise_model: noise_model::NoiseModel,
+    //whether the noise model is guessed from the picture once its patches are known
+    estimate_noise: bool,
+    //whether the picture is one prep_arr saved, sRGB stretched so its brightest pixel is white, and
+    //the stretch undone once it is known
+    stretched: bool,
+    exposure: Option<calibration::Exposure>,
+    median_filter: bool,
     img_gui: egui_extras::RetainedImage,
-    reverse_solution_height: u32,
+    reverse_solution_height: f32,
+    //rms residual of the radial profile fit
+    height_residual: f64,
     revere_solution_albedo: Vec<f32>,
-    reverse_solution_location: (i32, i32),
-    scene_arr: ndarray::Array2::<f32>
+    albedo_solution: Option<albedo_graph::AlbedoSolution>,
+    labels: label_map::LabelMap,
+    reverse_solution_location: (f32, f32),
+    loc_params: accumulator::AccumulatorParams,
+    //name of the registered reverse solver in use
+    solver: String,
+    scene_arr: ndarray::Array2::<f32>,
+    //how the loaded picture maps onto scene_arr
+    frame: frame::Frame,
+    use_lm: bool,
+    lm_solution: Option<lm::LmSolution>,
+    gradient_location: Option<gradient_lines::GradientLocation>,
+    loc_quality: Option<location_quality::LocationQuality>,
+    scene_file: scene_file::SceneFile,
+    calibration: Option<calibration::Calibration>,
+    //standard deviations of the separate solvers' answer and of the joint fit
+    confidence: Option<uncertainty::Confidence>,
+    lm_confidence: Option<uncertainty::Confidence>,
+    //spread of the voted location, None when voting wasn't kept
+    location_bootstrap: Option<(f32, f32)>,
+    //only sampled on request, it takes seconds
+    posterior: Option<mcmc::Posterior>,
+    //several lights at once, also only on request
+    multi_light: Option<multi_light::MultiLightSolution>,
+    //whether the picture could be solved, and what was barely determined
+    diagnostics: Option<diagnostics::Diagnostics>,
+    //milliseconds spent by cluster voting and by gradient lines
+    loc_times: (f32, f32),
+    //inputs of the picture and of the answers shown, None before the first
+    rendered: Option<RenderInputs>,
+    solved: Option<SolveInputs>,
+    //the gui's picture before the median filter, the solves start from it
+    picture: ndarray::Array2::<f32>,
+    //of the solve running on this app, and of the one the gui has running elsewhere
+    progress: std::sync::Arc<progress::Progress>,
+    job: Option<SolveJob>
 }
 
-
-fn load_im_egui() -> eframe::epaint::ColorImage{
-    let img = image::open("scene.png").unwrap().to_rgba8();
-    let rgba = img.as_raw();
-    let img_ = eframe::epaint::ColorImage::from_rgba_unmultiplied([img.width() as usize, img.height() as usize], rgba);
-    return img_;
+//a solve of the gui's picture on its own thread, it hands back the app it solved on or None when
+//it was cancelled
+struct SolveJob{
+    kind: JobKind,
+    progress: std::sync::Arc<progress::Progress>,
+    handle: std::thread::JoinHandle<Option<LightSimApp>>
 }
 
+//what a job was started for, and so which of its answers the gui takes
+#[derive(Clone, Copy, PartialEq)]
+enum JobKind{
+    Solve,
+    Answers(AnswersJob),
+    //a picture from disk, its answers are printed like the command line's
+    LoadPic
+}
 
-fn solve_eq(ls: &(i32, i32), center: (usize, usize), edge: (usize, usize), scene_arr: &ndarray::Array2::<f32>) -> (f32, f32){
-    let b2 = scene_arr[[center.0, center.1]].powf(1.0 / 3.0);
-    let b1 = scene_arr[[edge.0, edge.1]].powf(1.0 / 3.0);
-    let diff = b2 - b1;
-    let r1 = eucl_dist(&ls, &(edge.0 as i32, edge.1 as i32));
-    let r2 = eucl_dist(&ls, &(center.0 as i32, center.1 as i32));
-    let up = b2 * b2 * r2 * r2 - b1 * b1 * r1 * r1;
-    let down = (b1 * b1 - b2 * b2) as f32;
-    let mut h = up.abs() / down.abs();
-    h = h.sqrt();
-    return (h, diff.abs());
+//the jobs that start from the shown answers
+#[derive(Clone, Copy, PartialEq)]
+enum AnswersJob{
+    Posterior,
+    MultiLight
 }
 
+//what the answers depend on besides the picture
+#[derive(Clone, PartialEq)]
+struct SolveInputs{
+    solver: String,
+    loc_params: accumulator::AccumulatorParams,
+    use_lm: bool
+}
 
-//checks if absolute coordinates are within bounds
-fn within_bound(loc: (i32, i32)) -> bool{
-    if loc.0 < 0 || loc.1 < 0 || loc.0 > (SIZE*3 - 1) as i32 || loc.1 > (SIZE*3 - 1) as i32{
-        return false;
-    }
-    else{
-        return true;
-    }
+//what the gui's picture depends on
+#[derive(Clone, PartialEq)]
+struct RenderInputs{
+    location: (usize, usize),
+    coordinates: (i32, i32),
+    height: u32,
+    extra: Vec<PointLight>,
+    noise: bool,
+    median_filter: bool
 }
 
 
-//gets patch relative to coordinates
-fn get_patch(j: usize, k: usize) -> (usize, usize){
-    let mut x = k / SIZE;
-    let mut y = j / SIZE;
-    if x > 2{
-        x = 2;
-    }
-    if y > 2{
-        y = 2;
-    }
-    return (x, y);
+//the rendered picture straight into the gui, both are stored row by row
+fn gray_to_egui(img: &image::GrayImage) -> eframe::epaint::ColorImage{
+    let pixels = img.pixels().map(|p| eframe::epaint::Color32::from_gray(p.0[0])).collect();
+    return eframe::epaint::ColorImage{size: [img.width() as usize, img.height() as usize], pixels};
 }
 
 
 //Light simulation app implementation
 impl LightSimApp{
     fn init(sz: usize, alb: &[f32; 9]) -> Self{
-        let ls = LightSource::init(alb);
-        let sc = Scene::init(sz);
-        let img_ = load_im_egui();
-        let rev_sol_h = 0;
-        let rev_sol_loc = (0, 0);
+        let shape = (sz*3, sz*3);
+        let mut lsa = LightSimApp::headless(ndarray::Array2::<f32>::default(shape), scene_file::SceneFile::read_if_exists(SCENE_FILE));
+        lsa.light_source = LightSource::init(alb);
+        lsa.scene = Scene::new(sz);
+        lsa.noise = Noise::init();
+        lsa.picture = ndarray::Array2::<f32>::default(shape);
+        let img_ = gray_to_egui(&lsa.scene.scene_image);
+        lsa.img_gui = egui_extras::RetainedImage::from_color_image("sceneimg", img_);
+        return lsa;
+    }
+
+    //app around a picture already at the solvers' size, without a mondrian, light or noise of its
+    //own to render; those take a while to build and only the gui draws with them
+    fn headless(arr: ndarray::Array2::<f32>, scene_file: scene_file::SceneFile) -> Self{
+        let shape = arr.dim();
+        let rev_sol_h = 0.0;
+        let rev_sol_loc = (0.0, 0.0);
         let rev_sol_albed: Vec<f32> = [0.0, 0.0, 0.0, 
                                        0.0, 0.0, 0.0, 
                                        0.0, 0.0, 0.0].to_vec();
-        let shape = (sz*3, sz*3);
-        let arr = ndarray::Array2::<f32>::default(shape);
-        let ns = Noise::init();
         return LightSimApp { 
-            light_source: ls, 
-            scene: sc,
-            noise: ns,
+            light_source: LightSource::with_shape(ALBEDO, (0, 0)), 
+            scene: Scene::empty(),
+            noise: Noise::off(),
+            noise_model: noise_model::NoiseModel::clean(),
+            estimate_noise: false,
+            stretched: false,
+            exposure: None,
+            median_filter: false,
             reverse_solution_location: rev_sol_loc,
-            img_gui: egui_extras::RetainedImage::from_color_image("sceneimg", img_),
+            loc_params: accumulator::AccumulatorParams::init(),
+            solver: solver::DEFAULT_SOLVER.to_string(),
+            img_gui: egui_extras::RetainedImage::from_color_image("sceneimg", eframe::epaint::ColorImage::new([0, 0], eframe::epaint::Color32::BLACK)),
             reverse_solution_height: rev_sol_h,
+            height_residual: 0.0,
+            albedo_solution: None,
             revere_solution_albedo: rev_sol_albed,
-            scene_arr: arr
-        }
-    }
-
-
-    //get color clusters from patch
-    fn clusterize_patch(&mut self, loc: &(usize, usize)) -> (bool, std::collections::HashMap<usize, Vec<(i32, i32)>>){
-        let loc_restrictions = (loc.1 * SIZE, loc.0*SIZE);
-        let patch = 
-        self.scene_arr.slice(ndarray::s![loc_restrictions.0..loc_restrictions.0+SIZE, 
-                                            loc_restrictions.1..loc_restrictions.1+SIZE]);
-        let mut clusters: std::collections::HashMap<usize, Vec<(i32, i32)>> = std::collections::HashMap::new();
-        let shape = patch.shape();
-        //mapreduce?
-        //let (min, max) = find_min_max(&patch.to_owned());
-        let mut eligible = true;
-        //if (max - min).abs() < 0.01{
-        //    eligible = false;
-        //}
-        if eligible{
-            for i in 0..shape[0]{
-                for j in 0..shape[1]{
-                    let current_brightness = (((patch[[i, j]] * 10000.0).round() / 10000.0) * 100000000.0) as usize;
-                    if clusters.contains_key(&current_brightness){
-                        clusters.get_mut(&current_brightness).unwrap().push(((loc.1 * SIZE + i) as i32, (loc.0 * SIZE + j) as i32));
-                    }
-                    else if  clusters.keys().len() < 5 * NTHREADS{
-                        clusters.insert(current_brightness, Vec::new());
-                    }
-                }
-            }
-        }   
-        return (eligible, clusters);
+            labels: label_map::LabelMap::grid(shape, 3, 3),
+            picture: ndarray::Array2::<f32>::default((0, 0)),
+            scene_arr: arr,
+            frame: frame::Frame::identity(shape),
+            use_lm: false,
+            lm_solution: None,
+            gradient_location: None,
+            loc_quality: None,
+            scene_file,
+            calibration: None,
+            confidence: None,
+            lm_confidence: None,
+            location_bootstrap: None,
+            posterior: None,
+            multi_light: None,
+            diagnostics: None,
+            loc_times: (0.0, 0.0),
+            rendered: None,
+            solved: None,
+            progress: std::sync::Arc::new(progress::Progress::init()),
+            job: None
+        }
     }
 
-    fn update_(&mut self){
-        self.light_source.generate_light_matrix();
-        self.scene_arr = self.scene.update(&self.light_source, &self.noise);
-        if self.noise.is_on{
-            self.scene_arr = median_filter_image(&self.scene_arr);
-        }
-        self.solve_loc();
-        self.solve_height();
-        self.solve_albedo();
-        let img_ = load_im_egui();
-        self.img_gui = egui_extras::RetainedImage::from_color_image("sceneimg", img_);
+    //app around an already observed linear image of any size, for solving from the command line
+    fn from_scene_arr(arr: ndarray::Array2::<f32>) -> Self{
+        let frame = frame::Frame::fit(arr.dim(), SIZE * 3);
+        let arr = frame.resample(arr);
+        //the gui's scene file describes the gui's scene, not this picture
+        let mut lsa = LightSimApp::headless(arr, scene_file::SceneFile::init());
+        lsa.frame = frame;
+        lsa.noise_model = noise_model::NoiseModel::from_image(&lsa.scene_arr);
+        lsa.estimate_noise = true;
+        lsa.stretched = true;
+        return lsa;
     }
 
 
-    fn update_no_pic(&mut self){
-        self.solve_loc();
-        self.solve_height();
-        self.solve_albedo();
+    //patches of the picture, or the scene file's grid; when their number changes the per patch
+    //answers no longer fit them
+    fn segment(&mut self){
+        self.labels = match self.scene_file.grid{
+            Some((rows, cols)) => label_map::LabelMap::grid(self.scene_arr.dim(), rows, cols),
+            None => segmentation::segment(&self.scene_arr)
+        };
+        if self.revere_solution_albedo.len() != self.labels.count(){
+            self.revere_solution_albedo = vec![0.0; self.labels.count()];
+            self.albedo_solution = None;
+            self.lm_solution = None;
+            self.lm_confidence = None;
+            self.posterior = None;
+            self.multi_light = None;
+        }
     }
 
+    //the patch covering the middle of a square of the gui's grid, to compare with ALBEDO
+    fn grid_patch(&self, row: usize, col: usize) -> usize{
+        return self.labels.label(col * SIZE + SIZE / 2, row * SIZE + SIZE / 2);
+    }
 
-    fn update_no_reverse_solve(&mut self){
+    fn render_scene(&mut self){
         self.light_source.generate_light_matrix();
-        self.scene_arr = self.scene.update(&self.light_source, &self.noise);
-        if self.noise.is_on{
-            self.scene_arr = median_filter_image(&self.scene_arr);
-        }
-        let img_ = load_im_egui();
+        self.picture = self.scene.render(&self.light_source, &self.noise);
+        //the gui's own noise is known; the median filter only makes it smaller
+        self.noise_model = if self.noise.is_on {noise_model::NoiseModel::init(self.noise.mean, self.noise.sigma)} else {noise_model::NoiseModel::clean()};
+        let img_ = gray_to_egui(&self.scene.scene_image);
         self.img_gui = egui_extras::RetainedImage::from_color_image("sceneimg", img_);
     }
 
-    fn solve_loc(&mut self){
-        let mut answers: std::collections::HashMap<(i32, i32), usize> = std::collections::HashMap::new();
-        for loc in LOCATIONS{
-            let (valid, clusters) = self.clusterize_patch(loc);
-            if valid{
-                let pts = process_patch(clusters);
-                for pt in pts{
-                    if answers.contains_key(&pt.0){
-                        *answers.get_mut(&pt.0).unwrap() += pt.1;
-                    }
-                    else{
-                        answers.insert(pt.0, pt.1);
-                    }
-                }
-            }
+    fn render_inputs(&self) -> RenderInputs{
+        return RenderInputs{
+            location: self.light_source.location,
+            coordinates: self.light_source.coordinates,
+            height: self.light_source.height,
+            extra: self.light_source.extra.clone(),
+            noise: self.noise.is_on,
+            median_filter: self.median_filter
+        };
+    }
+
+    //the gui repaints on every mouse move; the picture is rendered again only when the light or the
+    //noise change, and a solve is started on another thread when the picture or the solver settings
+    //do. the answers shown stay those of the last finished solve until the next one is done
+    fn refresh(&mut self, ctx: &eframe::egui::Context){
+        let render = self.render_inputs();
+        let answers = SolveInputs{solver: self.solver.clone(), loc_params: self.loc_params, use_lm: self.use_lm};
+        let new_picture = self.rendered.as_ref() != Some(&render);
+        if new_picture{
+            self.render_scene();
+            self.rendered = Some(render);
         }
-        let max_elem = answers.iter().max_by_key(|entry| entry.1);
-        if !max_elem.is_none(){
-            self.reverse_solution_location = *max_elem.unwrap().0;
+        if new_picture || self.solved.as_ref() != Some(&answers){
+            self.start_job(ctx);
+            self.solved = Some(answers);
         }
     }
 
-
-    fn count_diff_albedo(&mut self, pointf1: (usize, usize), pointf2: (usize, usize)) -> f32{
-        let mut f1_b = self.scene_arr[[pointf1.0, pointf1.1]];
-        let r1 = eucl_dist(&self.reverse_solution_location, &(pointf1.0 as i32, pointf1.1 as i32));
-        let cos1 = self.reverse_solution_height as f32 / (r1*r1 + self.reverse_solution_height as f32*self.reverse_solution_height as f32).sqrt();
-        let mut f2_b = self.scene_arr[[pointf2.0, pointf2.1]];
-        let r2 = eucl_dist(&self.reverse_solution_location, &(pointf2.0 as i32, pointf2.1 as i32));
-        let cos2 = self.reverse_solution_height as f32 / (r2*r2 + self.reverse_solution_height as f32*self.reverse_solution_height as f32).sqrt();
-        f1_b /= cos1.pow(3);
-        f2_b /= cos2.pow(3);
-        return f1_b / f2_b;
+    //everything from the median filter to the joint fit on a fresh app of this one's picture and
+    //settings
+    fn start_job(&mut self, ctx: &eframe::egui::Context){
+        let picture = self.picture.clone();
+        let median_size = if self.median_filter {self.median_size()} else {0};
+        let (noise_model, solver, loc_params, use_lm) = (self.noise_model, self.solver.clone(), self.loc_params, self.use_lm);
+        let scene_file = self.scene_file.clone();
+        self.spawn_job(ctx, JobKind::Solve, move |progress| {
+            let mut lsa = LightSimApp::headless(picture, scene_file);
+            lsa.progress = progress;
+            lsa.noise_model = noise_model;
+            lsa.solver = solver;
+            lsa.loc_params = loc_params;
+            if median_size > 0{
+                lsa.progress.start("median filter", 0);
+                lsa.scene_arr = median_filter_image(&lsa.scene_arr, median_size);
+            }
+            lsa.progress.start("segmenting", 0);
+            lsa.segment();
+            lsa.solve_segmented();
+            if use_lm && !lsa.progress.is_cancelled(){
+                lsa.progress.start("joint fit", 0);
+                lsa.solve_lm();
+            }
+            return lsa;
+        });
     }
 
-    fn solve_height(&mut self){
-        let mut h_vec: Vec<(f32, f32)> = vec!();
-        let mut children = vec!();
-        let loc_copy = self.reverse_solution_location.clone();
-        for dir in DIRECTIONS{
-            let arr_copy = self.scene_arr.clone();
-            children.push(std::thread::spawn(move || -> (f32, f32){
-                let h_c = launch_ray(&loc_copy, dir, &arr_copy);
-                return h_c;
-            }));
+    //the posterior and the several lights start from the shown answers, on a copy of them
+    fn start_answers_job(&mut self, ctx: &eframe::egui::Context, job: AnswersJob){
+        if !self.is_solvable(){
+            return;
         }
-        let hs = children.into_iter().map(|c| c.join().unwrap());
-        for h in hs{
-            if h.1 != 0.0{
-                h_vec.push(h);
+        let mut lsa = LightSimApp::headless(self.scene_arr.clone(), self.scene_file.clone());
+        lsa.labels = self.labels.clone();
+        lsa.noise_model = self.noise_model;
+        lsa.reverse_solution_location = self.reverse_solution_location;
+        lsa.reverse_solution_height = self.reverse_solution_height;
+        lsa.revere_solution_albedo = self.revere_solution_albedo.clone();
+        lsa.lm_solution = self.lm_solution.clone();
+        self.spawn_job(ctx, JobKind::Answers(job), move |progress| {
+            lsa.progress = progress;
+            match job{
+                AnswersJob::Posterior => lsa.solve_posterior(&mcmc::ChainParams::init()),
+                AnswersJob::MultiLight => lsa.solve_multi_light(&multi_light::MultiLightParams::init())
             }
-        }
-        h_vec.sort_by(|a, b| a.1.total_cmp(&b.1));
-        if h_vec.len() > 0{
-            self.reverse_solution_height = h_vec[h_vec.len() - 1].0.round() as u32;
-        }
+            return lsa;
+        });
     }
 
+    fn start_load_pic(&mut self, ctx: &eframe::egui::Context, path: &str){
+        let (path, solver) = (path.to_string(), self.solver.clone());
+        self.spawn_job(ctx, JobKind::LoadPic, move |progress| {
+            let mut lsa = LightSimApp::from_scene_arr(load_linear_image(&path));
+            lsa.progress = progress;
+            lsa.solver = solver;
+            lsa.update_no_pic();
+            return lsa;
+        });
+    }
 
-    fn solve_albedo(&mut self){
-        let mut albedo_arr = ndarray::Array2::<f32>::default([9, 9]);
+    //a running job is cancelled, its answers would be stale anyway
+    fn spawn_job<F>(&mut self, ctx: &eframe::egui::Context, kind: JobKind, work: F)
+    where F: FnOnce(std::sync::Arc<progress::Progress>) -> LightSimApp + Send + 'static{
+        self.cancel_job();
+        let progress = std::sync::Arc::new(progress::Progress::init());
+        let (job_progress, ctx) = (progress.clone(), ctx.clone());
+        let handle = std::thread::spawn(move || -> Option<LightSimApp> {
+            let lsa = work(job_progress);
+            ctx.request_repaint();
+            if lsa.progress.is_cancelled(){
+                return None;
+            }
+            return Some(lsa);
+        });
+        self.job = Some(SolveJob{kind, progress, handle});
+    }
 
-        for i in 0..9{
-            albedo_arr[[i, i]] = 1.0;
+    //the thread winds down by itself at its next check, its answers are dropped
+    fn cancel_job(&mut self){
+        if let Some(job) = self.job.take(){
+            job.progress.cancel();
         }
-        
-        //this is faster since it works in O(1), no need for ifs and copying of array
-        let f_12 = self.count_diff_albedo((SIZE-1, SIZE/2), (SIZE, SIZE/2));
-        albedo_arr[[0, 1]] = f_12;
-        albedo_arr[[1, 0]] = 1.0 / f_12;
-
-        let f_14 = self.count_diff_albedo((SIZE/2, SIZE - 1), (SIZE/2, SIZE));
-        albedo_arr[[0, 3]]  = f_14;
-        albedo_arr[[3, 0]] = 1.0 / f_14;
-
-        let f_15 = self.count_diff_albedo((SIZE-1, SIZE-1), (SIZE, SIZE));
-        albedo_arr[[0, 4]] = f_15;
-        albedo_arr[[4, 0]] = 1.0 / f_15;
-
-        let f_23 = self.count_diff_albedo((SIZE*2 - 1, SIZE/2), (SIZE*2, SIZE/2));
-        albedo_arr[[1, 2]] = f_23;
-        albedo_arr[[2, 1]] = 1.0 / f_23;
-
-        let f_24 = self.count_diff_albedo((SIZE, SIZE-1), (SIZE - 1, SIZE));
-        albedo_arr[[1, 3]] = f_24;
-        albedo_arr[[3, 1]] = 1.0 / f_24;
-
-        let f_25 = self.count_diff_albedo((SIZE + SIZE/2, SIZE-1), (SIZE + SIZE/2, SIZE));
-        albedo_arr[[1, 4]] = f_25;
-        albedo_arr[[4, 1]] = 1.0 / f_25;
-
-        let f_26 = self.count_diff_albedo((SIZE * 2 - 1, SIZE-1), (SIZE * 2, SIZE));
-        albedo_arr[[1, 5]] = f_26;
-        albedo_arr[[5, 1]] = 1.0 / f_26;
+    }
 
-        let f_35 = self.count_diff_albedo((SIZE*2, SIZE-1), (SIZE*2-1, SIZE));
-        albedo_arr[[2, 4]] = f_35;
-        albedo_arr[[4, 2]] = 1.0 / f_35;
+    //answers of a finished job replace the shown ones
+    fn collect_job(&mut self){
+        if !self.job.as_ref().is_some_and(|j| j.handle.is_finished()){
+            return;
+        }
+        let job = self.job.take().unwrap();
+        if let Some(lsa) = job.handle.join().unwrap(){
+            match job.kind{
+                JobKind::Solve => self.take_answers(lsa),
+                JobKind::Answers(AnswersJob::Posterior) => self.posterior = lsa.posterior,
+                JobKind::Answers(AnswersJob::MultiLight) => self.multi_light = lsa.multi_light,
+                JobKind::LoadPic => print_reverse_solution(&lsa)
+            }
+        }
+    }
 
-        let f_36 = self.count_diff_albedo((SIZE*2 + SIZE / 2, SIZE-1), (SIZE*2 + SIZE/2, SIZE));
-        albedo_arr[[2, 5]] = f_36;
-        albedo_arr[[5, 2]] = 1.0 / f_36;
+    fn take_answers(&mut self, lsa: LightSimApp){
+        self.scene_arr = lsa.scene_arr;
+        self.labels = lsa.labels;
+        self.reverse_solution_location = lsa.reverse_solution_location;
+        self.reverse_solution_height = lsa.reverse_solution_height;
+        self.height_residual = lsa.height_residual;
+        self.revere_solution_albedo = lsa.revere_solution_albedo;
+        self.albedo_solution = lsa.albedo_solution;
+        self.gradient_location = lsa.gradient_location;
+        self.loc_quality = lsa.loc_quality;
+        self.location_bootstrap = lsa.location_bootstrap;
+        self.confidence = lsa.confidence;
+        self.calibration = lsa.calibration;
+        self.lm_solution = lsa.lm_solution;
+        self.lm_confidence = lsa.lm_confidence;
+        self.diagnostics = lsa.diagnostics;
+        self.loc_times = lsa.loc_times;
+        //these were found from the old answers
+        self.posterior = None;
+        self.multi_light = None;
+    }
 
-        let f_45 = self.count_diff_albedo((SIZE-1, SIZE + SIZE / 2), (SIZE, SIZE + SIZE / 2));
-        albedo_arr[[3, 4]] = f_45;
-        albedo_arr[[4, 3]] = 1.0 / f_45;
+    //window of the gui's median filter, the noisier the picture the wider
+    fn median_size(&self) -> usize{
+        return self.noise_model.median_size(MEDIAN_NOISE, MAX_MEDIAN_SIZE);
+    }
 
-        let f_48 = self.count_diff_albedo((SIZE - 1, 2*SIZE - 1), (SIZE, 2*SIZE));
-        albedo_arr[[3, 7]] = f_48;
-        albedo_arr[[7, 3]] = 1.0 / f_48;
+    fn update_no_pic(&mut self){
+        self.segment();
+        self.solve_noise();
+        self.solve_segmented();
+        self.undo_stretch();
+    }
 
-        let f_47 = self.count_diff_albedo((SIZE/2, SIZE*2 - 1), (SIZE/2, SIZE*2));
-        albedo_arr[[3, 6]] = f_47;
-        albedo_arr[[6, 3]] = 1.0 / f_47;
+    //the stretch of a saved picture bends the falloff like a lower light would, for lights far outside
+    //all the way down to the lowest height. it leaves the isophotes where they are, so the light found
+    //on the stretched picture tells the stretch, and the picture is solved again without it. noise
+    //hides the stretch, a picture that can't tell it is kept as it is
+    fn undo_stretch(&mut self){
+        if !self.stretched || !self.is_solvable() || self.progress.is_cancelled(){
+            return;
+        }
+        self.progress.start("undoing the exposure stretch", 0);
+        let exposure = calibration::find_exposure(&self.scene_arr, &self.labels, self.reverse_solution_location);
+        if let Some(e) = &exposure{
+            if !e.is_degenerate(){
+                self.scene_arr = calibration::unstretch(&self.scene_arr, e.white);
+                self.stretched = false;
+                self.solve_noise();
+                self.solve_segmented();
+            }
+        }
+        self.exposure = exposure;
+    }
 
-        let f_56 = self.count_diff_albedo((SIZE*2 - 1, SIZE + SIZE / 2), (SIZE*2, SIZE + SIZE / 2));
-        albedo_arr[[4, 5]] = f_56;
-        albedo_arr[[5, 4]] = 1.0 / f_56;
+    //offset and sigma of a picture whose noise isn't known, the patches have to be found first
+    fn solve_noise(&mut self){
+        if self.estimate_noise{
+            self.noise_model = noise_model::NoiseModel::estimate(&self.scene_arr, &self.labels);
+        }
+    }
 
-        let f_57 = self.count_diff_albedo((SIZE, SIZE*2 - 1), (SIZE-1, SIZE*2));
-        albedo_arr[[4, 6]] = f_57;
-        albedo_arr[[6, 4]] = 1.0 / f_57;
+    //every solver, on the patches already found; a picture that can't be solved leaves no answers
+    fn solve_segmented(&mut self){
+        self.progress.start("checking the picture", 0);
+        let diagnostics = diagnostics::Diagnostics::check_image(&self.scene_arr, &self.labels, &self.noise_model);
+        let solvable = diagnostics.is_solvable();
+        self.diagnostics = Some(diagnostics);
+        if !solvable{
+            self.clear_solutions();
+            return;
+        }
+        self.solve_reverse();
+        if self.progress.is_cancelled(){
+            return;
+        }
+        self.progress.start("confidence intervals", 0);
+        self.solve_confidence();
+        self.solve_calibration();
+        if let Some(d) = &mut self.diagnostics{
+            d.check_solution(self.albedo_solution.as_ref(), self.confidence.as_ref());
+        }
+    }
 
-        let f_58 = self.count_diff_albedo((SIZE + SIZE/2, SIZE*2 - 1), (SIZE + SIZE/2, SIZE*2));
-        albedo_arr[[4, 7]] = f_58;
-        albedo_arr[[7, 4]] = 1.0 / f_58;
+    fn is_solvable(&self) -> bool{
+        return self.diagnostics.as_ref().is_none_or(|d| d.is_solvable());
+    }
 
-        let f_59 = self.count_diff_albedo((SIZE * 2 - 1, SIZE*2 - 1), (SIZE * 2, SIZE * 2));
-        albedo_arr[[4, 8]] = f_59;
-        albedo_arr[[8, 4]] = 1.0 / f_59;
+    fn clear_solutions(&mut self){
+        self.reverse_solution_location = (0.0, 0.0);
+        self.reverse_solution_height = 0.0;
+        self.height_residual = 0.0;
+        self.revere_solution_albedo = vec![0.0; self.labels.count()];
+        self.albedo_solution = None;
+        self.gradient_location = None;
+        self.loc_quality = None;
+        self.location_bootstrap = None;
+        self.confidence = None;
+        self.calibration = None;
+        self.lm_solution = None;
+        self.lm_confidence = None;
+        self.posterior = None;
+        self.multi_light = None;
+    }
 
-        let f_68 = self.count_diff_albedo((SIZE * 2, SIZE*2 - 1), (SIZE * 2 - 1, SIZE * 2));
-        albedo_arr[[5, 7]] = f_68;
-        albedo_arr[[7, 5]] = 1.0 / f_68;
+    //confidence intervals of the separate solvers' location, height and albedo
+    fn solve_confidence(&mut self){
+        self.confidence = uncertainty::single_light(&self.scene_arr, &self.labels, &self.noise_model, self.reverse_solution_location, self.reverse_solution_height, &self.revere_solution_albedo);
+        if let Some(c) = &mut self.confidence{
+            c.bootstrap = self.location_bootstrap;
+        }
+    }
 
-        let f_69 = self.count_diff_albedo((SIZE * 2 + SIZE / 2, SIZE*2 - 1), (SIZE * 2 + SIZE / 2, SIZE * 2));
-        albedo_arr[[5, 8]] = f_69;
-        albedo_arr[[8, 5]] = 1.0 / f_69;
+    //posterior of location, height and albedo around the joint fit (or the separate solvers' answer)
+    fn solve_posterior(&mut self, params: &mcmc::ChainParams){
+        if !self.is_solvable(){
+            return;
+        }
+        let start = match &self.lm_solution{
+            Some(sol) => (sol.location, sol.height, sol.albedo.clone()),
+            None => (self.reverse_solution_location, self.reverse_solution_height, self.revere_solution_albedo.clone())
+        };
+        let noise = if self.noise_model.is_clean() {
+            noise_model::NoiseModel::from_misfit(&self.scene_arr, &self.labels, start.0, start.1, &start.2)
+        } else {
+            self.noise_model
+        };
+        let extent = solver::vote_extent(self.scene_arr.dim());
+        let bounds = mcmc::Bounds{
+            min: extent.0,
+            max: extent.1,
+            max_height: height_fit::MAX_HEIGHT_FRAMES * self.scene_arr.shape()[0].max(self.scene_arr.shape()[1]) as f64
+        };
+        self.posterior = Some(mcmc::sample(&self.scene_arr, &self.labels, &start, noise, &bounds, params, &self.progress));
+    }
 
-        let f_78 = self.count_diff_albedo((SIZE - 1, SIZE * 2 + SIZE / 2), (SIZE, SIZE * 2 + SIZE / 2));
-        albedo_arr[[6, 7]] = f_78;
-        albedo_arr[[7, 6]] = 1.0 / f_78;
+    //how many lights there are and where, starting from the single light answer
+    fn solve_multi_light(&mut self, params: &multi_light::MultiLightParams){
+        if !self.is_solvable(){
+            return;
+        }
+        let start = (self.reverse_solution_location, self.reverse_solution_height);
+        self.multi_light = multi_light::solve(&self.scene_arr, &self.labels, &self.noise_model, start, params, &self.progress);
+    }
 
-        let f_89 = self.count_diff_albedo((SIZE * 2 - 1, SIZE * 2 + SIZE / 2), (SIZE * 2, SIZE * 2 + SIZE / 2));
-        albedo_arr[[7, 8]] = f_89;
-        albedo_arr[[8, 7]] = 1.0 / f_89;
+    //absolute albedo and luminosity, only when the scene file has a reference
+    fn solve_calibration(&mut self){
+        self.calibration = match &self.scene_file.reference{
+            Some(reference) => calibration::calibrate(&self.scene_arr, &self.labels, self.reverse_solution_location, self.reverse_solution_height, reference),
+            None => None
+        };
+        //what is left of the stretch once the one found without the reference is undone
+        if let (Some(cal), Some(exposure)) = (&mut self.calibration, &self.exposure){
+            cal.white *= exposure.white;
+            cal.white_interval = (cal.white_interval.0 * exposure.white, cal.white_interval.1 * exposure.white);
+        }
+    }
 
 
-        for i in 0..9{
-            for j in 0..9{
-                if albedo_arr[[i, j]] == 0.0{
-                    albedo_arr[[i, j]] = albedo_arr[[i, 4]] * albedo_arr[[4, j]];
-                }
-            }
+    //refines location, height and albedo together, starting from the answers of the other solvers
+    fn solve_lm(&mut self){
+        if !self.is_solvable(){
+            return;
         }
-
-        //full array
-        let mut max: usize = 0;
-        let mut cur_greater = 0;
-        let mut min: usize = 0;
-        let mut cur_smaller = 0;
-        for (i, r) in albedo_arr.outer_iter().enumerate(){
-            let mut small_count = 0;
-            let mut large_count = 0;
-            for (j, c) in r.iter().enumerate(){
-                if i != j{
-                    if c < &1.0{
-                        small_count += 1;
-                    }
-                    else {
-                        large_count += 1;
-                    }
-                }
-            }
-            if large_count > cur_greater{
-                max = i;
-                cur_greater = large_count;
-            } 
-            if small_count > cur_smaller{
-                min = i;
-                cur_smaller = small_count;
-            }
+        let sol = lm::fit_single_light(&self.scene_arr, &self.labels, &self.noise_model, self.reverse_solution_location, self.reverse_solution_height, &self.revere_solution_albedo);
+        if let Some(d) = &mut self.diagnostics{
+            d.check_fit("the refining joint fit", sol.height, self.scene_arr.dim(), sol.converged);
         }
-        //given max albedo can't be higher than 1.0
-        self.revere_solution_albedo[min] = albedo_arr[[min, max]];
-        for i in 0..9{
-            //self.revere_solution_albedo[i] = albedo_arr[[min, max]] * albedo_arr[[i, min]];
-            self.revere_solution_albedo[i] = albedo_arr[[i, max]];
+        self.lm_confidence = uncertainty::single_light(&self.scene_arr, &self.labels, &self.noise_model, sol.location, sol.height, &sol.albedo);
+        self.lm_solution = Some(sol);
+    }
+
+    //the chosen solver's location, height and albedo, and how good the location's geometry is
+    fn solve_reverse(&mut self){
+        let flat = match &self.diagnostics{
+            Some(d) => d.flat.clone(),
+            None => vec!()
+        };
+        let obs = solver::Observation{
+            scene_arr: &self.scene_arr,
+            labels: &self.labels,
+            noise: &self.noise_model,
+            flat: &flat,
+            loc_params: &self.loc_params,
+            progress: &self.progress
+        };
+        let sol = solver::by_name(&self.solver).unwrap().solve(&obs);
+        if let Some(d) = &mut self.diagnostics{
+            d.check_fit("the solver's joint fit", sol.height, self.scene_arr.dim(), sol.converged);
         }
-        //minimum is counted via
+        self.location_bootstrap = match sol.voted{
+            Some(v) if v == sol.location => uncertainty::bootstrap_location(&sol.fits, solver::vote_extent(self.scene_arr.dim()), &self.loc_params),
+            _ => None
+        };
+        self.loc_quality = Some(location_quality::assess(sol.location, sol.voted, &sol.fits, sol.gradient_location.as_ref(), (self.scene_arr.shape()[0] as f32, self.scene_arr.shape()[1] as f32)));
+        self.reverse_solution_location = sol.location;
+        self.reverse_solution_height = sol.height;
+        self.height_residual = sol.height_residual;
+        self.revere_solution_albedo = sol.albedo;
+        self.albedo_solution = sol.albedo_solution;
+        self.gradient_location = sol.gradient_location;
+        self.loc_times = sol.loc_times;
     }
-    
 }
 
 struct LightSource{
@@ -600,48 +773,57 @@ struct LightSource{
     height: u32, //in pixels
     is_on: bool,
     albedo: [f32; 9],
-    size: usize,
+    //of the picture, the mondrian's 3x3 grid is spread over it
+    shape: (usize, usize),
+    //lamps besides this one, they light the same albedo
+    extra: Vec<PointLight>,
     light_matrix: ndarray::Array2::<f32>
 }
 
+//a further lamp, at absolute image coordinates and as bright as intensity times the first one
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct PointLight{
+    coordinates: (i32, i32),
+    height: u32,
+    intensity: f32
+}
+
 
 fn get_actual_location(coordinates: (i32, i32), location: (usize, usize), size: usize) -> (i32, i32){
     return ((location.1 * size) as i32 + coordinates.0, (location.0 * size) as i32 + coordinates.1);
 }
 
 
-fn get_light(coordinates: (i32, i32), location: (usize, usize), height: u32, size: usize, j: usize, k: usize, albedo: &[f32]) -> f32{
-    let actual_location = get_actual_location(coordinates, location, size);
+fn get_light(coordinates: (i32, i32), location: (usize, usize), height: u32, shape: (usize, usize), j: usize, k: usize, albedo: &[f32]) -> f32{
+    let actual_location = get_actual_location(coordinates, location, shape.0 / 3);
     if height == 0{
         return 0.0;
     }
     let ground_dist = eucl_dist(&actual_location, &(j as i32, k as i32));
     let tg_a = ground_dist / (height as f32);
     let alpha = tg_a.atan();
-    let mut x = k / size;
-    let mut y = j / size;
-    if x > 2{
-        x = 2;
-    }
-    if y > 2{
-        y = 2;
-    }
+    let x = (k * 3 / shape.1).min(2);
+    let y = (j * 3 / shape.0).min(2);
     return alpha.cos().pow(3) * LIGHT_LUMINOSITY * albedo[3 * x + y];
 }
 
 impl LightSource{
     fn init(albedo_: &[f32; 9]) -> Self{
+        return LightSource::with_shape(albedo_, (SIZE*3, SIZE*3));
+    }
+
+    fn with_shape(albedo_: &[f32; 9], shape: (usize, usize)) -> Self{
         let location_ = (0, 0);
         let height_: u32 = 0;
-        let shape = (SIZE*3, SIZE*3);
         let light_matrix_ = ndarray::Array2::<f32>::default(shape);
         let is_on_ = false;
         return LightSource { 
             location: location_,
             coordinates: (0, 0),
             height: height_, 
-            size: SIZE,
+            shape,
             albedo: *albedo_,
+            extra: vec!(),
             light_matrix: light_matrix_,
             is_on: is_on_ 
         };
@@ -651,7 +833,10 @@ impl LightSource{
         if self.is_on{
             ndarray::Zip::indexed(self.light_matrix.outer_iter_mut()).par_for_each(|j, mut row| {
                 for (k, col) in row.iter_mut().enumerate(){
-                    *col = get_light(self.coordinates, self.location, self.height, self.size, j, k, &self.albedo);
+                    *col = get_light(self.coordinates, self.location, self.height, self.shape, j, k, &self.albedo);
+                    for l in self.extra.iter(){
+                        *col += l.intensity * get_light(l.coordinates, (0, 0), l.height, self.shape, j, k, &self.albedo);
+                    }
                 }
             });
         }
@@ -664,29 +849,34 @@ struct Noise{
     noise_array: ndarray::Array2::<f32>,
     mean: f64,
     sigma: f64,
-    seed: u64,  
-    gen: probability::distribution::Gaussian,
     is_on: bool
 }
 
 impl Noise{
     fn init() -> Noise{
-        let mut source = probability::source::default(SEED);
-        let distr = probability::distribution::Gaussian::new(MEAN, SIGMA);
+        return Noise::from_params(MEAN, SIGMA, SEED, (SIZE * 3, SIZE * 3));
+    }
+
+    //nothing drawn, for apps that never render
+    fn off() -> Noise{
+        return Noise{noise_array: ndarray::Array2::<f32>::default((0, 0)), mean: MEAN, sigma: SIGMA, is_on: false};
+    }
+
+    fn from_params(mean: f64, sigma: f64, seed: u64, shape: (usize, usize)) -> Noise{
+        let mut source = probability::source::default(seed);
+        let distr = probability::distribution::Gaussian::new(mean, sigma);
         let sampler = probability::sampler::Independent(&distr, &mut source);
-        let values = sampler.take(SIZE*SIZE*9).collect::<Vec<_>>();
-        let mut n_a = ndarray::Array2::<f32>::default((SIZE * 3, SIZE*3));
+        let values = sampler.take(shape.0 * shape.1).collect::<Vec<_>>();
+        let mut n_a = ndarray::Array2::<f32>::default(shape);
         for i in 0..n_a.shape()[0]{
             for j in 0..n_a.shape()[1]{
-                n_a[[i, j]] = values[SIZE*3*j + i] as f32;
+                n_a[[i, j]] = values[shape.0*j + i] as f32;
             }
         }
         return Noise { 
             noise_array: n_a, 
-            mean: MEAN, 
-            sigma: SIGMA, 
-            seed: SEED, 
-            gen: distr, 
+            mean, 
+            sigma, 
             is_on: false 
         }
     }
@@ -695,20 +885,13 @@ impl Noise{
 
 struct Scene{
     scene_array: ndarray::Array2::<f32>,
-    scene_image: image::GrayImage,
-    size: usize
+    scene_image: image::GrayImage
 }
 
-fn decide(sz: usize, j: usize, k:usize) -> f32{
-    let mut x = k / sz;
-    let mut y = j / sz;
-    if x > 2{
-        x = 2;
-    }
-    if y > 2{
-        y = 2;
-    }
-    return COLORS[x * 3 +  y] as f32;
+fn decide(shape: (usize, usize), j: usize, k:usize) -> f32{
+    let x = (k * 3 / shape.1).min(2);
+    let y = (j * 3 / shape.0).min(2);
+    return COLORS[x * 3 +  y];
 }
 
 fn decide_light(orig: f32, lighted: f32, noise: f32, is_noise_on: bool) -> f32{
@@ -743,12 +926,11 @@ fn srgb_to_clinear(input: usize) -> f32{
     }
 }
 
-fn generate_arr(sz: usize) -> ndarray::Array2::<f32>{
-    let shape = (sz*3, sz*3);
+fn generate_arr(shape: (usize, usize)) -> ndarray::Array2::<f32>{
     let mut arr = ndarray::Array2::<f32>::default(shape);
     ndarray::Zip::indexed(arr.outer_iter_mut()).par_for_each(|j, mut row| {
         for (k, col) in row.iter_mut().enumerate(){
-            *col = decide(sz, j, k);
+            *col = decide(shape, j, k);
         }
     });
     return arr
@@ -786,7 +968,7 @@ fn prep_arr(arr: &ndarray::Array2::<f32>) -> ndarray::Array2::<f32>{
 }
 
 fn arr_to_img(arr: &ndarray::Array2::<f32>) -> image::GrayImage{
-    let arr_ = prep_arr(&arr);
+    let arr_ = prep_arr(arr);
     let mut img = image::ImageBuffer::new(arr_.dim().0 as u32, arr_.dim().1 as u32);
     for (r, row) in arr_.outer_iter().enumerate()  {
         for (c, col) in row.iter().enumerate(){
@@ -798,27 +980,34 @@ fn arr_to_img(arr: &ndarray::Array2::<f32>) -> image::GrayImage{
     return img;  
 }
 
-fn generate_arr_and_img(sz: usize)-> (ndarray::Array2::<f32>, image::GrayImage){
-    let arr = generate_arr(sz);
+fn generate_arr_and_img(shape: (usize, usize))-> (ndarray::Array2::<f32>, image::GrayImage){
+    let arr = generate_arr(shape);
     let img = arr_to_img(&arr);
     return (arr, img)
 }
 
 
 impl Scene{
-    fn init(sz: usize) -> Self{
-        let (arr, img) = generate_arr_and_img(sz);
-        img.save("scene.png").unwrap();
+    fn new(sz: usize) -> Self{
+        return Scene::with_shape((sz*3, sz*3));
+    }
+
+    //no mondrian, for apps that never render
+    fn empty() -> Self{
+        return Scene{scene_array: ndarray::Array2::<f32>::default((0, 0)), scene_image: image::GrayImage::new(0, 0)};
+    }
+
+    //the mondrian stretched over a picture of any width and height
+    fn with_shape(shape: (usize, usize)) -> Self{
+        let (arr, img) = generate_arr_and_img(shape);
         return Scene{
             scene_array: arr, 
-            scene_image: img,
-            size: sz
+            scene_image: img
         };
     }
 
     fn recount_final_array(&self, light_matrix: &ndarray::Array2::<f32>, noise_matrix: &ndarray::Array2::<f32>, is_noise_on: bool) -> ndarray::Array2::<f32>{
-        let shape = (self.size*3, self.size*3);
-        let mut arr = ndarray::Array2::<f32>::default(shape);
+        let mut arr = ndarray::Array2::<f32>::default(self.scene_array.dim());
         ndarray::Zip::indexed(arr.outer_iter_mut()).par_for_each(|j, mut row| {
             for (k, col) in row.iter_mut().enumerate(){
                 *col = decide_light(self.scene_array[[j, k]], light_matrix[[j, k]], noise_matrix[[j, k]], is_noise_on);
@@ -827,50 +1016,274 @@ impl Scene{
         return arr;
     }
 
-    fn update(&mut self, ls: &LightSource, ns: &Noise) -> ndarray::Array2::<f32>{
+    //the linear picture; its 8 bit version stays in scene_image, only Save pic writes it out
+    fn render(&mut self, ls: &LightSource, ns: &Noise) -> ndarray::Array2::<f32>{
         let mut new_arr = self.recount_final_array(&ls.light_matrix, &ns.noise_array, ns.is_on);
         if !ls.is_on{
             new_arr = self.scene_array.clone();
         }
         self.scene_image = arr_to_img(&new_arr);
-        self.scene_image.save("scene.png").unwrap();
         return new_arr;
     }
 }
 
-fn reverse_solve_task(path: &str){
+fn load_linear_image(path: &str) -> ndarray::Array2::<f32>{
     let img = image::open(path).unwrap().grayscale();
     let img = img.as_luma8().unwrap();
     let mut img_arr = ndarray::Array2::<f32>::default((img.width() as usize, img.height() as usize));
-    for i in 0..900{
-        for j in 0..900{
+    for i in 0..img.width() as usize{
+        for j in 0..img.height() as usize{
             img_arr[[i, j]] = srgb_to_clinear(img.get_pixel(i as u32, j as u32).0[0] as usize);
         }
     }
-    let ls = LightSource::init(ALBEDO);
-    let sc = Scene::init(300);
-    let img_ = load_im_egui();
-    let rev_sol_h = 0;
-    let rev_sol_loc = (0, 0);
-    let rev_sol_albed: Vec<f32> = [0.0, 0.0, 0.0, 
-                                   0.0, 0.0, 0.0, 
-                                   0.0, 0.0, 0.0].to_vec();
-    let arr = img_arr;
-    let ns = Noise::init();
-    let mut lsa = LightSimApp { 
-        light_source: ls, 
-        scene: sc,
-        noise: ns,
-        reverse_solution_location: rev_sol_loc,
-        img_gui: egui_extras::RetainedImage::from_color_image("sceneimg", img_),
-        reverse_solution_height: rev_sol_h,
-        revere_solution_albedo: rev_sol_albed,
-        scene_arr: arr
+    return img_arr;
+}
+
+fn print_location_quality(lsa: &LightSimApp){
+    if let Some(q) = &lsa.loc_quality{
+        println!("loc_geometry: {}, {:.1} px outside the frame, longest arc {:.0}°, fit spread {:.1} px",
+                 if q.is_weak() {"weak"} else {"ok"}, lsa.frame.length_to_picture(q.outside), q.best_arc.to_degrees(), lsa.frame.length_to_picture(q.spread));
+        for r in q.reasons.iter(){
+            println!("loc_weak_geometry: {}", r);
+        }
+    }
+}
+
+//every problem found with its severity, false when the picture couldn't be solved
+fn print_diagnostics(lsa: &LightSimApp) -> bool{
+    if let Some(d) = &lsa.diagnostics{
+        for p in d.problems.iter(){
+            let severity = match p.severity(){
+                diagnostics::Severity::IllConditioned => "ill_conditioned",
+                diagnostics::Severity::Unsolvable => "unsolvable"
+            };
+            println!("{}: {}", severity, p.describe());
+        }
+    }
+    return lsa.is_solvable();
+}
+
+fn print_noise(lsa: &LightSimApp){
+    println!("noise: mean {}, sigma {}{}", lsa.noise_model.mean, lsa.noise_model.sigma, if lsa.estimate_noise {" (estimated)"} else {""});
+}
+
+fn print_exposure(lsa: &LightSimApp){
+    if let Some(e) = &lsa.exposure{
+        println!("exposure: prep_arr stretched sRGB by {} (white {} within [{}, {}]), residual {}", 1.0 / e.white, e.white, e.white_interval.0, e.white_interval.1, e.residual);
+        if lsa.stretched{
+            println!("exposure_weak: the picture barely constrains the stretch, it was left in and the height moves with it");
+        }
+    }
+}
+
+fn print_albedo_confidence(lsa: &LightSimApp){
+    if let Some(sol) = &lsa.albedo_solution{
+        println!("albedo_sigma: {:?}", sol.log_sigma);
+        println!("albedo_graph: {} edges ({} rejected), {} boundary pairs ({} rejected)", sol.edges, sol.rejected_edges, sol.pairs, sol.rejected_pairs);
+    }
+}
+
+//95% intervals in the picture's pixels, height in the same units as height_sol
+fn print_confidence(prefix: &str, frame: &frame::Frame, conf: &Option<uncertainty::Confidence>, location: (f32, f32), height: f32, albedo: &[f32]){
+    if let Some(c) = conf{
+        let sigma = c.location_sigma();
+        let location = frame.to_picture(location);
+        let sigma = (frame.length_to_picture(sigma.0), frame.length_to_picture(sigma.1));
+        println!("{}loc_ci: x {:?}, y {:?}", prefix, uncertainty::interval(location.0, sigma.0), uncertainty::interval(location.1, sigma.1));
+        if let Some(b) = c.bootstrap{
+            println!("{}loc_sigma: jacobian ({}, {}), bootstrap ({}, {})", prefix, frame.length_to_picture(c.location.0), frame.length_to_picture(c.location.1),
+                     frame.length_to_picture(b.0), frame.length_to_picture(b.1));
+        }
+        let h = uncertainty::interval(height, c.height);
+        println!("{}height_ci: ({}, {})", prefix, h.0 / frame.diagonal(), h.1 / frame.diagonal());
+        let albedo: Vec<(f32, f32)> = albedo.iter().zip(c.albedo.iter()).map(|(a, s)| uncertainty::interval(*a, *s)).collect();
+        println!("{}albedo_ci: {:?}", prefix, albedo);
+    }
+}
+
+fn print_calibration(lsa: &LightSimApp){
+    if let Some(cal) = &lsa.calibration{
+        println!("luminosity_sol: {}", cal.luminosity);
+        println!("albedo_abs_sol: {:?}", cal.albedo);
+        println!("height_cal_sol: {}", lsa.frame.length_to_picture(cal.height));
+        println!("exposure: brightest pixel was {} linear ({} sRGB), prep_arr stretched sRGB by {}; white within [{}, {}], residual {}",
+                 cal.brightest(), cal.white, cal.gain(), cal.white_interval.0, cal.white_interval.1, cal.residual);
+        if cal.is_degenerate(){
+            println!("exposure_weak: the picture barely constrains the stretch, luminosity and absolute albedo scale with it");
+        }
+    }
+}
+
+fn reverse_solve_task(path: &str, solver: &str){
+    let mut lsa = LightSimApp::from_scene_arr(load_linear_image(path));
+    lsa.solver = solver.to_string();
+    lsa.update_no_pic();
+    print_reverse_solution(&lsa);
+}
+
+fn print_reverse_solution(lsa: &LightSimApp){
+    if !print_diagnostics(lsa){
+        return;
+    }
+    print_noise(lsa);
+    print_exposure(lsa);
+    println!("height_sol: {} (residual {})", lsa.reverse_solution_height / lsa.frame.diagonal(), lsa.height_residual);
+    println!("loc_sol: {:?}", lsa.frame.to_picture(lsa.reverse_solution_location));
+    if let Some(gl) = &lsa.gradient_location{
+        println!("loc_sol_gradient: {:?} ({} lines, conditioning {})", lsa.frame.to_picture(gl.location), gl.lines, gl.condition);
+    }
+    print_location_quality(lsa);
+    println!("patches: {}", lsa.labels.count());
+    println!("albedo_sol: {:?}", lsa.revere_solution_albedo);
+    print_albedo_confidence(lsa);
+    print_confidence("", &lsa.frame, &lsa.confidence, lsa.reverse_solution_location, lsa.reverse_solution_height, &lsa.revere_solution_albedo);
+}
+
+fn reverse_solve_lm(path: &str, scene_path: Option<&str>){
+    let mut lsa = LightSimApp::from_scene_arr(load_linear_image(path));
+    if let Some(scene_path) = scene_path{
+        //the file speaks of the picture's pixels, the solvers see the resampled ones
+        lsa.scene_file = scene_file::SceneFile::read(scene_path);
+        if let Some(noise) = lsa.scene_file.noise{
+            lsa.noise_model = noise_model::NoiseModel::init(noise.mean, noise.sigma * lsa.frame.noise_factor());
+            lsa.estimate_noise = false;
+        }
+        lsa.scene_file.reference = lsa.scene_file.reference.as_ref().map(|r| lsa.frame.reference(r));
+    }
+    lsa.update_no_pic();
+    //solve_lm leaves an unsolvable picture alone, and adds what it finds to the diagnostics
+    lsa.solve_lm();
+    if !print_diagnostics(&lsa){
+        return;
+    }
+    let sol = lsa.lm_solution.as_ref().unwrap();
+    print_noise(&lsa);
+    println!("height_sol: {}", sol.height / lsa.frame.diagonal());
+    println!("loc_sol: {:?}", lsa.frame.to_picture(sol.location));
+    println!("patches: {}", lsa.labels.count());
+    println!("albedo_sol: {:?}", sol.relative_albedo());
+    print_location_quality(&lsa);
+    print_albedo_confidence(&lsa);
+    print_confidence("", &lsa.frame, &lsa.confidence, lsa.reverse_solution_location, lsa.reverse_solution_height, &lsa.revere_solution_albedo);
+    print_confidence("lm_", &lsa.frame, &lsa.lm_confidence, sol.location, sol.height, &sol.relative_albedo());
+    print_calibration(&lsa);
+    println!("lm: cost {} after {} iterations, converged: {}", sol.cost, sol.iterations, sol.converged);
+    //x y h albedo in the picture's pixels, the same format NOMAD and estimate_solutions.py use
+    let albedo = sol.albedo.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(" ");
+    let location = lsa.frame.to_picture(sol.location);
+    println!("{} {} {} {}", location.0.round(), location.1.round(), lsa.frame.length_to_picture(sol.height).round(), albedo);
+}
+
+fn reverse_solve_mcmc(path: &str, params: &mcmc::ChainParams, samples_path: Option<&str>){
+    let mut lsa = LightSimApp::from_scene_arr(load_linear_image(path));
+    lsa.update_no_pic();
+    //solve_lm leaves an unsolvable picture alone, and adds what it finds to the diagnostics
+    lsa.solve_lm();
+    if !print_diagnostics(&lsa){
+        return;
+    }
+    lsa.solve_posterior(params);
+    let post = lsa.posterior.as_ref().unwrap();
+    println!("mcmc: {} samples, acceptance {:.3}, noise sigma {}", post.samples.len(), post.acceptance, post.noise.sigma);
+    //95% credible intervals and the medians, location in the picture's pixels and height in the same
+    //units as height_sol
+    let to_picture = |i: usize, v: f64| match i{
+        0 => lsa.frame.to_picture((v as f32, 0.0)).0 as f64,
+        1 => lsa.frame.to_picture((0.0, v as f32)).1 as f64,
+        2 => v / lsa.frame.diagonal() as f64,
+        _ => v
     };
+    for (i, name) in post.names.iter().enumerate(){
+        let (lo, median, hi) = post.credible(i);
+        println!("{}_ci: ({}, {}), median {}", name, to_picture(i, lo), to_picture(i, hi), to_picture(i, median));
+    }
+    for (i, name) in post.names.iter().enumerate(){
+        //bins in the picture's pixels, they are only stretched
+        let (lo, hi, counts) = post.histogram(i);
+        let (lo, hi) = if i == 2 {(lsa.frame.length_to_picture(lo as f32) as f64, lsa.frame.length_to_picture(hi as f32) as f64)} else {(to_picture(i, lo), to_picture(i, hi))};
+        println!("{}_hist: {} {} {:?}", name, lo, hi, counts);
+    }
+    if let Some(samples_path) = samples_path{
+        post.write_csv(samples_path);
+    }
+}
+
+fn reverse_solve_lights(path: &str, params: &multi_light::MultiLightParams){
+    let mut lsa = LightSimApp::from_scene_arr(load_linear_image(path));
     lsa.update_no_pic();
-    println!("height_sol: {}", lsa.reverse_solution_height as f32 / DIAG.sqrt());
-    println!("loc_sol: {}", format!("{:?}", lsa.reverse_solution_location));
-    println!("albedo_sol: {}", format!("{:?}", lsa.revere_solution_albedo));
+    if !print_diagnostics(&lsa){
+        return;
+    }
+    lsa.solve_multi_light(params);
+    let sol = match &lsa.multi_light{
+        Some(sol) => sol,
+        None => {
+            println!("no pixels left to fit lights to");
+            return;
+        }
+    };
+    println!("lights: {} (bic {:?}), offset {}, cost {}, converged: {}", sol.lights.len(), sol.bic, sol.offset, sol.cost, sol.converged);
+    for l in sol.lights.iter(){
+        println!("light_sol: {:?} height {} intensity {}", lsa.frame.to_picture(l.location), l.height / lsa.frame.diagonal(), l.intensity);
+    }
+    println!("albedo_sol: {:?}", sol.albedo);
+}
+
+//pictures of the same mondrian under different lights, every one solved alone first
+fn reverse_solve_stereo(paths: &[String]){
+    let apps: Vec<LightSimApp> = paths.iter().map(|path| LightSimApp::from_scene_arr(load_linear_image(path))).collect();
+    //one mondrian seen from one place, so one size of picture
+    if let Some((path, _)) = paths.iter().zip(apps.iter()).find(|(_, lsa)| lsa.frame.original != apps[0].frame.original){
+        println!("unsolvable: {} is not the size of {}, stereo pictures are of one mondrian seen from one place", path, paths[0]);
+        return;
+    }
+    let frame = frame::Frame::fit(apps[0].frame.original, SIZE * 3);
+    //the patches don't move between the pictures, but a boundary one light hardly shows another may,
+    //so the picture cut into the most patches decides them for all
+    let labels = apps.iter().map(|lsa| segmentation::segment(&lsa.scene_arr)).max_by_key(|l| l.count()).unwrap();
+    //pictures with nothing to solve don't take part
+    let mut solved = vec!();
+    let mut starts = vec!();
+    let mut arrs = vec!();
+    for (path, mut lsa) in paths.iter().zip(apps){
+        lsa.labels = labels.clone();
+        lsa.revere_solution_albedo = vec![0.0; labels.count()];
+        lsa.solve_noise();
+        lsa.solve_segmented();
+        //every picture was stretched on its own, a scale per frame can't undo that
+        lsa.undo_stretch();
+        if !lsa.is_solvable(){
+            println!("skipped: {}", path);
+            print_diagnostics(&lsa);
+            continue;
+        }
+        solved.push(path);
+        starts.push((lsa.reverse_solution_location, lsa.reverse_solution_height, lsa.revere_solution_albedo.clone()));
+        arrs.push(lsa.scene_arr);
+    }
+    if arrs.len() < 2{
+        println!("unsolvable: fewer than two of the pictures can be solved");
+        return;
+    }
+    let sol = match stereo::solve(&arrs, &labels, &starts){
+        Some(sol) => sol,
+        None => {
+            println!("unsolvable: fewer than two of the pictures have a light that is not stuck at a bound of its height or brightness");
+            return;
+        }
+    };
+    println!("stereo: {} frames, cost {} after {} iterations, converged: {}", arrs.len(), sol.cost, sol.iterations, sol.converged);
+    for (path, light) in solved.iter().zip(sol.lights.iter()){
+        println!("light_sol: {} {:?} height {} scale {} offset {}", path, frame.to_picture(light.location), light.height / frame.diagonal(), light.scale, light.offset);
+        if light.degenerate{
+            println!("ill_conditioned: the light of {} is stuck at a bound of its height or brightness, the picture was left out of the fit", path);
+        }
+    }
+    println!("albedo_sol: {:?}", sol.albedo);
+    println!("albedo_sigma: {:?}", sol.albedo_sigma);
+    for (path, sigma) in solved.iter().zip(sol.single_sigma.iter()){
+        println!("albedo_sigma_single: {} {:?}", path, sigma);
+    }
 }
 
 //GUI
@@ -881,6 +1294,7 @@ impl eframe::App for LightSimApp{
             ui.heading("Light Simulation");
             ui.vertical(|ui|{
                 ui.vertical(|ui|{
+                    let reach = (location_quality::OUTSIDE_FRAMES * (SIZE * 3) as f64) as i32;
                     ui.add(eframe::egui::Slider::new(&mut self.light_source.height, 0..=1200).text("Light source height"));
                     eframe::egui::ComboBox::from_label("Light Position")
                     .selected_text(format!("{:?}", self.light_source.location)).show_ui(ui, |ui| {
@@ -894,10 +1308,48 @@ impl eframe::App for LightSimApp{
                         ui.selectable_value(&mut self.light_source.location, (2, 1), "(2, 1)");
                         ui.selectable_value(&mut self.light_source.location, (2, 2), "(2, 2)");
                     });
-                    ui.add(eframe::egui::Slider::new(&mut self.light_source.coordinates.0, -1200..=(SIZE-1) as i32).text("Light source X coordinate"));
-                    ui.add(eframe::egui::Slider::new(&mut self.light_source.coordinates.1, -1200..=(SIZE-1) as i32).text("Light source Y coordinate"));
+                    ui.add(eframe::egui::Slider::new(&mut self.light_source.coordinates.0, -reach..=reach + (SIZE-1) as i32).text("Light source X coordinate"));
+                    ui.add(eframe::egui::Slider::new(&mut self.light_source.coordinates.1, -reach..=reach + (SIZE-1) as i32).text("Light source Y coordinate"));
                     ui.add(eframe::egui::Checkbox::new(&mut self.light_source.is_on, "Turn the light on"));
                     ui.add(eframe::egui::Checkbox::new(&mut self.noise.is_on, "Noise"));
+                    let median_size = self.median_size();
+                    ui.add(eframe::egui::Checkbox::new(&mut self.median_filter, format!("Median filter ({}x{})", median_size, median_size)));
+                    ui.add(eframe::egui::Checkbox::new(&mut self.use_lm, "Levenberg-Marquardt refinement"));
+                    ui.collapsing("Further lights", |ui| {
+                        let mut removed = None;
+                        for (i, l) in self.light_source.extra.iter_mut().enumerate(){
+                            ui.horizontal(|ui| {
+                                ui.add(eframe::egui::Slider::new(&mut l.coordinates.0, -reach..=reach + (SIZE * 3 - 1) as i32).text("X"));
+                                ui.add(eframe::egui::Slider::new(&mut l.coordinates.1, -reach..=reach + (SIZE * 3 - 1) as i32).text("Y"));
+                                ui.add(eframe::egui::Slider::new(&mut l.height, 1..=1200).text("Height"));
+                                ui.add(eframe::egui::Slider::new(&mut l.intensity, 0.0..=2.0).text("Intensity"));
+                                if ui.button("Remove").clicked(){
+                                    removed = Some(i);
+                                }
+                            });
+                        }
+                        if let Some(i) = removed{
+                            self.light_source.extra.remove(i);
+                        }
+                        if ui.button("Add light").clicked(){
+                            self.light_source.extra.push(PointLight{coordinates: ((SIZE * 3 / 2) as i32, (SIZE * 3 / 2) as i32), height: SIZE as u32, intensity: 1.0});
+                        }
+                    });
+                    eframe::egui::ComboBox::from_label("Reverse solver")
+                    .selected_text(self.solver.clone()).show_ui(ui, |ui| {
+                        for s in solver::registry(){
+                            ui.selectable_value(&mut self.solver, s.name().to_string(), s.name()).on_hover_text(s.describe());
+                        }
+                    });
+                    ui.collapsing("Location accumulator", |ui| {
+                        ui.add(eframe::egui::Slider::new(&mut self.loc_params.bin_size, 0.25..=8.0).text("Bin size, px"));
+                        ui.add(eframe::egui::Slider::new(&mut self.loc_params.smoothing, 0.0..=5.0).text("Smoothing sigma, bins"));
+                        eframe::egui::ComboBox::from_label("Peak refinement")
+                        .selected_text(format!("{:?}", self.loc_params.refinement)).show_ui(ui, |ui| {
+                            ui.selectable_value(&mut self.loc_params.refinement, accumulator::PeakRefinement::Parabolic, "Parabolic");
+                            ui.selectable_value(&mut self.loc_params.refinement, accumulator::PeakRefinement::Centroid, "Centroid");
+                        });
+                    });
                 });
             self.img_gui.show(ui);
             if ui.button("Save pic").clicked(){
@@ -905,35 +1357,181 @@ impl eframe::App for LightSimApp{
 
                 let mut path = "scene_x".to_string() + &loc.0.to_string() + "_y" + &loc.1.to_string()+ "_h" + self.light_source.height.to_string().as_str();
                 if self.noise.is_on{
-                    path += &"_noised".to_string();
+                    path += "_noised";
                 }
-                path += &".png".to_string();
+                path += ".png";
                 self.scene.scene_image.save(path).unwrap();
             }
-            if ui.button("Load pic").clicked(){
-                let path = "mondrian_albedo_estimation_frame_3.png";
-                reverse_solve_task(path);
+            //one job at a time; a new picture still cancels whatever runs
+            if ui.add_enabled(self.job.is_none(), eframe::egui::Button::new("Load pic")).clicked(){
+                self.start_load_pic(ui.ctx(), "mondrian_albedo_estimation_frame_3.png");
+            }
+            self.collect_job();
+            if self.light_source.is_on{
+                self.refresh(ui.ctx());
+            }
+            if let Some(stage) = self.job.as_ref().map(|j| j.progress.describe()){
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(format!("solving: {}", stage));
+                    if ui.button("Cancel").clicked(){
+                        self.cancel_job();
+                    }
+                });
+                //progress is polled, the worker only wakes the gui when it is done
+                ui.ctx().request_repaint_after(std::time::Duration::from_millis(100));
             }
             if self.light_source.is_on{
-                self.update_();
                 ui.label("Reverse task soltions:");
                 ui.horizontal(|ui| {
-                    ui.label(format!("location: {:?}", self.reverse_solution_location));
-                    ui.label(format!(" ~ error{}", (eucl_dist(&get_actual_location(self.light_source.coordinates, self.light_source.location, SIZE), &self.reverse_solution_location) as f32 / DIAG.sqrt())));
+                    let actual = get_actual_location(self.light_source.coordinates, self.light_source.location, SIZE);
+                    ui.label(format!("location: ({:.1}, {:.1})", self.reverse_solution_location.0, self.reverse_solution_location.1));
+                    ui.label(format!(" ~ error{}", (eucl_dist_f32(&(actual.0 as f32, actual.1 as f32), &self.reverse_solution_location) / DIAG.sqrt())));
+                    if let Some(c) = &self.confidence{
+                        let sigma = c.location_sigma();
+                        ui.label(format!(" ~ 95%: ± ({:.1}, {:.1}) px", uncertainty::half_width(sigma.0), uncertainty::half_width(sigma.1)));
+                    }
                  });
+                if let Some(gl) = &self.gradient_location{
+                    ui.horizontal(|ui| {
+                        ui.label(format!("gradient lines: ({:.1}, {:.1}), {} lines, conditioning {:.3}", gl.location.0, gl.location.1, gl.lines, gl.condition));
+                        ui.label(format!(" ~ {:.0} ms vs {:.0} ms voting, {:.1} px apart", self.loc_times.1, self.loc_times.0,
+                                         eucl_dist_f32(&gl.location, &self.reverse_solution_location)));
+                    });
+                }
+                if let Some(q) = &self.loc_quality{
+                    if q.outside > 0.0{
+                        ui.label(format!("light is {:.0} px outside the frame, longest arc {:.0}°, fit spread {:.0} px", q.outside, q.best_arc.to_degrees(), q.spread));
+                    }
+                    for r in q.reasons.iter(){
+                        ui.colored_label(eframe::egui::Color32::from_rgb(220, 120, 0), format!("weak geometry: {}", r));
+                    }
+                }
                 ui.horizontal(|ui| {
-                    ui.label(format!("height: {}", (self.reverse_solution_height as f32 / DIAG.sqrt() as f32)));
-                    ui.label(format!(" ~ error {}", (self.reverse_solution_height.abs_diff(self.light_source.height) as f32/ self.light_source.height as f32)));
+                    ui.label(format!("height: {}", (self.reverse_solution_height / DIAG.sqrt())));
+                    ui.label(format!(" ~ error {}", ((self.reverse_solution_height - self.light_source.height as f32).abs() / self.light_source.height as f32)));
+                    ui.label(format!(" ~ residual {:.4}", self.height_residual));
+                    if let Some(c) = &self.confidence{
+                        let h = uncertainty::interval(self.reverse_solution_height, c.height);
+                        ui.label(format!(" ~ 95%: [{:.4}, {:.4}]", h.0 / DIAG.sqrt(), h.1 / DIAG.sqrt()));
+                    }
                 });
+                if let Some(sol) = &self.albedo_solution{
+                    ui.label(format!("albedo graph: {} edges ({} rejected), {} boundary pairs ({} rejected)", sol.edges, sol.rejected_edges, sol.pairs, sol.rejected_pairs));
+                }
+                ui.label(format!("segmentation: {} patches", self.labels.count()));
+                if let Some(d) = &self.diagnostics{
+                    for p in d.problems.iter(){
+                        match p.severity(){
+                            diagnostics::Severity::Unsolvable => ui.colored_label(eframe::egui::Color32::from_rgb(220, 40, 40), format!("unsolvable: {}", p.describe())),
+                            diagnostics::Severity::IllConditioned => ui.colored_label(eframe::egui::Color32::from_rgb(220, 120, 0), format!("ill conditioned: {}", p.describe()))
+                        };
+                    }
+                }
                 for i in 0..3{
                     for j in 0..3{
+                        let p = self.grid_patch(i, j);
                         ui.horizontal(|ui| {
-                            ui.label(format!("Albedo [{}, {}] / Maximum Albedo: {:.2}", i, j, self.revere_solution_albedo[3*i + j]));
-                            ui.label(format!(" ~ error {:.3}", (self.revere_solution_albedo[3*i + j] - (ALBEDO[3*i + j] / ALBEDO.iter().max_by(|p, l| p.partial_cmp(l).unwrap()).unwrap())).abs()));
+                            ui.label(format!("Albedo [{}, {}] / Maximum Albedo: {:.2}", i, j, self.revere_solution_albedo[p]));
+                            ui.label(format!(" ~ error {:.3}", (self.revere_solution_albedo[p] - (ALBEDO[3*i + j] / ALBEDO.iter().max_by(|p, l| p.partial_cmp(l).unwrap()).unwrap())).abs()));
+                            if let Some(sol) = &self.albedo_solution{
+                                //log sigma is the relative error of the ratio
+                                ui.label(format!(" ± {:.1}%", sol.log_sigma[p] * 100.0));
+                            }
+                            if let Some(c) = &self.confidence{
+                                let a = uncertainty::interval(self.revere_solution_albedo[p], c.albedo[p]);
+                                ui.label(format!(" ~ 95%: [{:.3}, {:.3}]", a.0, a.1));
+                            }
                         });
 
                     }
                 }
+                if let Some(cal) = &self.calibration{
+                    ui.label("Calibrated by the reference:");
+                    ui.horizontal(|ui| {
+                        ui.label(format!("luminosity: {:.3}", cal.luminosity));
+                        ui.label(format!(" ~ error {:.3}", (cal.luminosity - LIGHT_LUMINOSITY).abs() / LIGHT_LUMINOSITY));
+                    });
+                    ui.label(format!("exposure: brightest pixel {:.3} linear, stretched by {:.3}, white within [{:.3}, {:.3}]",
+                                     cal.brightest(), cal.gain(), cal.white_interval.0, cal.white_interval.1));
+                    if cal.is_degenerate(){
+                        ui.colored_label(eframe::egui::Color32::from_rgb(220, 120, 0), "weak exposure: luminosity and absolute albedo scale with the stretch");
+                    }
+                    for i in 0..3{
+                        ui.horizontal(|ui| {
+                            for j in 0..3{
+                                let p = self.grid_patch(i, j);
+                                ui.label(format!("[{}, {}]: {:.2} (~ error {:.3})", i, j, cal.albedo[p], (cal.albedo[p] - ALBEDO[3*i + j]).abs()));
+                            }
+                        });
+                    }
+                }
+                if self.use_lm{
+                    if let Some(sol) = &self.lm_solution{
+                        let actual = get_actual_location(self.light_source.coordinates, self.light_source.location, SIZE);
+                        let loc_err = ((sol.location.0 - actual.0 as f32).powi(2) + (sol.location.1 - actual.1 as f32).powi(2)).sqrt();
+                        ui.label(format!("Levenberg-Marquardt ({} iterations):", sol.iterations));
+                        ui.horizontal(|ui| {
+                            ui.label(format!("location: ({:.1}, {:.1})", sol.location.0, sol.location.1));
+                            ui.label(format!(" ~ error{}", loc_err / DIAG.sqrt()));
+                            if let Some(c) = &self.lm_confidence{
+                                ui.label(format!(" ~ 95%: ± ({:.1}, {:.1}) px", uncertainty::half_width(c.location.0), uncertainty::half_width(c.location.1)));
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label(format!("height: {}", sol.height / DIAG.sqrt()));
+                            ui.label(format!(" ~ error {}", (sol.height - self.light_source.height as f32).abs() / self.light_source.height as f32));
+                            if let Some(c) = &self.lm_confidence{
+                                let h = uncertainty::interval(sol.height, c.height);
+                                ui.label(format!(" ~ 95%: [{:.4}, {:.4}]", h.0 / DIAG.sqrt(), h.1 / DIAG.sqrt()));
+                            }
+                        });
+                        let albedo = sol.relative_albedo();
+                        let max_albedo = ALBEDO.iter().cloned().fold(f32::MIN, f32::max);
+                        for i in 0..3{
+                            ui.horizontal(|ui| {
+                                for j in 0..3{
+                                    let p = self.grid_patch(i, j);
+                                    ui.label(format!("[{}, {}]: {:.2} (~ error {:.3})", i, j, albedo[p], (albedo[p] - ALBEDO[3*i + j] / max_albedo).abs()));
+                                    if let Some(c) = &self.lm_confidence{
+                                        ui.label(format!("± {:.3}", uncertainty::half_width(c.albedo[p])));
+                                    }
+                                }
+                            });
+                        }
+                    }
+                }
+                if ui.add_enabled(self.job.is_none(), eframe::egui::Button::new("Find several lights")).clicked(){
+                    self.start_answers_job(ui.ctx(), AnswersJob::MultiLight);
+                }
+                if let Some(sol) = &self.multi_light{
+                    ui.label(format!("{} lights, offset {:.4}, cost {:.1}", sol.lights.len(), sol.offset, sol.cost));
+                    for l in sol.lights.iter(){
+                        ui.label(format!("light: ({:.1}, {:.1}), height {:.4}, intensity {:.3}", l.location.0, l.location.1, l.height / DIAG.sqrt(), l.intensity));
+                    }
+                }
+                if ui.add_enabled(self.job.is_none(), eframe::egui::Button::new("Sample posterior (MCMC)")).clicked(){
+                    self.start_answers_job(ui.ctx(), AnswersJob::Posterior);
+                }
+                if let Some(post) = &self.posterior{
+                    ui.label(format!("MCMC: {} samples, acceptance {:.2}, noise sigma {:.4}", post.samples.len(), post.acceptance, post.noise.sigma));
+                    for (i, name) in post.names.iter().enumerate(){
+                        let (lo, median, hi) = post.credible(i);
+                        let unit = if i == 2 {DIAG.sqrt() as f64} else {1.0};
+                        ui.label(format!("{}: {:.3}, 95% in [{:.3}, {:.3}]", name, median / unit, lo / unit, hi / unit));
+                    }
+                    //marginals of the location and the height
+                    ui.horizontal(|ui| {
+                        for i in 0..3{
+                            let (lo, hi, counts) = post.histogram(i);
+                            let width = ((hi - lo) / counts.len() as f64).max(1e-9);
+                            let bars = counts.iter().enumerate().map(|(b, c)| eframe::egui::plot::Bar::new(lo + (b as f64 + 0.5) * width, *c as f64).width(width)).collect();
+                            eframe::egui::plot::Plot::new(&post.names[i]).width(200.0).height(120.0).show(ui, |plot_ui| {
+                                plot_ui.bar_chart(eframe::egui::plot::BarChart::new(bars).name(&post.names[i]));
+                            });
+                        }
+                    });
+                }
             }
             });
             });
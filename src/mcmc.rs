@@ -0,0 +1,256 @@
+use probability::distribution::Sample;
+use probability::source::Source;
+use crate::label_map::LabelMap;
+use crate::linalg::cholesky;
+use crate::lm;
+use crate::noise_model::NoiseModel;
+use crate::progress::Progress;
+
+
+//adaptive metropolis (Haario et al.) over x, y, ln h and the ln albedo of every patch: the proposal is a
+//gaussian whose covariance is the chain's own so far, scaled by 2.38² / d, and by a global factor
+//tuned towards the optimal acceptance so that a bad first guess of the step doesn't stall the chain.
+//the likelihood is the noise model's gaussian per pixel, pixels taken sparse enough for the median
+//filter not to tie them
+static STRIDE: usize = 6;
+//the proposal covariance is only learnt after this many iterations and refreshed this often
+static ADAPT_START: usize = 1000;
+static ADAPT_EVERY: usize = 100;
+//keeps the learnt covariance from collapsing
+static EPSILON: f64 = 1e-10;
+//first proposal steps: pixels for x and y, log units for h and the albedo
+static INITIAL_STEP: (f64, f64, f64) = (0.5, 0.005, 0.005);
+static TARGET_ACCEPTANCE: f64 = 0.234;
+static THIN: usize = 10;
+static HISTOGRAM_BINS: usize = 20;
+//albedo scale is searched within these, it absorbs the luminosity and the exposure
+static MIN_ALBEDO: f64 = 1e-3;
+static MAX_ALBEDO: f64 = 1e3;
+
+
+//sampled quantities: x, y, h and the albedo of every patch relative to the largest
+pub fn names(patches: usize) -> Vec<String>{
+    let mut names = vec!("x".to_string(), "y".to_string(), "h".to_string());
+    names.extend((1..=patches).map(|p| format!("albedo{}", p)));
+    return names;
+}
+
+
+pub struct Bounds{
+    //x and y, usually the area the location solvers search
+    pub min: (f64, f64),
+    pub max: (f64, f64),
+    pub max_height: f64
+}
+
+pub struct ChainParams{
+    pub iterations: usize,
+    pub seed: u64
+}
+
+impl ChainParams{
+    pub fn init() -> Self{
+        return ChainParams{
+            iterations: 20000,
+            seed: 1337
+        };
+    }
+}
+
+pub struct Posterior{
+    //one row per kept sample, columns as in names
+    pub names: Vec<String>,
+    pub samples: Vec<Vec<f64>>,
+    pub acceptance: f64,
+    pub noise: NoiseModel
+}
+
+impl Posterior{
+    fn column(&self, i: usize) -> Vec<f64>{
+        let mut values: Vec<f64> = self.samples.iter().map(|s| s[i]).collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        return values;
+    }
+
+    //2.5%, 50% and 97.5% quantiles
+    pub fn credible(&self, i: usize) -> (f64, f64, f64){
+        let values = self.column(i);
+        let q = |p: f64| values[((values.len() - 1) as f64 * p).round() as usize];
+        return (q(0.025), q(0.5), q(0.975));
+    }
+
+    //lowest and highest value and the counts of equal bins between them
+    pub fn histogram(&self, i: usize) -> (f64, f64, Vec<usize>){
+        let values = self.column(i);
+        let (lo, hi) = (values[0], values[values.len() - 1]);
+        let mut counts = vec![0; HISTOGRAM_BINS];
+        for v in values{
+            let bin = if hi > lo {((v - lo) / (hi - lo) * HISTOGRAM_BINS as f64) as usize} else {0};
+            counts[bin.min(HISTOGRAM_BINS - 1)] += 1;
+        }
+        return (lo, hi, counts);
+    }
+
+    pub fn write_csv(&self, path: &str){
+        let mut lines = vec!(self.names.join(","));
+        for s in self.samples.iter(){
+            lines.push(s.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(","));
+        }
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+}
+
+struct Likelihood{
+    samples: Vec<lm::PixelSample>,
+    noise: NoiseModel
+}
+
+impl Likelihood{
+    //up to a constant; theta is x, y, ln h, ln albedo
+    fn log(&self, theta: &[f64]) -> f64{
+        let h = theta[2].exp();
+        let h2 = h * h;
+        let h3 = h2 * h;
+        let albedo: Vec<f64> = theta[3..].iter().map(|t| t.exp()).collect();
+        let mut ss = 0.0;
+        for px in self.samples.iter(){
+            let (dx, dy) = (px.pos.0 - theta[0], px.pos.1 - theta[1]);
+            let s = dx * dx + dy * dy + h2;
+            //clamped at 1 like the render
+            let model = (albedo[px.patch] * h3 / (s * s.sqrt()) + self.noise.mean).min(1.0);
+            ss += (model - px.value).powi(2);
+        }
+        return -0.5 * ss / (self.noise.sigma * self.noise.sigma);
+    }
+}
+
+fn in_bounds(theta: &[f64], bounds: &Bounds) -> bool{
+    return theta[0] >= bounds.min.0 && theta[0] <= bounds.max.0 && theta[1] >= bounds.min.1 && theta[1] <= bounds.max.1
+        && theta[2] >= 0.0 && theta[2] <= bounds.max_height.ln()
+        && theta[3..].iter().all(|t| *t >= MIN_ALBEDO.ln() && *t <= MAX_ALBEDO.ln());
+}
+
+//what gets reported of a chain state: x, y, h, albedo / the largest albedo
+fn quantities(theta: &[f64]) -> Vec<f64>{
+    let max = theta[3..].iter().cloned().fold(f64::MIN, f64::max);
+    let mut q = vec![theta[0], theta[1], theta[2].exp()];
+    q.extend(theta[3..].iter().map(|t| (t - max).exp()));
+    return q;
+}
+
+//starts from a fitted location, height and albedo, the first half of the iterations is thrown away as burn-in
+pub fn sample(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, start: &((f32, f32), f32, Vec<f32>), noise: NoiseModel, bounds: &Bounds, params: &ChainParams, progress: &Progress) -> Posterior{
+    let samples = lm::sample_pixels(scene_arr, labels, STRIDE);
+    let (location, height, albedo) = start;
+    let light = (location.0 as f64, location.1 as f64);
+    let h = (*height as f64).clamp(1.0, bounds.max_height);
+    let albedo: Vec<f32> = (0..labels.count()).map(|p| match albedo.get(p){
+        Some(a) if a.is_finite() && *a > 0.0 => *a,
+        _ => 1.0
+    }).collect();
+    let scale = lm::fit_albedo_scale(&samples, light, h, &albedo);
+    let mut theta = vec![light.0.clamp(bounds.min.0, bounds.max.0), light.1.clamp(bounds.min.1, bounds.max.1), h.ln()];
+    theta.extend(albedo.iter().map(|a| (*a as f64 * scale).clamp(MIN_ALBEDO, MAX_ALBEDO).ln()));
+    let d = theta.len();
+    let likelihood = Likelihood{samples, noise};
+    let mut log_p = likelihood.log(&theta);
+
+    let mut source = crate::dataset::seeded_source(params.seed);
+    let gaussian = probability::distribution::Gaussian::new(0.0, 1.0);
+    let mut chol = ndarray::Array2::<f64>::zeros((d, d));
+    for i in 0..d{
+        chol[[i, i]] = match i{
+            0 | 1 => INITIAL_STEP.0,
+            2 => INITIAL_STEP.1,
+            _ => INITIAL_STEP.2
+        };
+    }
+    //running mean and scatter of the chain for the proposal covariance
+    let mut mean = vec![0.0; d];
+    let mut scatter = ndarray::Array2::<f64>::zeros((d, d));
+    let mut log_lambda = 0.0f64;
+    let mut accepted = 0;
+    let mut kept = vec!();
+    progress.start("sampling the posterior", params.iterations);
+    for t in 1..=params.iterations{
+        //a cancelled chain is thrown away, what it kept so far doesn't matter
+        if progress.is_cancelled(){
+            break;
+        }
+        progress.step();
+        let z: Vec<f64> = (0..d).map(|_| gaussian.sample(&mut source)).collect();
+        let lambda = log_lambda.exp();
+        let proposal: Vec<f64> = (0..d).map(|i| theta[i] + lambda * (0..=i).map(|j| chol[[i, j]] * z[j]).sum::<f64>()).collect();
+        let mut alpha = 0.0;
+        if in_bounds(&proposal, bounds){
+            let log_q = likelihood.log(&proposal);
+            alpha = (log_q - log_p).min(0.0).exp();
+            if (log_q - log_p) >= source.read_f64().ln(){
+                theta = proposal;
+                log_p = log_q;
+                accepted += 1;
+            }
+        }
+        //robbins-monro, the steps shrink so the chain still settles
+        log_lambda += (alpha - TARGET_ACCEPTANCE) / (t as f64).powf(0.6);
+        //welford update of the chain's covariance
+        let delta: Vec<f64> = (0..d).map(|i| theta[i] - mean[i]).collect();
+        for i in 0..d{
+            mean[i] += delta[i] / t as f64;
+        }
+        for i in 0..d{
+            for j in 0..d{
+                scatter[[i, j]] += delta[i] * (theta[j] - mean[j]);
+            }
+        }
+        if t >= ADAPT_START && t % ADAPT_EVERY == 0{
+            let mut cov = &scatter * (2.38 * 2.38 / d as f64 / (t - 1) as f64);
+            for i in 0..d{
+                cov[[i, i]] += EPSILON;
+            }
+            if let Some(l) = cholesky(&cov){
+                chol = l;
+            }
+        }
+        if t > params.iterations / 2 && t % THIN == 0{
+            kept.push(quantities(&theta));
+        }
+    }
+    return Posterior{
+        names: names(labels.count()),
+        samples: kept,
+        acceptance: accepted as f64 / params.iterations.max(1) as f64,
+        noise
+    };
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.55, 0.9, 0.3, 0.7, 1.0, 0.45, 0.2, 0.8, 0.6];
+
+    #[test]
+    fn credible_intervals_are_around_the_truth(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (450.0, 380.0), 350.0, &ALBEDO, 0.01, 5);
+        //a start a little off, relative albedo only
+        let albedo: Vec<f32> = ALBEDO.iter().map(|a| *a as f32 * 0.5).collect();
+        let bounds = Bounds{min: (0.0, 0.0), max: (900.0, 900.0), max_height: 3000.0};
+        let params = ChainParams{iterations: 8000, seed: 21};
+        let post = sample(&arr, &labels, &((453.0, 377.0), 340.0, albedo), NoiseModel::init(0.0, 0.01), &bounds, &params, &Progress::init());
+        assert!(post.acceptance > 0.05 && post.acceptance < 0.6, "{}", post.acceptance);
+        let truth = [450.0, 380.0, 350.0, 0.55, 0.9, 0.3, 0.7, 1.0, 0.45, 0.2, 0.8, 0.6];
+        //twelve 95% intervals miss now and then, the truth stays within a width of the median
+        for (i, t) in truth.iter().enumerate(){
+            let (lo, median, hi) = post.credible(i);
+            //the largest albedo is 1 in every sample
+            assert!((median - t).abs() <= (hi - lo).max(1e-9), "{} {} far from {} .. {} .. {}", post.names[i], t, lo, median, hi);
+        }
+        //and they are narrow
+        let (lo, _, hi) = post.credible(0);
+        assert!(hi - lo < 3.0, "{} .. {}", lo, hi);
+    }
+}
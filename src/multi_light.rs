@@ -0,0 +1,435 @@
+use crate::label_map::LabelMap;
+use crate::lm::{self, NormalEquations, PixelSample};
+use crate::noise_model::NoiseModel;
+use crate::progress::Progress;
+use ndarray::parallel::prelude::*;
+
+
+//several lamps over one mondrian: v(p) = a(p) * Σ s_k g(light_k, h_k, p), the same falloff get_light
+//renders, plus b for what the noise mean of a noisy picture isn't known to be, with s_0 = 1 so the first lamp's brightness goes into the
+//albedo. lights are added one at a
+//time, each new one started where the fit so far leaves the most light unexplained, and all of them
+//are refitted together; the number of lights is the one of the lowest BIC.
+//the residuals are far from independent (quantization, the exposure stretch of prep_arr), and a further light always soaks up a little of that: a faint one, one far
+//away that lights the picture evenly, or a second one next to a light that bends its falloff. so
+//a light is only added if it also makes some part of the picture noticeably brighter and stands
+//apart from the others
+static MAX_ITERATIONS: usize = 200;
+//steps every seed height gets before the best one is refined to the end
+static SEED_ITERATIONS: usize = 15;
+//pixels the normal equations are built from per task
+static CHUNK: usize = 2048;
+//unexplained light is looked for in cells of this many pixels
+static CELL: usize = 30;
+//heights a new light is tried at, in frame widths
+static SEED_HEIGHTS: &[f64] = &[0.1, 0.3, 0.7];
+//of the brightest light falling anywhere on the picture
+static MIN_PEAK: f64 = 0.1;
+//distance between two lights over their mean height, closer they are one lamp
+static MIN_SEPARATION: f64 = 0.5;
+
+
+pub struct MultiLightParams{
+    pub max_lights: usize,
+    pub stride: usize
+}
+
+impl MultiLightParams{
+    pub fn init() -> Self{
+        return MultiLightParams{
+            max_lights: 4,
+            stride: 6
+        };
+    }
+}
+
+pub struct FittedLight{
+    pub location: (f32, f32),
+    pub height: f32,
+    //brightness relative to the brightest light
+    pub intensity: f32
+}
+
+pub struct MultiLightSolution{
+    //brightest first
+    pub lights: Vec<FittedLight>,
+    //albedo / the largest albedo
+    pub albedo: Vec<f32>,
+    //added to every pixel besides the noise model's mean
+    pub offset: f32,
+    //BIC of the best fit with 1, 2, ... lights, as far as they were tried
+    pub bic: Vec<f64>,
+    //half the sum of squared residuals in noise standard deviations
+    pub cost: f64,
+    pub converged: bool
+}
+
+//where everything sits in the parameter vector: x, y, h of every light, the intensities of all
+//lights but the first, the offset if there is one, then the albedo
+struct Layout{
+    lights: usize,
+    offset: bool,
+    patches: usize
+}
+
+impl Layout{
+    fn light(&self, k: usize) -> usize{
+        return 3 * k;
+    }
+
+    fn intensity(&self, k: usize) -> Option<usize>{
+        if k == 0{
+            return None;
+        }
+        return Some(3 * self.lights + k - 1);
+    }
+
+    fn offset(&self) -> Option<usize>{
+        if !self.offset{
+            return None;
+        }
+        return Some(4 * self.lights - 1);
+    }
+
+    fn albedo(&self, p: usize) -> usize{
+        return 4 * self.lights - 1 + self.offset as usize + p;
+    }
+
+    fn len(&self) -> usize{
+        return self.albedo(self.patches);
+    }
+}
+
+//x, y, h and intensity of every light, the offset and the albedo, the way the parameter vector is
+//built from
+struct Model{
+    lights: Vec<(f64, f64, f64, f64)>,
+    //what the noise model's mean leaves over, None on a clean picture: nothing dark can be taken
+    //for an offset there, and a patch darkened to 0 would no longer move the fit
+    offset: Option<f64>,
+    albedo: Vec<f64>
+}
+
+impl Model{
+    fn layout(&self) -> Layout{
+        return Layout{lights: self.lights.len(), offset: self.offset.is_some(), patches: self.albedo.len()};
+    }
+
+    fn params(&self) -> Vec<f64>{
+        let layout = self.layout();
+        let mut p = vec![0.0; layout.len()];
+        for (k, l) in self.lights.iter().enumerate(){
+            p[layout.light(k)] = l.0;
+            p[layout.light(k) + 1] = l.1;
+            p[layout.light(k) + 2] = l.2;
+            if let Some(i) = layout.intensity(k){
+                p[i] = l.3;
+            }
+        }
+        if let (Some(i), Some(b)) = (layout.offset(), self.offset){
+            p[i] = b;
+        }
+        p[layout.albedo(0)..].copy_from_slice(&self.albedo);
+        return p;
+    }
+
+    fn from_params(layout: &Layout, p: &[f64]) -> Self{
+        return Model{
+            lights: (0..layout.lights).map(|k| {
+                let l = layout.light(k);
+                (p[l], p[l + 1], p[l + 2], layout.intensity(k).map(|i| p[i]).unwrap_or(1.0))
+            }).collect(),
+            offset: layout.offset().map(|i| p[i]),
+            albedo: p[layout.albedo(0)..].to_vec()
+        };
+    }
+
+    //observed brightness of a pixel less the offset
+    fn signal(&self, noise: &NoiseModel, v: f64) -> f64{
+        return noise.signal(v) - self.offset.unwrap_or(0.0);
+    }
+
+    //light falling on a pixel before the albedo
+    fn irradiance(&self, pos: (f64, f64)) -> f64{
+        return self.lights.iter().map(|l| l.3 * lm::light_falloff((l.0, l.1), l.2, pos).0).sum();
+    }
+}
+
+fn normal_equations(samples: &[PixelSample], noise: &NoiseModel, layout: &Layout, params: &[f64], with_jacobian: bool) -> NormalEquations{
+    let sd = noise.sd();
+    let intensities: Vec<f64> = (0..layout.lights).map(|k| layout.intensity(k).map(|i| params[i]).unwrap_or(1.0)).collect();
+    let offset = layout.offset().map(|i| params[i]).unwrap_or(0.0);
+    //chunks of pixels in parallel, summed in order so the fit takes the same steps every run
+    let chunks: Vec<&[PixelSample]> = samples.chunks(CHUNK).collect();
+    let parts: Vec<NormalEquations> = chunks.par_iter().map(|chunk| {
+        let mut ne = NormalEquations::init(params.len());
+        let mut falloffs = vec![(0.0, 0.0, 0.0, 0.0); layout.lights];
+        let mut row = Vec::with_capacity(2 + 4 * layout.lights);
+        for px in chunk.iter(){
+            let ai = layout.albedo(px.patch);
+            let a = params[ai];
+            for (k, f) in falloffs.iter_mut().enumerate(){
+                let l = layout.light(k);
+                *f = lm::light_falloff((params[l], params[l + 1]), params[l + 2], px.pos);
+            }
+            let irradiance: f64 = falloffs.iter().zip(intensities.iter()).map(|(f, s)| s * f.0).sum();
+            let (expected, slope) = noise.expected(a * irradiance);
+            let residual = (expected + offset - px.value) / sd;
+            if !with_jacobian{
+                ne.cost += 0.5 * residual * residual;
+                continue;
+            }
+            let d = slope / sd;
+            row.clear();
+            row.push((ai, d * irradiance));
+            if let Some(i) = layout.offset(){
+                row.push((i, 1.0 / sd));
+            }
+            for (k, ((g, dg_dx, dg_dy, dg_dh), s)) in falloffs.iter().zip(intensities.iter()).enumerate(){
+                let l = layout.light(k);
+                row.push((l, d * a * s * dg_dx));
+                row.push((l + 1, d * a * s * dg_dy));
+                row.push((l + 2, d * a * s * dg_dh));
+                if let Some(i) = layout.intensity(k){
+                    row.push((i, d * a * g));
+                }
+            }
+            ne.add(residual, &row);
+        }
+        return ne;
+    }).collect();
+    let mut ne = NormalEquations::init(params.len());
+    for part in parts.iter(){
+        ne.merge(part);
+    }
+    return ne;
+}
+
+fn project(layout: &Layout, p: &mut [f64]){
+    for k in 0..layout.lights{
+        p[layout.light(k) + 2] = p[layout.light(k) + 2].max(1.0);
+        if let Some(i) = layout.intensity(k){
+            p[i] = p[i].max(0.0);
+        }
+    }
+    for a in p[layout.albedo(0)..].iter_mut(){
+        *a = a.max(0.0);
+    }
+}
+
+//refits everything together, the cost comes back with the model
+fn refine(samples: &[PixelSample], noise: &NoiseModel, model: &Model, max_iter: usize) -> (Model, f64, bool){
+    let layout = model.layout();
+    let res = lm::levenberg_marquardt(&model.params(),
+                                      |p, with_jacobian| normal_equations(samples, noise, &layout, p, with_jacobian),
+                                      |p| project(&layout, p),
+                                      max_iter);
+    return (Model::from_params(&layout, &res.params), res.cost, res.converged);
+}
+
+//per patch albedo in closed form under the model's lights
+fn fit_albedo(samples: &[PixelSample], noise: &NoiseModel, model: &mut Model){
+    let mut up = vec![0.0; model.albedo.len()];
+    let mut down = vec![0.0; model.albedo.len()];
+    for px in samples{
+        let e = model.irradiance(px.pos);
+        up[px.patch] += e * model.signal(noise, px.value);
+        down[px.patch] += e * e;
+    }
+    for (p, a) in model.albedo.iter_mut().enumerate(){
+        *a = if down[p] > 0.0 {(up[p] / down[p]).max(0.0)} else {0.0};
+    }
+}
+
+//center of the cell the model leaves the most light unexplained in, light before the albedo
+fn brightest_residual(samples: &[PixelSample], noise: &NoiseModel, model: &Model, shape: (usize, usize)) -> (f64, f64){
+    let cells = (shape.0.div_ceil(CELL), shape.1.div_ceil(CELL));
+    let mut sums = ndarray::Array2::<f64>::zeros(cells);
+    let mut counts = ndarray::Array2::<f64>::zeros(cells);
+    for px in samples{
+        let a = model.albedo[px.patch];
+        if a <= 0.0{
+            continue;
+        }
+        let cell = [px.pos.0 as usize / CELL, px.pos.1 as usize / CELL];
+        sums[cell] += (model.signal(noise, px.value) - a * model.irradiance(px.pos)) / a;
+        counts[cell] += 1.0;
+    }
+    let mut best = ((0, 0), f64::MIN);
+    for ((j, k), s) in sums.indexed_iter(){
+        if counts[[j, k]] > 0.0 && s / counts[[j, k]] > best.1{
+            best = ((j, k), s / counts[[j, k]]);
+        }
+    }
+    return (((best.0.0 as f64 + 0.5) * CELL as f64).min(shape.0 as f64), ((best.0.1 as f64 + 0.5) * CELL as f64).min(shape.1 as f64));
+}
+
+//intensity of a new light at location and height that best explains what the model leaves
+fn seed_intensity(samples: &[PixelSample], noise: &NoiseModel, model: &Model, light: (f64, f64), h: f64) -> f64{
+    let mut up = 0.0;
+    let mut down = 0.0;
+    for px in samples{
+        let a = model.albedo[px.patch];
+        let g = a * lm::light_falloff(light, h, px.pos).0;
+        up += g * (model.signal(noise, px.value) - a * model.irradiance(px.pos));
+        down += g * g;
+    }
+    return if down > 0.0 {(up / down).max(0.0)} else {0.0};
+}
+
+//the best of the model with one more light: every seed height gets a few steps, the best of them
+//the rest; None if cancelled before any
+fn add_light(samples: &[PixelSample], noise: &NoiseModel, model: &Model, shape: (usize, usize), progress: &Progress) -> Option<(Model, f64, bool)>{
+    let location = brightest_residual(samples, noise, model, shape);
+    let frame = shape.0.max(shape.1) as f64;
+    let mut best: Option<(Model, f64, bool)> = None;
+    for h in SEED_HEIGHTS.iter().map(|f| f * frame){
+        let mut seed = Model{lights: model.lights.clone(), offset: model.offset, albedo: model.albedo.clone()};
+        //the first light sets the albedo scale, the others are scaled to what is left unexplained
+        if model.lights.is_empty(){
+            seed.lights.push((location.0, location.1, h, 1.0));
+            fit_albedo(samples, noise, &mut seed);
+        }
+        else{
+            seed.lights.push((location.0, location.1, h, seed_intensity(samples, noise, model, location, h)));
+        }
+        if progress.is_cancelled(){
+            break;
+        }
+        let candidate = refine(samples, noise, &seed, SEED_ITERATIONS);
+        if best.as_ref().is_none_or(|b| candidate.1 < b.1){
+            best = Some(candidate);
+        }
+    }
+    let (seed, _, _) = best?;
+    return Some(refine(samples, noise, &seed, MAX_ITERATIONS));
+}
+
+//whether every light shows somewhere in the picture and no two of them are one lamp
+fn is_plausible(samples: &[PixelSample], model: &Model) -> bool{
+    let mut peaks = vec![0.0f64; model.lights.len()];
+    let mut brightest = 0.0f64;
+    for px in samples{
+        for (k, l) in model.lights.iter().enumerate(){
+            peaks[k] = peaks[k].max(l.3 * lm::light_falloff((l.0, l.1), l.2, px.pos).0);
+        }
+        brightest = brightest.max(model.irradiance(px.pos));
+    }
+    if peaks.iter().any(|p| *p < MIN_PEAK * brightest){
+        return false;
+    }
+    for (k, a) in model.lights.iter().enumerate(){
+        for b in model.lights[k + 1..].iter(){
+            if ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2) + (a.2 - b.2).powi(2)).sqrt() < MIN_SEPARATION * 0.5 * (a.2 + b.2){
+                return false;
+            }
+        }
+    }
+    return true;
+}
+
+//2 ln L with the noise model's sigma, and ln n per parameter
+fn bic(cost: f64, params: usize, samples: usize) -> f64{
+    return 2.0 * cost + params as f64 * (samples as f64).ln();
+}
+
+//start is the single light location and height the other solvers found; None if no pixel is left
+//to fit or the solve was cancelled before the first light
+pub fn solve(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, start: ((f32, f32), f32), params: &MultiLightParams, progress: &Progress) -> Option<MultiLightSolution>{
+    //clamped pixels don't add up
+    let saturated = scene_arr.iter().cloned().fold(0.0, f32::max) as f64 * 0.999;
+    let samples: Vec<PixelSample> = lm::sample_pixels(scene_arr, labels, params.stride).into_iter().filter(|px| px.value < saturated).collect();
+    if samples.is_empty(){
+        return None;
+    }
+    let shape = (scene_arr.shape()[0], scene_arr.shape()[1]);
+    let offset = if noise.is_clean() {None} else {Some(0.0)};
+    let empty = Model{lights: vec!(), offset, albedo: vec![1.0; labels.count()]};
+    //one light from the other solvers' answer, or from the brightest spot if that fits better
+    let (location, height) = start;
+    let h = if height >= 1.0 {height as f64} else {lm::fallback_height(scene_arr)};
+    let mut given = Model{lights: vec![(location.0 as f64, location.1 as f64, h, 1.0)], offset, albedo: vec![0.0; labels.count()]};
+    fit_albedo(&samples, noise, &mut given);
+    let mut best = refine(&samples, noise, &given, MAX_ITERATIONS);
+    let seeded = add_light(&samples, noise, &empty, shape, progress)?;
+    if seeded.1 < best.1{
+        best = seeded;
+    }
+    let mut bics = vec![bic(best.1, best.0.layout().len(), samples.len())];
+    progress.start("adding lights", params.max_lights);
+    progress.step();
+    while best.0.lights.len() < params.max_lights{
+        let more = match add_light(&samples, noise, &best.0, shape, progress){
+            Some(m) => m,
+            None => break
+        };
+        let b = bic(more.1, more.0.layout().len(), samples.len());
+        bics.push(b);
+        if b >= bics[bics.len() - 2] || !is_plausible(&samples, &more.0){
+            break;
+        }
+        best = more;
+        progress.step();
+    }
+    let (model, cost, converged) = best;
+    let brightest = model.lights.iter().map(|l| l.3).fold(0.0, f64::max);
+    let mut lights: Vec<FittedLight> = model.lights.iter().map(|l| FittedLight{
+        location: (l.0 as f32, l.1 as f32),
+        height: l.2 as f32,
+        intensity: if brightest > 0.0 {(l.3 / brightest) as f32} else {0.0}
+    }).collect();
+    lights.sort_by(|a, b| b.intensity.total_cmp(&a.intensity));
+    let max = model.albedo.iter().cloned().fold(0.0, f64::max);
+    return Some(MultiLightSolution{
+        lights,
+        albedo: model.albedo.iter().map(|a| if max > 0.0 {(a / max) as f32} else {0.0}).collect(),
+        offset: model.offset.unwrap_or(0.0) as f32,
+        bic: bics,
+        cost,
+        converged
+    });
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.6, 0.25, 0.9, 0.4, 1.0, 0.15, 0.75, 0.5, 0.3];
+
+    #[test]
+    fn bic_picks_two_lights_for_two_lamps(){
+        let labels = LabelMap::grid((480, 420), 3, 3);
+        //the second lamp is dimmer and lower, on the other side
+        let arr = render(&labels, (120.0, 110.0), 180.0, &ALBEDO, 0.003, 5) + render(&labels, (370.0, 300.0), 120.0, &ALBEDO.map(|a| 0.6 * a), 0.0, 1);
+        let sol = solve(&arr, &labels, &NoiseModel::init(0.0, 0.003), ((150.0, 140.0), 200.0), &MultiLightParams::init(), &Progress::init()).unwrap();
+        assert_eq!(sol.lights.len(), 2, "{:?}", sol.bic);
+        assert!(sol.bic[1] < sol.bic[0], "{:?}", sol.bic);
+        let truth = [((120.0, 110.0), 180.0, 1.0), ((370.0, 300.0), 120.0, 0.6)];
+        for (light, (location, height, intensity)) in sol.lights.iter().zip(truth.iter()){
+            assert!((light.location.0 - location.0).abs() < 2.0 && (light.location.1 - location.1).abs() < 2.0, "{:?}", light.location);
+            assert!((light.height - height).abs() < 2.0, "{}", light.height);
+            assert!((light.intensity - intensity).abs() < 0.01, "{}", light.intensity);
+        }
+        for (a, truth) in sol.albedo.iter().zip(ALBEDO.iter()){
+            assert!((*a as f64 - truth).abs() < 0.01, "{:?}", sol.albedo);
+        }
+    }
+
+    #[test]
+    fn bic_keeps_one_light_for_one_lamp(){
+        let labels = LabelMap::grid((450, 450), 3, 3);
+        let arr = render(&labels, (200.0, 260.0), 150.0, &ALBEDO, 0.003, 6);
+        let sol = solve(&arr, &labels, &NoiseModel::init(0.0, 0.003), ((205.0, 250.0), 160.0), &MultiLightParams::init(), &Progress::init()).unwrap();
+        assert_eq!(sol.lights.len(), 1, "{:?}", sol.bic);
+        assert!((sol.lights[0].location.0 - 200.0).abs() < 1.0 && (sol.lights[0].location.1 - 260.0).abs() < 1.0, "{:?}", sol.lights[0].location);
+    }
+
+    #[test]
+    fn a_picture_with_nothing_but_clamped_pixels_has_no_lights(){
+        let labels = LabelMap::grid((300, 240), 3, 3);
+        let arr = ndarray::Array2::<f32>::ones((300, 240));
+        assert!(solve(&arr, &labels, &NoiseModel::clean(), ((150.0, 120.0), 100.0), &MultiLightParams::init(), &Progress::init()).is_none());
+    }
+}
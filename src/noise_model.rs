@@ -0,0 +1,272 @@
+use probability::distribution::{Continuous, Distribution};
+use crate::label_map::LabelMap;
+use crate::lm;
+
+
+//what the solvers know of the noise of a picture: gaussian noise of this mean and sigma was added to
+//every linear pixel, which was then clamped to 0..1 and quantized to 8 bit sRGB. pixels are weighted
+//by the inverse of their variance and the mean is taken off before they are compared with the light
+//8 bit quantization, taken as the same everywhere: in linear units a step is 30 times finer for dark
+//pixels than for bright ones, but the exposure stretch of prep_arr bends the picture away from the
+//model by more than that, and trusting dark pixels by their quantization throws far lights off
+static QUANTIZATION_SD: f64 = 1.0 / 255.0 / 3.4641;
+//misfit sampling stride, sparse enough for a median filter not to tie the pixels
+static STRIDE: usize = 6;
+//noise left in the box means the offset is measured with, and the largest box radius for it
+static OFFSET_NOISE: f64 = 0.002;
+static MAX_OFFSET_RADIUS: usize = 6;
+//a boundary needs this many pixel pairs, and sides this different, to say anything of the offset
+static MIN_BOUNDARY_PAIRS: usize = 50;
+static MIN_RATIO_STEP: f64 = 0.05;
+//an offset known worse than this is no better than taking it as 0
+static MAX_OFFSET_SD: f64 = 0.005;
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct NoiseModel{
+    pub mean: f64,
+    pub sigma: f64
+}
+
+impl NoiseModel{
+    pub fn init(mean: f64, sigma: f64) -> Self{
+        return NoiseModel{mean, sigma};
+    }
+
+    //nothing but the quantization
+    pub fn clean() -> Self{
+        return NoiseModel::init(0.0, 0.0);
+    }
+
+    //for pictures of unknown noise, from the pixel to pixel scatter: a detrended step
+    //1.5 x₊₁ - 1.5 x + x₋₁/2 - x₊₂/2 has 5 times the variance of a pixel. the mean isn't known
+    pub fn from_image(scene_arr: &ndarray::Array2::<f32>) -> Self{
+        return NoiseModel::init(0.0, crate::segmentation::noise_deviation(scene_arr) as f64 / 5f64.sqrt());
+    }
+
+    //for pictures of unknown noise, the offset as well: on both sides of a boundary between two patches
+    //the light is the same, so there v_a - mean = r (v_b - mean) with r the ratio of their albedo, and
+    //the line through the pairs (v_b, v_a) along the boundary crosses v_a = v_b at the mean. box means
+    //a little off the boundary keep the noise out of the line, and the pixels the clamping at 0 or 1
+    //bent are left out
+    pub fn estimate(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap) -> Self{
+        let sigma = NoiseModel::from_image(scene_arr).sigma;
+        let radius = NoiseModel::init(0.0, sigma).averaging_radius(OFFSET_NOISE, MAX_OFFSET_RADIUS);
+        let smoothed = if radius > 0 {crate::segmentation::local_mean(scene_arr, radius)} else {scene_arr.clone()};
+        let saturated = scene_arr.iter().cloned().fold(0.0, f32::max) as f64 * 0.999;
+        let dark = 3.0 * NoiseModel::init(0.0, sigma).sd();
+        let (w, h) = labels.shape();
+        let r = radius as i32;
+        //the box around (j, k) lies in patch label
+        let inside = |j: i32, k: i32, label: usize| -> bool {
+            if j < r || k < r || j + r >= w as i32 || k + r >= h as i32{
+                return false;
+            }
+            return [(j - r, k - r), (j + r, k - r), (j - r, k + r), (j + r, k + r), (j, k)].iter().all(|c| labels.label(c.0 as usize, c.1 as usize) == label);
+        };
+        let offset = r + 2;
+        let mut boundaries: std::collections::BTreeMap<(usize, usize), Vec<(f64, f64)>> = std::collections::BTreeMap::new();
+        for ((j, k), step) in labels.boundary_pairs(){
+            let (a, b) = (labels.label(j, k), labels.label((j as i32 + step.0) as usize, (k as i32 + step.1) as usize));
+            let p = (j as i32 - (offset - 1) * step.0, k as i32 - (offset - 1) * step.1);
+            let q = (j as i32 + offset * step.0, k as i32 + offset * step.1);
+            if !inside(p.0, p.1, a) || !inside(q.0, q.1, b){
+                continue;
+            }
+            let (va, vb) = (smoothed[[p.0 as usize, p.1 as usize]] as f64, smoothed[[q.0 as usize, q.1 as usize]] as f64);
+            if va < dark || vb < dark || va >= saturated || vb >= saturated{
+                continue;
+            }
+            let (key, pair) = if a < b {((a, b), (vb, va))} else {((b, a), (va, vb))};
+            boundaries.entry(key).or_insert(vec!()).push(pair);
+        }
+        //every boundary's answer with its weight, the inverse of its variance: the intercept is known
+        //the better the more the light changes along the boundary, and the mean the further r is from 1
+        let mut answers: Vec<(f64, f64)> = vec!();
+        for pairs in boundaries.values(){
+            if pairs.len() < MIN_BOUNDARY_PAIRS{
+                continue;
+            }
+            let n = pairs.len() as f64;
+            let mx = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+            let my = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+            let sxx = pairs.iter().map(|p| (p.0 - mx).powi(2)).sum::<f64>() / n;
+            let syy = pairs.iter().map(|p| (p.1 - my).powi(2)).sum::<f64>() / n;
+            let sxy = pairs.iter().map(|p| (p.0 - mx) * (p.1 - my)).sum::<f64>() / n;
+            if sxy <= 0.0{
+                continue;
+            }
+            //both sides are as noisy, so the line is the orthogonal one
+            let ratio = (syy - sxx + ((syy - sxx).powi(2) + 4.0 * sxy * sxy).sqrt()) / (2.0 * sxy);
+            if (1.0 - ratio).abs() < MIN_RATIO_STEP{
+                continue;
+            }
+            let intercept = my - ratio * mx;
+            let residual = pairs.iter().map(|p| (p.1 - ratio * p.0 - intercept).powi(2)).sum::<f64>() / (n - 2.0);
+            let variance = residual * (1.0 / n + mx * mx / (n * sxx)) / (1.0 - ratio).powi(2);
+            answers.push((intercept / (1.0 - ratio), 1.0 / variance.max(1e-300)));
+        }
+        //a weighted median, a boundary the segmentation got wrong shouldn't drag it
+        answers.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total: f64 = answers.iter().map(|a| a.1).sum();
+        if total < 1.0 / (MAX_OFFSET_SD * MAX_OFFSET_SD){
+            return NoiseModel::init(0.0, sigma);
+        }
+        let mut acc = 0.0;
+        let mut mean = 0.0;
+        for (m, weight) in answers{
+            acc += weight;
+            if acc >= total / 2.0{
+                mean = m;
+                break;
+            }
+        }
+        //the rounding to 8 bit bends every line by as much as a level, so an offset within twice that
+        //of 0 is no evidence of one; clean pictures come out at a fraction of a level
+        if mean.abs() < 2.0 * (1.0 / total + QUANTIZATION_SD * QUANTIZATION_SD).sqrt(){
+            return NoiseModel::init(0.0, sigma);
+        }
+        //the light adds nothing negative, so hardly a pixel in a hundred is darker than mean - 2.33 sigma;
+        //a boundary or two with sides almost alike can't put it above that
+        let mut values: Vec<f32> = scene_arr.iter().cloned().collect();
+        let n = values.len() / 100;
+        let (_, floor, _) = values.select_nth_unstable_by(n, |x, y| x.total_cmp(y));
+        return NoiseModel::init(mean.min(*floor as f64 + 2.33 * sigma), sigma);
+    }
+
+    //for pictures of unknown noise: the rms misfit of a fitted light, all of it taken as noise
+    pub fn from_misfit(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, location: (f32, f32), height: f32, albedo: &[f32]) -> Self{
+        let samples = lm::sample_pixels(scene_arr, labels, STRIDE);
+        let light = (location.0 as f64, location.1 as f64);
+        let h = (height as f64).max(1.0);
+        let scale = lm::fit_albedo_scale(&samples, light, h, albedo);
+        let ss: f64 = samples.iter().map(|px| (albedo[px.patch] as f64 * scale * lm::light_falloff(light, h, px.pos).0 - px.value).powi(2)).sum();
+        let sigma = (ss / samples.len().max(1) as f64).sqrt();
+        return NoiseModel::init(0.0, sigma.max(QUANTIZATION_SD));
+    }
+
+    pub fn is_clean(&self) -> bool{
+        return self.sigma <= 0.0;
+    }
+
+    //variance of a pixel, the noise and the quantization
+    pub fn variance(&self) -> f64{
+        return self.sigma * self.sigma + QUANTIZATION_SD * QUANTIZATION_SD;
+    }
+
+    pub fn sd(&self) -> f64{
+        return self.variance().sqrt();
+    }
+
+    //noiseless brightness of a pixel observed as v, on average
+    pub fn signal(&self, v: f64) -> f64{
+        return v - self.mean;
+    }
+
+    //mean observation of a pixel whose noiseless brightness is model, and its derivative over model:
+    //the noise is clamped at 0 with the pixel, so dark pixels come out brighter than model + mean
+    pub fn expected(&self, model: f64) -> (f64, f64){
+        let m = model + self.mean;
+        if self.is_clean(){
+            return if m > 0.0 {(m, 1.0)} else {(0.0, 0.0)};
+        }
+        let gaussian = probability::distribution::Gaussian::new(0.0, 1.0);
+        let z = m / self.sigma;
+        let cdf = gaussian.distribution(z);
+        return (m * cdf + self.sigma * gaussian.density(z), cdf);
+    }
+
+    //side of the smallest median window that leaves at most sigma of noise, up to max (odd): the
+    //median of n values scatters about sqrt(π/2) times as much as their mean
+    pub fn median_size(&self, sigma: f64, max: usize) -> usize{
+        let n = std::f64::consts::FRAC_PI_2 * (self.sigma / sigma).powi(2);
+        let side = n.sqrt().ceil().max(1.0) as usize;
+        return (side | 1).min(max);
+    }
+
+    //smallest box radius whose mean has at most sigma of noise left, up to max
+    pub fn averaging_radius(&self, sigma: f64, max: usize) -> usize{
+        let side = (self.sigma / sigma).ceil().max(1.0) as usize;
+        return (side / 2).min(max);
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.55, 0.9, 0.3, 0.7, 1.0, 0.45, 0.2, 0.8, 0.6];
+
+    #[test]
+    fn the_misfit_of_the_true_light_is_the_rendered_noise(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (450.0, 380.0), 350.0, &ALBEDO, 0.01, 4);
+        let albedo: Vec<f32> = ALBEDO.iter().map(|a| *a as f32).collect();
+        let noise = NoiseModel::from_misfit(&arr, &labels, (450.0, 380.0), 350.0, &albedo);
+        assert!((noise.sigma - 0.01).abs() < 5e-4, "{}", noise.sigma);
+    }
+
+    #[test]
+    fn the_pixel_scatter_is_the_rendered_noise(){
+        //a light far outside, shading that barely changes from pixel to pixel
+        let labels = LabelMap::grid((600, 900), 3, 3);
+        let arr = render(&labels, (-700.0, 450.0), 600.0, &ALBEDO, 0.02, 5);
+        let noise = NoiseModel::from_image(&arr);
+        assert!((noise.sigma - 0.02).abs() < 0.002, "{}", noise.sigma);
+        assert_eq!(noise.mean, 0.0);
+    }
+
+    #[test]
+    fn expected_brightness_follows_the_clamp_at_0(){
+        let clean = NoiseModel::clean();
+        assert!(clean.is_clean());
+        assert_eq!(clean.expected(0.3), (0.3, 1.0));
+        assert_eq!(clean.expected(-0.1), (0.0, 0.0));
+        let noise = NoiseModel::init(0.02, 0.05);
+        //well above 0 the clamp does nothing
+        let (m, d) = noise.expected(0.5);
+        assert!((m - 0.52).abs() < 1e-9 && (d - 1.0).abs() < 1e-9, "{} {}", m, d);
+        //at 0 half the noise is cut off: σ φ(0) on top
+        let (m, d) = noise.expected(-0.02);
+        assert!((m - 0.05 / (2.0 * std::f64::consts::PI).sqrt()).abs() < 1e-9 && (d - 0.5).abs() < 1e-9, "{} {}", m, d);
+    }
+
+    #[test]
+    fn averaging_radius_leaves_the_asked_noise(){
+        let noise = NoiseModel::init(0.0, 0.02);
+        assert_eq!(noise.averaging_radius(0.002, 100), 5);
+        assert_eq!(noise.averaging_radius(0.002, 3), 3);
+        assert_eq!(NoiseModel::clean().averaging_radius(0.002, 100), 0);
+    }
+
+    #[test]
+    fn estimate_finds_the_noise_mean(){
+        let labels = LabelMap::grid((900, 750), 3, 3);
+        let arr = render(&labels, (520.0, 300.0), 450.0, &ALBEDO, 0.01, 6).mapv(|v| v + 0.03);
+        let noise = NoiseModel::estimate(&arr, &labels);
+        assert!((noise.mean - 0.03).abs() < 0.005, "{}", noise.mean);
+    }
+
+    #[test]
+    fn estimate_finds_no_mean_in_a_clean_8_bit_picture(){
+        //over the picture and a frame width outside it, stretched to full range and rounded to sRGB levels
+        for (shape, light, h) in [((900, 900), (450.0, 380.0), 350.0), ((900, 700), (-900.0, 350.0), 300.0)]{
+            let labels = LabelMap::grid(shape, 3, 3);
+            let arr = render(&labels, light, h, &ALBEDO, 0.0, 1);
+            let max = arr.iter().cloned().fold(0.0, f32::max);
+            let arr = arr.mapv(|v| crate::srgb_to_clinear((crate::clinear_to_srgb(v / max) * 255.0).round() as usize));
+            let noise = NoiseModel::estimate(&arr, &labels);
+            assert_eq!(noise.mean, 0.0, "{:?}", light);
+        }
+    }
+
+    #[test]
+    fn median_size_leaves_the_asked_noise(){
+        //25 values, and the window is odd
+        assert_eq!(NoiseModel::init(0.0, 0.02).median_size(0.005, 15), 7);
+        assert_eq!(NoiseModel::init(0.0, 0.02).median_size(0.005, 5), 5);
+        assert_eq!(NoiseModel::clean().median_size(0.005, 15), 1);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::{srgb_to_clinear, LightSource, Noise, Scene, MEAN, SEED, SIGMA};
+
+
+//the part of a NOMAD parameter file the built-in optimizer understands
+pub struct NomadParams{
+    pub dimension: usize,
+    pub integer: Vec<bool>,
+    pub x0: Vec<f64>,
+    pub lower_bound: Vec<f64>,
+    pub upper_bound: Vec<f64>,
+    pub max_bb_eval: usize,
+    pub history_file: String
+}
+
+//values of a "KEYWORD ( v1 v2 ... )" line
+fn parse_list<'a>(values: &[&'a str]) -> Vec<&'a str>{
+    return values.iter().filter(|v| **v != "(" && **v != ")").map(|v| v.trim_matches(|c| c == '(' || c == ')')).collect();
+}
+
+impl NomadParams{
+    pub fn read(path: &str) -> Self{
+        let contents = std::fs::read_to_string(path).unwrap();
+        let mut params = NomadParams{
+            dimension: 0,
+            integer: vec!(),
+            x0: vec!(),
+            lower_bound: vec!(),
+            upper_bound: vec!(),
+            max_bb_eval: 1000,
+            history_file: "history.txt".to_string()
+        };
+        for line in contents.lines(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty(){
+                continue;
+            }
+            let splitted: Vec<&str> = line.split_whitespace().collect();
+            let values = parse_list(&splitted[1..]);
+            match splitted[0]{
+                "DIMENSION" => params.dimension = values[0].parse::<usize>().unwrap(),
+                "BB_INPUT_TYPE" => params.integer = values.iter().map(|v| *v == "I").collect(),
+                "X0" => params.x0 = values.iter().map(|v| v.parse::<f64>().unwrap()).collect(),
+                "LOWER_BOUND" => params.lower_bound = values.iter().map(|v| v.parse::<f64>().unwrap()).collect(),
+                "UPPER_BOUND" => params.upper_bound = values.iter().map(|v| v.parse::<f64>().unwrap()).collect(),
+                "MAX_BB_EVAL" => params.max_bb_eval = values[0].parse::<usize>().unwrap(),
+                "HISTORY_FILE" => params.history_file = values[0].to_string(),
+                //BB_EXE, BB_OUTPUT_TYPE, TMP_DIR etc. only matter to NOMAD itself
+                _ => {}
+            }
+        }
+        if params.integer.is_empty(){
+            params.integer = vec![false; params.dimension];
+        }
+        assert!(params.x0.len() == params.dimension && params.lower_bound.len() == params.dimension
+                && params.upper_bound.len() == params.dimension && params.integer.len() == params.dimension,
+                "X0, bounds and BB_INPUT_TYPE must all have DIMENSION values");
+        return params;
+    }
+}
+
+
+//renders a candidate (x, y, h, albedo1 .. albedo9) and compares it to the target picture,
+//this is the functional NOMAD minimizes through reverse_solve_nomad
+pub struct BlackboxObjective{
+    scene: Scene,
+    noise: Noise,
+    target: Vec<f32>,
+    //of the target, candidates are rendered the same size
+    shape: (usize, usize),
+    to_linear: Vec<f32>
+}
+
+impl BlackboxObjective{
+    pub fn init(target_path: &str) -> Self{
+        let to_linear: Vec<f32> = (0..256).map(srgb_to_clinear).collect();
+        let img_original = image::open(target_path).unwrap().grayscale();
+        let img_orig = img_original.as_luma8().unwrap();
+        let shape = (img_orig.width() as usize, img_orig.height() as usize);
+        let mut target = vec!();
+        for i in 0..shape.0{
+            for j in 0..shape.1{
+                target.push(to_linear[img_orig.get_pixel(i as u32, j as u32).0[0] as usize]);
+            }
+        }
+        return BlackboxObjective{
+            scene: Scene::with_shape(shape),
+            noise: Noise::from_params(MEAN, SIGMA, SEED, shape),
+            target,
+            shape,
+            to_linear
+        };
+    }
+
+    pub fn evaluate(&mut self, x_: i32, y_: i32, h_: u32, albedo: &[f32; 9]) -> f64{
+        let mut ls = LightSource::with_shape(albedo, self.shape);
+        ls.coordinates = (x_, y_);
+        ls.height = h_;
+        ls.is_on = true;
+        ls.generate_light_matrix();
+        self.scene.render(&ls, &self.noise);
+        let img_gen = &self.scene.scene_image;
+        let mut diff = 0.0;
+        for i in 0..self.shape.0{
+            for j in 0..self.shape.1{
+                let gen = self.to_linear[img_gen.get_pixel(i as u32, j as u32).0[0] as usize];
+                diff += ((gen - self.target[i * self.shape.1 + j]) * (gen - self.target[i * self.shape.1 + j])) as f64;
+            }
+        }
+        return diff;
+    }
+
+    pub fn evaluate_vec(&mut self, x: &[f64]) -> f64{
+        let mut albedo = [0.0; 9];
+        for (a, v) in albedo.iter_mut().zip(x[3..12].iter()){
+            *a = *v as f32;
+        }
+        return self.evaluate(x[0].round() as i32, x[1].round() as i32, x[2].round().max(0.0) as u32, &albedo);
+    }
+}
+
+
+//bounded Nelder-Mead over the unit cube; integer variables are rounded before evaluation and
+//evaluations are cached, since many simplex points round to the same blackbox input
+pub struct NelderMead<'a>{
+    params: &'a NomadParams,
+    cache: std::collections::HashMap<Vec<u64>, f64>,
+    pub history: Vec<(Vec<f64>, f64)>,
+    pub best: (Vec<f64>, f64)
+}
+
+impl<'a> NelderMead<'a>{
+    pub fn init(params: &'a NomadParams) -> Self{
+        return NelderMead{
+            params,
+            cache: std::collections::HashMap::new(),
+            history: vec!(),
+            best: (params.x0.clone(), f64::INFINITY)
+        };
+    }
+
+    fn to_point(&self, u: &[f64]) -> Vec<f64>{
+        let p = self.params;
+        return (0..p.dimension).map(|i| {
+            let v = p.lower_bound[i] + u[i].clamp(0.0, 1.0) * (p.upper_bound[i] - p.lower_bound[i]);
+            if p.integer[i] {v.round()} else {v}
+        }).collect();
+    }
+
+    fn to_unit(&self, x: &[f64]) -> Vec<f64>{
+        let p = self.params;
+        return (0..p.dimension).map(|i| {
+            let range = p.upper_bound[i] - p.lower_bound[i];
+            if range > 0.0 {((x[i] - p.lower_bound[i]) / range).clamp(0.0, 1.0)} else {0.0}
+        }).collect();
+    }
+
+    fn budget_left(&self) -> bool{
+        return self.history.len() < self.params.max_bb_eval;
+    }
+
+    fn eval<F: FnMut(&[f64]) -> f64>(&mut self, u: &[f64], objective: &mut F) -> f64{
+        let x = self.to_point(u);
+        let key: Vec<u64> = x.iter().map(|v| v.to_bits()).collect();
+        if let Some(v) = self.cache.get(&key){
+            return *v;
+        }
+        if !self.budget_left(){
+            return f64::INFINITY;
+        }
+        let value = objective(&x);
+        self.cache.insert(key, value);
+        if value < self.best.1{
+            self.best = (x.clone(), value);
+        }
+        self.history.push((x, value));
+        return value;
+    }
+
+    //one Nelder-Mead run from the given point, until the simplex collapses or the budget is over
+    fn run<F: FnMut(&[f64]) -> f64>(&mut self, start: &[f64], step: f64, objective: &mut F){
+        let n = self.params.dimension;
+        let mut simplex: Vec<(Vec<f64>, f64)> = vec!();
+        let f0 = self.eval(start, objective);
+        simplex.push((start.to_vec(), f0));
+        for i in 0..n{
+            let mut u = start.to_vec();
+            u[i] = if u[i] + step <= 1.0 {u[i] + step} else {u[i] - step};
+            let f = self.eval(&u, objective);
+            simplex.push((u, f));
+        }
+        while self.budget_left(){
+            simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let diameter = simplex[1..].iter().map(|s| s.0.iter().zip(simplex[0].0.iter()).map(|(a, b)| (a - b).abs()).fold(0.0, f64::max)).fold(0.0, f64::max);
+            if diameter < 1e-4{
+                break;
+            }
+            let mut centroid = vec![0.0; n];
+            for s in simplex[..n].iter(){
+                for (c, v) in centroid.iter_mut().zip(s.0.iter()){
+                    *c += v / n as f64;
+                }
+            }
+            let worst = simplex[n].clone();
+            let point_along = |t: f64| -> Vec<f64> {
+                (0..n).map(|i| (centroid[i] + t * (worst.0[i] - centroid[i])).clamp(0.0, 1.0)).collect()
+            };
+            let reflected = point_along(-1.0);
+            let f_r = self.eval(&reflected, objective);
+            if f_r < simplex[0].1{
+                let expanded = point_along(-2.0);
+                let f_e = self.eval(&expanded, objective);
+                simplex[n] = if f_e < f_r {(expanded, f_e)} else {(reflected, f_r)};
+                continue;
+            }
+            if f_r < simplex[n - 1].1{
+                simplex[n] = (reflected, f_r);
+                continue;
+            }
+            let contracted = if f_r < worst.1 {point_along(-0.5)} else {point_along(0.5)};
+            let f_c = self.eval(&contracted, objective);
+            if f_c < worst.1.min(f_r){
+                simplex[n] = (contracted, f_c);
+                continue;
+            }
+            //shrink towards the best point
+            let best = simplex[0].0.clone();
+            for s in simplex[1..].iter_mut(){
+                let u: Vec<f64> = (0..n).map(|i| best[i] + 0.5 * (s.0[i] - best[i])).collect();
+                let f = self.eval(&u, objective);
+                *s = (u, f);
+            }
+        }
+    }
+
+    //restarts from the best point with smaller simplices while evaluations are left
+    pub fn minimize<F: FnMut(&[f64]) -> f64>(&mut self, objective: &mut F){
+        let mut step = 0.25;
+        let mut start = self.to_unit(&self.params.x0);
+        let mut last_best = f64::INFINITY;
+        while self.budget_left(){
+            let evals_before = self.history.len();
+            self.run(&start, step, objective);
+            start = self.to_unit(&self.best.0);
+            if self.best.1 < last_best{
+                last_best = self.best.1;
+            }
+            else{
+                step *= 0.5;
+            }
+            //everything around the best point is already cached
+            if self.history.len() == evals_before || step < 1e-4{
+                break;
+            }
+        }
+    }
+
+    //one line per blackbox evaluation: inputs then the objective, like NOMAD's history file
+    pub fn write_history(&self, path: &str){
+        let mut lines = vec!();
+        for (x, f) in self.history.iter(){
+            let x_str = x.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ");
+            lines.push(format!("{} {}", x_str, f));
+        }
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+}
+
+
+pub fn optimize(params_path: &str, target_path: &str){
+    let params = NomadParams::read(params_path);
+    assert!(params.dimension == 12, "the blackbox takes x, y, h and 9 albedos");
+    let mut objective = BlackboxObjective::init(target_path);
+    let mut nm = NelderMead::init(&params);
+    nm.minimize(&mut |x: &[f64]| objective.evaluate_vec(x));
+    nm.write_history(&params.history_file);
+    let best = nm.best.0.iter().map(|v| v.to_string()).collect::<Vec<String>>().join(" ");
+    println!("evaluations: {}", nm.history.len());
+    println!("best objective: {}", nm.best.1);
+    println!("{}", best);
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn nelder_mead_finds_the_bounded_minimum_of_a_quadratic(){
+        //the second variable is integer and the third one's minimum lies below its bound
+        let params = NomadParams{
+            dimension: 3,
+            integer: vec!(false, true, false),
+            x0: vec!(8.0, 1.0, 4.0),
+            lower_bound: vec!(-10.0, 0.0, 0.0),
+            upper_bound: vec!(10.0, 10.0, 5.0),
+            max_bb_eval: 2000,
+            history_file: String::new()
+        };
+        let mut nm = NelderMead::init(&params);
+        nm.minimize(&mut |x: &[f64]| (x[0] - 2.3).powi(2) + (x[1] - 6.6).powi(2) + (x[2] + 3.0).powi(2));
+        let best = &nm.best.0;
+        assert!((best[0] - 2.3).abs() < 1e-3, "{:?}", best);
+        assert_eq!(best[1], 7.0);
+        assert!(best[2].abs() < 1e-3, "{:?}", best);
+        assert!(nm.history.len() <= params.max_bb_eval);
+        assert!(nm.history.iter().all(|(x, _)| x[1].fract() == 0.0));
+    }
+
+    #[test]
+    fn nomad_params_are_read_from_a_parameter_file(){
+        let path = std::env::temp_dir().join("techvision_nomad_params.txt");
+        std::fs::write(&path, "DIMENSION 3\nBB_EXE bb.exe # not ours\nBB_INPUT_TYPE ( I I R )\nX0 ( 1 2 0.5 )\nLOWER_BOUND ( 0 0 0 )\nUPPER_BOUND ( 10 10 1 )\nMAX_BB_EVAL 50\n").unwrap();
+        let params = NomadParams::read(path.to_str().unwrap());
+        assert_eq!(params.dimension, 3);
+        assert_eq!(params.integer, vec!(true, true, false));
+        assert_eq!(params.x0, vec!(1.0, 2.0, 0.5));
+        assert_eq!(params.upper_bound, vec!(10.0, 10.0, 1.0));
+        assert_eq!(params.max_bb_eval, 50);
+        assert_eq!(params.history_file, "history.txt");
+    }
+}
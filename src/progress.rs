@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+
+//how far a solve got and whether it should give up; the gui reads it from another thread while
+//the solve runs, the command line makes one nobody reads
+
+
+pub struct Progress{
+    stage: std::sync::Mutex<String>,
+    done: AtomicUsize,
+    //steps of the stage, 0 when they aren't counted
+    total: AtomicUsize,
+    cancelled: AtomicBool
+}
+
+impl Progress{
+    pub fn init() -> Self{
+        return Progress{
+            stage: std::sync::Mutex::new(String::new()),
+            done: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false)
+        };
+    }
+
+    pub fn start(&self, stage: &str, total: usize){
+        *self.stage.lock().unwrap() = stage.to_string();
+        self.done.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+    }
+
+    pub fn step(&self){
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self){
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool{
+        return self.cancelled.load(Ordering::Relaxed);
+    }
+
+    pub fn describe(&self) -> String{
+        let stage = self.stage.lock().unwrap().clone();
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0{
+            return stage;
+        }
+        return format!("{} {}/{}", stage, self.done.load(Ordering::Relaxed).min(total), total);
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn describe_counts_the_steps_of_the_stage(){
+        let progress = Progress::init();
+        progress.start("fitting isophote clusters", 3);
+        progress.step();
+        assert_eq!(progress.describe(), "fitting isophote clusters 1/3");
+        for _ in 0..5{
+            progress.step();
+        }
+        assert_eq!(progress.describe(), "fitting isophote clusters 3/3");
+        //a new stage starts over
+        progress.start("joint fit", 0);
+        assert_eq!(progress.describe(), "joint fit");
+        assert!(!progress.is_cancelled());
+        progress.cancel();
+        assert!(progress.is_cancelled());
+    }
+}
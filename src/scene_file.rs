@@ -0,0 +1,82 @@
+use crate::noise_model::NoiseModel;
+
+
+//what is known about the photographed scene besides the picture itself
+
+
+//pixel rectangle of known reflectance, corners inclusive, in image x and y
+#[derive(Clone)]
+pub struct Reference{
+    pub min: (usize, usize),
+    pub max: (usize, usize),
+    pub albedo: f32
+}
+
+impl Reference{
+    pub fn contains(&self, j: usize, k: usize) -> bool{
+        return j >= self.min.0 && j <= self.max.0 && k >= self.min.1 && k <= self.max.1;
+    }
+}
+
+#[derive(Clone)]
+pub struct SceneFile{
+    pub reference: Option<Reference>,
+    //noise the camera added, in linear brightness
+    pub noise: Option<NoiseModel>,
+    //rows and columns of equal patches, when the picture is known to be such a grid
+    pub grid: Option<(usize, usize)>
+}
+
+impl SceneFile{
+    pub fn init() -> Self{
+        return SceneFile{reference: None, noise: None, grid: None};
+    }
+
+    //same format as dataset.params: "KEYWORD values", '#' starts a comment
+    pub fn read(path: &str) -> Self{
+        let mut scene = SceneFile::init();
+        let mut region: Option<((usize, usize), (usize, usize))> = None;
+        let mut albedo: Option<f32> = None;
+        let mut noise_mean: Option<f64> = None;
+        let mut noise_sigma: Option<f64> = None;
+        let contents = std::fs::read_to_string(path).unwrap();
+        for line in contents.lines(){
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty(){
+                continue;
+            }
+            let splitted: Vec<&str> = line.split_whitespace().collect();
+            match splitted[0].to_uppercase().as_str(){
+                "REFERENCE_REGION" => {
+                    let v: Vec<usize> = splitted[1..5].iter().map(|s| s.parse::<usize>().unwrap()).collect();
+                    region = Some(((v[0].min(v[2]), v[1].min(v[3])), (v[0].max(v[2]), v[1].max(v[3]))));
+                }
+                "REFERENCE_ALBEDO" => albedo = Some(splitted[1].parse::<f32>().unwrap()),
+                "NOISE_MEAN" => noise_mean = Some(splitted[1].parse::<f64>().unwrap()),
+                "NOISE_SIGMA" => noise_sigma = Some(splitted[1].parse::<f64>().unwrap()),
+                "GRID" => scene.grid = Some((splitted[1].parse::<usize>().unwrap().max(1), splitted[2].parse::<usize>().unwrap().max(1))),
+                other => panic!("unknown scene parameter {}", other)
+            }
+        }
+        match (region, albedo){
+            (Some((min, max)), Some(albedo)) => scene.reference = Some(Reference{min, max, albedo}),
+            (None, None) => {}
+            _ => panic!("REFERENCE_REGION and REFERENCE_ALBEDO go together")
+        }
+        //a sigma alone is noise around 0
+        match (noise_mean, noise_sigma){
+            (mean, Some(sigma)) => scene.noise = Some(NoiseModel::init(mean.unwrap_or(0.0), sigma)),
+            (None, None) => {}
+            _ => panic!("NOISE_MEAN needs NOISE_SIGMA")
+        }
+        return scene;
+    }
+
+    //the file is optional, without it nothing is known
+    pub fn read_if_exists(path: &str) -> Self{
+        if std::path::Path::new(path).exists(){
+            return SceneFile::read(path);
+        }
+        return SceneFile::init();
+    }
+}
@@ -0,0 +1,423 @@
+use crate::label_map::LabelMap;
+
+
+//patches of a mondrian are regions of constant albedo, so in log brightness a boundary is a step
+//while the shading only adds a smooth slope: the step between two neighbouring pixels minus the
+//mean of the steps beside it is the shading-normalized edge strength. the picture is first cut
+//generously along everything that might be an edge, then touching regions are merged again unless
+//the step over their whole common boundary stands out of the noise and the sRGB quantization;
+//a boundary hundreds of pixels long shows through noise no single pixel would
+//brightness below it is all quantization and noise in the log
+static LOG_FLOOR: f32 = 2e-3;
+//edge strengths are averaged this far along the edge, against noise; the longer ones find the
+//boundaries of dark noisy patches, the shorter ones short boundaries
+static LENGTHS: &[usize] = &[12, 48, 150];
+//weakest log albedo step taken as a boundary
+static MIN_STEP: f32 = 0.02;
+//one sRGB level is a step in the log as well, an edge has to be more than that
+static QUANTIZATION_STEPS: f32 = 1.5;
+//how far above the noise an edge strength has to be to cut, and a whole boundary to keep two regions apart
+static CUT_DEVIATIONS: f32 = 3.0;
+static KEEP_DEVIATIONS: f64 = 6.0;
+//brightness the thresholds are set by is the mean of a box this far around the pixel
+static SMOOTH: usize = 2;
+//fraction of the picture a region needs to count as a patch
+static MIN_REGION: f32 = 0.002;
+
+
+fn find(parent: &mut [usize], i: usize) -> usize{
+    let mut root = i;
+    while parent[root] != root{
+        root = parent[root];
+    }
+    let mut i = i;
+    while parent[i] != root{
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    return root;
+}
+
+fn union(parent: &mut [usize], a: usize, b: usize){
+    let (ra, rb) = (find(parent, a), find(parent, b));
+    if ra != rb{
+        parent[ra.max(rb)] = ra.min(rb);
+    }
+}
+
+//the pixel next to (j, k) along axis, 0 for j and 1 for k
+fn next(axis: usize, j: usize, k: usize) -> (usize, usize){
+    return if axis == 0 {(j + 1, k)} else {(j, k + 1)};
+}
+
+//every step between a pixel and the next along axis minus the mean of the steps just before and
+//after it, which is the local slope; 0 at the far border
+fn detrended_steps(arr: &ndarray::Array2::<f32>, axis: usize) -> ndarray::Array2::<f32>{
+    let (w, h) = (arr.shape()[0], arr.shape()[1]);
+    let n = if axis == 0 {w} else {h};
+    let step = |j: usize, k: usize| -> f32 {
+        let (j1, k1) = next(axis, j, k);
+        return arr[[j1, k1]] - arr[[j, k]];
+    };
+    return ndarray::Array2::<f32>::from_shape_fn((w, h), |(j, k)| {
+        let i = if axis == 0 {j} else {k};
+        if i + 1 >= n{
+            return 0.0;
+        }
+        let before = if i > 0 {Some(if axis == 0 {step(j - 1, k)} else {step(j, k - 1)})} else {None};
+        let after = if i + 2 < n {Some(if axis == 0 {step(j + 1, k)} else {step(j, k + 1)})} else {None};
+        let slope = match (before, after){
+            (Some(b), Some(a)) => 0.5 * (a + b),
+            (Some(b), None) => b,
+            (None, Some(a)) => a,
+            (None, None) => 0.0
+        };
+        step(j, k) - slope
+    });
+}
+
+//mean along the edge over up to length + 1 pixels, every step weighted by the inverse of its
+//variance; a straight boundary adds up and noise doesn't. one-sided so that where two boundaries
+//meet at a corner their steps don't cancel. of the two sides the one further from noise is kept,
+//as its absolute mean and how many standard deviations that is
+fn along_edge(raw: &ndarray::Array2::<f32>, weight: &ndarray::Array2::<f32>, axis: usize, length: usize) -> ndarray::Array2::<(f32, f32)>{
+    //the edge between a pixel and the next along axis runs along the other axis
+    let along = ndarray::Axis(1 - axis);
+    let mut strength = ndarray::Array2::<(f32, f32)>::from_elem(raw.raw_dim(), (0.0, 0.0));
+    ndarray::Zip::from(strength.lanes_mut(along)).and(raw.lanes(along)).and(weight.lanes(along)).par_for_each(|mut out, r, w| {
+        let n = r.len();
+        //prefix sums of the weighted steps and of the weights
+        let mut prefix = vec![(0.0f64, 0.0f64); n + 1];
+        for i in 0..n{
+            prefix[i + 1] = (prefix[i].0 + (r[i] * w[i]) as f64, prefix[i].1 + w[i] as f64);
+        }
+        let side = |lo: usize, hi: usize| -> (f32, f32) {
+            let (sum, total) = (prefix[hi].0 - prefix[lo].0, (prefix[hi].1 - prefix[lo].1).max(1e-30));
+            return ((sum / total).abs() as f32, (sum.abs() / total.sqrt()) as f32);
+        };
+        for i in 0..n{
+            let before = side(i.saturating_sub(length), i + 1);
+            let after = side(i, (i + length + 1).min(n));
+            out[i] = if before.1 >= after.1 {before} else {after};
+        }
+    });
+    return strength;
+}
+
+//robust standard deviation of the detrended linear steps, the noise of the picture more or less
+pub fn noise_deviation(scene_arr: &ndarray::Array2::<f32>) -> f32{
+    let mut values: Vec<f32> = detrended_steps(scene_arr, 0).iter().map(|v| v.abs()).collect();
+    let n = values.len();
+    let (_, median, _) = values.select_nth_unstable_by(n / 2, |x, y| x.total_cmp(y));
+    return 1.4826 * *median;
+}
+
+//separable box mean
+pub fn local_mean(arr: &ndarray::Array2::<f32>, r: usize) -> ndarray::Array2::<f32>{
+    let (w, h) = (arr.shape()[0], arr.shape()[1]);
+    let rows = ndarray::Array2::<f32>::from_shape_fn((w, h), |(j, k)| {
+        let (lo, hi) = (j.saturating_sub(r), (j + r).min(w - 1));
+        (lo..=hi).map(|jj| arr[[jj, k]]).sum::<f32>() / (hi - lo + 1) as f32
+    });
+    return ndarray::Array2::<f32>::from_shape_fn((w, h), |(j, k)| {
+        let (lo, hi) = (k.saturating_sub(r), (k + r).min(h - 1));
+        (lo..=hi).map(|kk| rows[[j, kk]]).sum::<f32>() / (hi - lo + 1) as f32
+    });
+}
+
+//one 8 bit sRGB level at linear brightness v, in the log; levels is the table of these
+fn quantization_step(v: f32, levels: &[f32]) -> f32{
+    let s = ((crate::clinear_to_srgb(v.clamp(LOG_FLOOR, 1.0)) * 255.0).round() as usize).clamp(1, 254);
+    return levels[s];
+}
+
+//per pixel: the weakest log step that is neither a small albedo difference nor quantization, and
+//the variance of a detrended log step from the noise, which is the larger the darker the pixel,
+//and from the quantization
+struct Thresholds{
+    floor: ndarray::Array2::<f32>,
+    variance: ndarray::Array2::<f32>
+}
+
+impl Thresholds{
+    fn init(scene_arr: &ndarray::Array2::<f32>) -> Self{
+        let linear_noise = noise_deviation(scene_arr);
+        let brightness = local_mean(scene_arr, SMOOTH).mapv(|v| v.max(LOG_FLOOR));
+        let levels: Vec<f32> = (0..255).map(|s| (crate::srgb_to_clinear(s + 1) / crate::srgb_to_clinear(s.max(1))).ln()).collect();
+        let quantization = brightness.mapv(|v| quantization_step(v, &levels));
+        return Thresholds{
+            floor: quantization.mapv(|q| MIN_STEP.max(QUANTIZATION_STEPS * q)),
+            //a uniform rounding error of one level has a variance of a twelfth, and a step has two roundings
+            variance: ndarray::Zip::from(&brightness).and(&quantization).map_collect(|v, q| (linear_noise / v).powi(2) + q * q / 6.0)
+        };
+    }
+}
+
+//the detrended log steps between every pixel and the next along axis, with the floor and the
+//inverse variance of every step; of the two pixels the darker one decides
+struct Steps{
+    axis: usize,
+    raw: ndarray::Array2::<f32>,
+    floor: ndarray::Array2::<f32>,
+    weight: ndarray::Array2::<f32>
+}
+
+impl Steps{
+    fn init(log: &ndarray::Array2::<f32>, thresholds: &Thresholds, axis: usize) -> Self{
+        let (w, h) = (log.shape()[0], log.shape()[1]);
+        let last = |j: usize, k: usize| (axis == 0 && j + 1 >= w) || (axis == 1 && k + 1 >= h);
+        return Steps{
+            axis,
+            raw: detrended_steps(log, axis),
+            floor: ndarray::Array2::<f32>::from_shape_fn((w, h), |(j, k)| {
+                if last(j, k) {0.0} else {thresholds.floor[[j, k]].max(thresholds.floor[next(axis, j, k)])}
+            }),
+            weight: ndarray::Array2::<f32>::from_shape_fn((w, h), |(j, k)| {
+                if last(j, k) {0.0} else {1.0 / thresholds.variance[[j, k]].max(thresholds.variance[next(axis, j, k)])}
+            })
+        };
+    }
+
+    //strong enough at any length and the largest across the boundary, so a step gives one line of edges
+    fn edges(&self) -> ndarray::Array2::<bool>{
+        let axis = self.axis;
+        let (w, h) = (self.raw.shape()[0], self.raw.shape()[1]);
+        let n = if axis == 0 {w} else {h};
+        let mut found = ndarray::Array2::<bool>::from_elem((w, h), false);
+        for &length in LENGTHS{
+            let strength = along_edge(&self.raw, &self.weight, axis, length);
+            ndarray::Zip::indexed(&mut found).for_each(|(j, k), f| {
+                let i = if axis == 0 {j} else {k};
+                if *f || i + 1 >= n{
+                    return;
+                }
+                let (mean, significance) = strength[[j, k]];
+                if mean < self.floor[[j, k]] || significance < CUT_DEVIATIONS{
+                    return;
+                }
+                let prev = if i == 0 {0.0} else if axis == 0 {strength[[j - 1, k]].1} else {strength[[j, k - 1]].1};
+                *f = significance >= prev && significance >= strength[next(axis, j, k)].1;
+            });
+        }
+        return found;
+    }
+}
+
+//connected regions of pixels not cut apart by edges, the ones too small to be a patch given to
+//whichever region grows into them first; labels are 0 .. count in no particular order
+fn regions(edges_j: &ndarray::Array2::<bool>, edges_k: &ndarray::Array2::<bool>) -> (ndarray::Array2::<usize>, usize){
+    let (w, h) = (edges_j.shape()[0], edges_j.shape()[1]);
+    let index = |j: usize, k: usize| j * h + k;
+    let mut parent: Vec<usize> = (0..w * h).collect();
+    for j in 0..w{
+        for k in 0..h{
+            if j + 1 < w && !edges_j[[j, k]]{
+                union(&mut parent, index(j, k), index(j + 1, k));
+            }
+            if k + 1 < h && !edges_k[[j, k]]{
+                union(&mut parent, index(j, k), index(j, k + 1));
+            }
+        }
+    }
+    let mut size = vec![0usize; w * h];
+    for i in 0..w * h{
+        let r = find(&mut parent, i);
+        size[r] += 1;
+    }
+    let min_size = (MIN_REGION * (w * h) as f32) as usize;
+    let largest = (0..w * h).max_by_key(|i| size[*i]).unwrap();
+    let mut number = std::collections::HashMap::<usize, usize>::new();
+    let mut labels = ndarray::Array2::<usize>::from_elem((w, h), usize::MAX);
+    let mut queue = std::collections::VecDeque::new();
+    for j in 0..w{
+        for k in 0..h{
+            let r = find(&mut parent, index(j, k));
+            if size[r] < min_size && r != largest{
+                continue;
+            }
+            let count = number.len();
+            labels[[j, k]] = *number.entry(r).or_insert(count);
+            queue.push_back((j, k));
+        }
+    }
+    while let Some((j, k)) = queue.pop_front(){
+        let l = labels[[j, k]];
+        let mut grow = |jj: usize, kk: usize| {
+            if labels[[jj, kk]] == usize::MAX{
+                labels[[jj, kk]] = l;
+                queue.push_back((jj, kk));
+            }
+        };
+        if j > 0 {grow(j - 1, k);}
+        if j + 1 < w {grow(j + 1, k);}
+        if k > 0 {grow(j, k - 1);}
+        if k + 1 < h {grow(j, k + 1);}
+    }
+    return (labels, number.len());
+}
+
+//evidence of a step over the common boundary of two regions, sums over its pixel pairs weighted by
+//their inverse variances; the step is signed from the lower label to the higher
+#[derive(Clone, Copy, Default)]
+struct Boundary{
+    step: f64,
+    floor: f64,
+    weight: f64
+}
+
+impl Boundary{
+    fn add(&mut self, other: &Boundary, sign: f64){
+        self.step += sign * other.step;
+        self.floor += other.floor;
+        self.weight += other.weight;
+    }
+
+    //how many standard deviations the mean step is away from none, 0 when it is below the floor
+    fn deviations(&self) -> f64{
+        if self.step.abs() < self.floor{
+            return 0.0;
+        }
+        return self.step.abs() / self.weight.max(1e-30).sqrt();
+    }
+}
+
+//merges touching regions, weakest boundary first, until every boundary left is a real step
+fn merge(labels: &mut ndarray::Array2::<usize>, count: usize, steps: &[Steps]) -> usize{
+    let (w, h) = (labels.shape()[0], labels.shape()[1]);
+    let mut boundaries = std::collections::HashMap::<(usize, usize), Boundary>::new();
+    for s in steps{
+        let axis = s.axis;
+        for j in 0..w - 1 + axis{
+            for k in 0..h - axis{
+                let (a, b) = (labels[[j, k]], labels[next(axis, j, k)]);
+                if a == b{
+                    continue;
+                }
+                let sign = if a < b {1.0} else {-1.0};
+                let weight = s.weight[[j, k]] as f64;
+                let boundary = boundaries.entry((a.min(b), a.max(b))).or_default();
+                boundary.add(&Boundary{step: s.raw[[j, k]] as f64 * weight, floor: s.floor[[j, k]] as f64 * weight, weight}, sign);
+            }
+        }
+    }
+    let mut parent: Vec<usize> = (0..count).collect();
+    loop{
+        let weakest = boundaries.iter()
+            .map(|(key, b)| (*key, b.deviations()))
+            .filter(|(_, d)| *d < KEEP_DEVIATIONS)
+            .min_by(|x, y| x.1.total_cmp(&y.1).then(x.0.cmp(&y.0)));
+        let (a, b) = match weakest{
+            Some((key, _)) => key,
+            None => break
+        };
+        boundaries.remove(&(a, b));
+        //b goes into a, and its boundaries with everyone else become a's
+        parent[b] = a;
+        let moved: Vec<((usize, usize), Boundary)> = boundaries.iter().filter(|(key, _)| key.0 == b || key.1 == b).map(|(key, v)| (*key, *v)).collect();
+        for (key, boundary) in moved{
+            boundaries.remove(&key);
+            let other = if key.0 == b {key.1} else {key.0};
+            //the stored step goes from key.0 to key.1, now it has to go from min(a, other) to the max
+            let sign = if (key.0 == b) == (a < other) {1.0} else {-1.0};
+            boundaries.entry((a.min(other), a.max(other))).or_default().add(&boundary, sign);
+        }
+    }
+    //what every old label ended up as, numbered in the order their first pixel comes up row by row,
+    //which for the grid is 3 * row + column
+    let mut number = vec![usize::MAX; count];
+    let mut next_label = 0;
+    for k in 0..h{
+        for j in 0..w{
+            let mut r = labels[[j, k]];
+            while parent[r] != r{
+                r = parent[r];
+            }
+            if number[r] == usize::MAX{
+                number[r] = next_label;
+                next_label += 1;
+            }
+            labels[[j, k]] = number[r];
+        }
+    }
+    return next_label;
+}
+
+pub fn segment(scene_arr: &ndarray::Array2::<f32>) -> LabelMap{
+    let log = scene_arr.mapv(|v| v.max(LOG_FLOOR).ln());
+    let thresholds = Thresholds::init(scene_arr);
+    let steps = [Steps::init(&log, &thresholds, 0), Steps::init(&log, &thresholds, 1)];
+    let (mut labels, count) = regions(&steps[0].edges(), &steps[1].edges());
+    let count = merge(&mut labels, count, &steps);
+    return LabelMap::from_labels(labels, count);
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    //mondrian-like rectangles of unequal sizes: a strip on the left, the rest cut in two across,
+    //and a small box inside the lower part
+    fn layout() -> LabelMap{
+        let labels = ndarray::Array2::<usize>::from_shape_fn((800, 600), |(j, k)| {
+            if j < 250{
+                return 0;
+            }
+            if (450..650).contains(&j) && (420..540).contains(&k){
+                return 3;
+            }
+            return if k < 350 {1} else {2};
+        });
+        return LabelMap::from_labels(labels, 4);
+    }
+
+    //the found patch of most pixels of every true patch, away from the boundaries
+    fn matched(found: &LabelMap, truth: &LabelMap) -> Vec<usize>{
+        let (w, h) = truth.shape();
+        let margin = 4;
+        let mut counts = vec![vec![0; found.count()]; truth.count()];
+        for j in margin..w - margin{
+            for k in margin..h - margin{
+                let t = truth.label(j, k);
+                if truth.label(j - margin, k) == t && truth.label(j + margin, k) == t && truth.label(j, k - margin) == t && truth.label(j, k + margin) == t{
+                    counts[t][found.label(j, k)] += 1;
+                }
+            }
+        }
+        return counts.iter().map(|c| {
+            let best = (0..c.len()).max_by_key(|i| c[*i]).unwrap();
+            //and nearly all of them
+            assert!(c[best] * 100 > c.iter().sum::<usize>() * 99, "{:?}", counts);
+            best
+        }).collect();
+    }
+
+    #[test]
+    fn segment_finds_patches_that_are_not_a_grid(){
+        let truth = layout();
+        let arr = render(&truth, (420.0, 280.0), 500.0, &[0.8, 0.35, 0.6, 0.2], 0.003, 7);
+        let found = segment(&arr);
+        assert_eq!(found.count(), 4);
+        let mut m = matched(&found, &truth);
+        m.sort();
+        assert_eq!(m, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn segment_finds_the_grid_of_a_noisy_picture(){
+        let truth = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&truth, (650.0, 300.0), 350.0, &[0.6, 0.25, 0.9, 0.4, 1.0, 0.15, 0.75, 0.5, 0.3], 0.005, 3);
+        let found = segment(&arr);
+        assert_eq!(found.count(), 9);
+        //numbered like the grid as well
+        assert_eq!(matched(&found, &truth), (0..9).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn a_flat_picture_is_one_patch(){
+        let arr = ndarray::Array2::<f32>::from_elem((300, 200), 0.4);
+        assert_eq!(segment(&arr).count(), 1);
+    }
+}
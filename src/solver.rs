@@ -0,0 +1,516 @@
+use crate::accumulator::{self, AccumulatorParams};
+use crate::albedo_graph::{self, AlbedoSolution};
+use crate::circle_fit::{self, ClusterFit};
+use crate::gradient_lines::{self, GradientLocation};
+use crate::height_fit;
+use crate::label_map::LabelMap;
+use crate::lm;
+use crate::location_quality;
+use crate::noise_model::NoiseModel;
+use crate::progress::Progress;
+use crate::segmentation;
+use ndarray::parallel::prelude::*;
+
+
+//a reverse solver turns an observed picture into location, height and albedo of its light. every
+//one is registered under a name below, the cli and the gui pick them by it
+
+
+//noise left in the box means brightness clusters are taken from, and the largest box radius for it
+static CLUSTER_NOISE: f64 = 0.003;
+static MAX_CLUSTER_RADIUS: usize = 6;
+//brightness clusters kept per patch, brightness levels first seen after that many are left out
+static MAX_CLUSTERS: usize = 60;
+//the pyramid halves the picture while its shorter side stays at least this long
+static PYRAMID_MIN_SIDE: usize = 150;
+pub static DEFAULT_SOLVER: &str = "separate";
+
+
+//what a solver is given: the linear picture, its patches and its noise
+pub struct Observation<'a>{
+    pub scene_arr: &'a ndarray::Array2::<f32>,
+    pub labels: &'a LabelMap,
+    pub noise: &'a NoiseModel,
+    //patches the diagnostics found flat, they have no isophotes to fit
+    pub flat: &'a [bool],
+    pub loc_params: &'a AccumulatorParams,
+    pub progress: &'a Progress
+}
+
+pub struct ReverseSolution{
+    pub location: (f32, f32),
+    //0 when no height could be fitted
+    pub height: f32,
+    //albedo / the largest albedo, 0 everywhere when it couldn't be solved
+    pub albedo: Vec<f32>,
+    //rms residual of the radial profile fit
+    pub height_residual: f64,
+    pub albedo_solution: Option<AlbedoSolution>,
+    //the cluster fits and where they voted, empty and None for solvers that don't vote
+    pub fits: Vec<ClusterFit>,
+    pub voted: Option<(f32, f32)>,
+    pub gradient_location: Option<GradientLocation>,
+    //milliseconds spent by cluster voting and by gradient lines
+    pub loc_times: (f32, f32),
+    //false when a joint fit stopped without converging, solvers without one always converge
+    pub converged: bool
+}
+
+impl ReverseSolution{
+    fn init(patches: usize) -> Self{
+        return ReverseSolution{
+            location: (0.0, 0.0),
+            height: 0.0,
+            albedo: vec![0.0; patches],
+            height_residual: 0.0,
+            albedo_solution: None,
+            fits: vec!(),
+            voted: None,
+            gradient_location: None,
+            loc_times: (0.0, 0.0),
+            converged: true
+        };
+    }
+}
+
+pub trait ReverseSolver{
+    fn name(&self) -> &'static str;
+    //one line for the cli's list and the gui's tooltip
+    fn describe(&self) -> &'static str;
+    fn solve(&self, obs: &Observation) -> ReverseSolution;
+}
+
+
+//every solver there is; a new one only has to be added here
+pub fn registry() -> Vec<Box<dyn ReverseSolver>>{
+    return vec!(Box::new(Separate), Box::new(Voting), Box::new(Gradient), Box::new(Joint), Box::new(Pyramid));
+}
+
+pub fn by_name(name: &str) -> Option<Box<dyn ReverseSolver>>{
+    return registry().into_iter().find(|s| s.name() == name);
+}
+
+
+//area the light is searched in: the frame and OUTSIDE_FRAMES frame widths around it
+pub fn vote_extent(shape: (usize, usize)) -> ((f64, f64), (f64, f64)){
+    let reach = location_quality::OUTSIDE_FRAMES * shape.0.max(shape.1) as f64;
+    return ((-reach, -reach), (shape.0 as f64 + reach, shape.1 as f64 + reach));
+}
+
+//pixels of a patch by brightness
+type Clusters = std::collections::HashMap<usize, Vec<(i32, i32)>>;
+
+fn process_patch(cluster: Clusters, progress: &Progress) -> Vec<ClusterFit>{
+    return cluster.into_par_iter().filter_map(|cl| {
+        if progress.is_cancelled(){
+            return None;
+        }
+        //brightness key doubles as the seed, so reruns give the same answer
+        let fit = circle_fit::fit_cluster(&cl.1, crate::SEED ^ cl.0 as u64);
+        progress.step();
+        return fit;
+    }).collect();
+}
+
+//get color clusters of every patch; on a noisy picture a pixel's own brightness hardly says which
+//isophote it is on, so box means with a few thousandths of noise left are clustered instead.
+//a flat patch has no isophotes, only noise, so it isn't clustered
+fn clusterize_patches(obs: &Observation) -> Vec<Clusters>{
+    let mut clusters: Vec<Clusters> = vec![std::collections::HashMap::new(); obs.labels.count()];
+    let radius = obs.noise.averaging_radius(CLUSTER_NOISE, MAX_CLUSTER_RADIUS);
+    let smoothed = if radius > 0 {segmentation::local_mean(obs.scene_arr, radius)} else {obs.scene_arr.clone()};
+    //box means across a boundary are of two patches
+    let uniform = obs.labels.uniform_boxes(radius);
+    for ((j, k), v) in smoothed.indexed_iter(){
+        let label = obs.labels.label(j, k);
+        if obs.flat.get(label).cloned().unwrap_or(false) || !uniform[[j, k]]{
+            continue;
+        }
+        let patch = &mut clusters[label];
+        let current_brightness = (((v * 10000.0).round() / 10000.0) * 100000000.0) as usize;
+        if patch.contains_key(&current_brightness){
+            patch.get_mut(&current_brightness).unwrap().push((j as i32, k as i32));
+        }
+        else if patch.keys().len() < MAX_CLUSTERS{
+            patch.insert(current_brightness, Vec::new());
+        }
+    }
+    return clusters;
+}
+
+//cluster fits and the location they vote for, None when nothing voted within reach
+fn vote(obs: &Observation) -> (Vec<ClusterFit>, Option<(f32, f32)>){
+    let mut fits: Vec<ClusterFit> = vec!();
+    let patches = clusterize_patches(obs);
+    obs.progress.start("fitting isophote clusters", patches.iter().map(|c| c.len()).sum());
+    for clusters in patches{
+        fits.extend(process_patch(clusters, obs.progress));
+    }
+    //every fit votes for its center with its weight; lights up to OUTSIDE_FRAMES frame widths
+    //away from the picture are searched for
+    let votes: Vec<((f64, f64), f64)> = fits.iter().map(|f| (f.circle.center, f.weight())).collect();
+    let voted = accumulator::find_peak(&votes, vote_extent(obs.scene_arr.dim()), obs.loc_params).map(|c| {
+        let c = circle_fit::combine_centers(&fits, c);
+        (c.0 as f32, c.1 as f32)
+    });
+    return (fits, voted);
+}
+
+//height by fitting the cos³ falloff to every pixel with per patch albedo in closed form, then the
+//albedo by log least squares over all boundary pixel pairs of touching patches
+fn solve_height_albedo(obs: &Observation, sol: &mut ReverseSolution){
+    obs.progress.start("fitting height and albedo", 0);
+    if let Some(fit) = height_fit::fit_height(obs.scene_arr, obs.labels, obs.noise, sol.location){
+        sol.height = fit.height;
+        sol.height_residual = fit.residual;
+    }
+    if let Some(albedo) = albedo_graph::solve_albedo(obs.scene_arr, obs.labels, obs.noise, sol.location, sol.height){
+        sol.albedo = albedo.albedo.clone();
+        sol.albedo_solution = Some(albedo);
+    }
+}
+
+
+//both location solvers, the gradient one as a cross-check of the voting, then height and albedo
+pub struct Separate;
+
+impl ReverseSolver for Separate{
+    fn name(&self) -> &'static str{
+        return "separate";
+    }
+
+    fn describe(&self) -> &'static str{
+        return "isophote circle voting checked by gradient lines, then falloff height and boundary albedo";
+    }
+
+    fn solve(&self, obs: &Observation) -> ReverseSolution{
+        let mut sol = ReverseSolution::init(obs.labels.count());
+        let start = std::time::Instant::now();
+        let (fits, voted) = vote(obs);
+        let voting = start.elapsed().as_secs_f32() * 1000.0;
+        if obs.progress.is_cancelled(){
+            return sol;
+        }
+        let start = std::time::Instant::now();
+        obs.progress.start("intersecting gradient lines", 0);
+        let starts: Vec<(f32, f32)> = voted.into_iter().collect();
+        sol.gradient_location = gradient_lines::solve_loc_gradient(obs.scene_arr, obs.labels, &starts);
+        sol.loc_times = (voting, start.elapsed().as_secs_f32() * 1000.0);
+        if let Some(v) = voted{
+            sol.location = v;
+        }
+        //far outside the frame the brightness bands are wide and flat and the circle fits go astray,
+        //so when the two disagree the answer the gradients point at better is kept
+        if let Some(gl) = &sol.gradient_location{
+            match voted{
+                None => sol.location = gl.location,
+                Some(v) if crate::eucl_dist_f32(&v, &gl.location) > 1.0 => {
+                    let scores = gradient_lines::agreement(obs.scene_arr, obs.labels, &[v]);
+                    if gl.agreement > scores[0]{
+                        sol.location = gl.location;
+                    }
+                }
+                _ => {}
+            }
+        }
+        sol.fits = fits;
+        sol.voted = voted;
+        solve_height_albedo(obs, &mut sol);
+        return sol;
+    }
+}
+
+//the voted location alone
+pub struct Voting;
+
+impl ReverseSolver for Voting{
+    fn name(&self) -> &'static str{
+        return "voting";
+    }
+
+    fn describe(&self) -> &'static str{
+        return "isophote circle voting alone, then falloff height and boundary albedo";
+    }
+
+    fn solve(&self, obs: &Observation) -> ReverseSolution{
+        let mut sol = ReverseSolution::init(obs.labels.count());
+        let start = std::time::Instant::now();
+        let (fits, voted) = vote(obs);
+        sol.loc_times = (start.elapsed().as_secs_f32() * 1000.0, 0.0);
+        if let Some(v) = voted{
+            sol.location = v;
+        }
+        sol.fits = fits;
+        sol.voted = voted;
+        solve_height_albedo(obs, &mut sol);
+        return sol;
+    }
+}
+
+//where the brightness gradients point, without any circles
+pub struct Gradient;
+
+impl ReverseSolver for Gradient{
+    fn name(&self) -> &'static str{
+        return "gradient";
+    }
+
+    fn describe(&self) -> &'static str{
+        return "intersection of gradient lines alone, then falloff height and boundary albedo";
+    }
+
+    fn solve(&self, obs: &Observation) -> ReverseSolution{
+        let mut sol = ReverseSolution::init(obs.labels.count());
+        let start = std::time::Instant::now();
+        obs.progress.start("intersecting gradient lines", 0);
+        sol.gradient_location = gradient_lines::solve_loc_gradient(obs.scene_arr, obs.labels, &[]);
+        sol.loc_times = (0.0, start.elapsed().as_secs_f32() * 1000.0);
+        if let Some(gl) = &sol.gradient_location{
+            sol.location = gl.location;
+        }
+        solve_height_albedo(obs, &mut sol);
+        return sol;
+    }
+}
+
+//the separate answer refined by the joint fit of location, height and albedo
+pub struct Joint;
+
+impl ReverseSolver for Joint{
+    fn name(&self) -> &'static str{
+        return "lm";
+    }
+
+    fn describe(&self) -> &'static str{
+        return "the separate answer refined by a Levenberg-Marquardt fit of everything at once";
+    }
+
+    fn solve(&self, obs: &Observation) -> ReverseSolution{
+        let mut sol = Separate.solve(obs);
+        obs.progress.start("joint fit", 0);
+        let fit = lm::fit_single_light(obs.scene_arr, obs.labels, obs.noise, sol.location, sol.height, &sol.albedo);
+        sol.location = fit.location;
+        sol.height = fit.height;
+        sol.albedo = fit.relative_albedo();
+        sol.converged = fit.converged;
+        //what the separate fit left belongs to its own light, both again at the joint one
+        sol.height_residual = height_fit::residual_at(obs.scene_arr, obs.labels, obs.noise, sol.location, sol.height).unwrap_or(0.0);
+        sol.albedo_solution = albedo_graph::solve_albedo(obs.scene_arr, obs.labels, obs.noise, sol.location, sol.height);
+        return sol;
+    }
+}
+
+//one level of the pyramid, half the size of the one below it
+struct Level{
+    scene_arr: ndarray::Array2::<f32>,
+    labels: LabelMap,
+    noise: NoiseModel
+}
+
+impl Level{
+    //2x2 box means, a mean of four pixels has half their noise
+    fn halve(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel) -> Self{
+        let (w, h) = scene_arr.dim();
+        let halved = ndarray::Array2::<f32>::from_shape_fn((w / 2, h / 2), |(j, k)| {
+            (scene_arr[[2 * j, 2 * k]] + scene_arr[[2 * j + 1, 2 * k]] + scene_arr[[2 * j, 2 * k + 1]] + scene_arr[[2 * j + 1, 2 * k + 1]]) / 4.0
+        });
+        return Level{
+            scene_arr: halved,
+            labels: labels.halved(),
+            noise: NoiseModel::init(noise.mean, noise.sigma / 2.0)
+        };
+    }
+
+    fn observation<'a>(&'a self, full: &Observation<'a>) -> Observation<'a>{
+        return Observation{
+            scene_arr: &self.scene_arr,
+            labels: &self.labels,
+            noise: &self.noise,
+            flat: full.flat,
+            loc_params: full.loc_params,
+            progress: full.progress
+        };
+    }
+}
+
+//pixel j of a halved picture covers pixels 2j and 2j + 1 of the one below
+fn to_finer(p: (f32, f32)) -> (f32, f32){
+    return (2.0 * p.0 + 0.5, 2.0 * p.1 + 0.5);
+}
+
+//the separate solvers on a picture halved until it is small, then the joint fit on every finer level
+//starting from the answer of the level above. the cluster fits, the vote and the gradient lines are
+//those of the smallest picture, put back in full size pixels
+pub struct Pyramid;
+
+impl ReverseSolver for Pyramid{
+    fn name(&self) -> &'static str{
+        return "pyramid";
+    }
+
+    fn describe(&self) -> &'static str{
+        return "the separate solvers on a downsampled picture, refined by the joint fit level by level up to full size";
+    }
+
+    fn solve(&self, obs: &Observation) -> ReverseSolution{
+        let mut levels: Vec<Level> = vec!();
+        loop{
+            let (scene_arr, labels, noise) = match levels.last(){
+                Some(l) => (&l.scene_arr, &l.labels, &l.noise),
+                None => (obs.scene_arr, obs.labels, obs.noise)
+            };
+            if scene_arr.shape()[0].min(scene_arr.shape()[1]) / 2 < PYRAMID_MIN_SIDE{
+                break;
+            }
+            let level = Level::halve(scene_arr, labels, noise);
+            levels.push(level);
+        }
+        //too small to halve even once
+        if levels.is_empty(){
+            return Separate.solve(obs);
+        }
+        let mut sol = Separate.solve(&levels[levels.len() - 1].observation(obs));
+        let scale = (1 << levels.len()) as f64;
+        let to_full = |p: (f64, f64)| (p.0 * scale + (scale - 1.0) / 2.0, p.1 * scale + (scale - 1.0) / 2.0);
+        for f in sol.fits.iter_mut(){
+            f.circle.center = to_full(f.circle.center);
+            f.circle.radius *= scale;
+            f.rms *= scale;
+        }
+        sol.voted = sol.voted.map(|v| {
+            let v = to_full((v.0 as f64, v.1 as f64));
+            (v.0 as f32, v.1 as f32)
+        });
+        if let Some(gl) = &mut sol.gradient_location{
+            let l = to_full((gl.location.0 as f64, gl.location.1 as f64));
+            gl.location = (l.0 as f32, l.1 as f32);
+        }
+        let mut location = sol.location;
+        let mut height = sol.height;
+        let mut albedo = sol.albedo.clone();
+        obs.progress.start("refining finer levels", levels.len());
+        for i in (0..levels.len()).rev(){
+            if obs.progress.is_cancelled(){
+                break;
+            }
+            let finer = if i == 0 {Observation{..*obs}} else {levels[i - 1].observation(obs)};
+            //solve_height leaves 0 when nothing fitted, the joint fit then starts from its own guess
+            let fit = lm::fit_single_light(finer.scene_arr, finer.labels, finer.noise, to_finer(location), 2.0 * height, &albedo);
+            location = fit.location;
+            height = fit.height;
+            albedo = fit.relative_albedo();
+            //the finest level's fit is the answer
+            sol.converged = fit.converged;
+            obs.progress.step();
+        }
+        sol.location = location;
+        sol.height = height;
+        sol.albedo = albedo;
+        sol.height_residual = height_fit::residual_at(obs.scene_arr, obs.labels, obs.noise, location, height).unwrap_or(0.0);
+        sol.albedo_solution = albedo_graph::solve_albedo(obs.scene_arr, obs.labels, obs.noise, location, height);
+        if let Some(a) = &sol.albedo_solution{
+            sol.albedo = a.albedo.clone();
+        }
+        return sol;
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.45, 0.7, 0.3, 0.55, 0.8, 0.25, 0.6, 0.4, 0.9];
+
+    #[test]
+    fn registry_names_are_unique_and_found(){
+        let names: Vec<&str> = registry().iter().map(|s| s.name()).collect();
+        for (i, n) in names.iter().enumerate(){
+            assert!(!names[i + 1..].contains(n), "{:?}", names);
+            assert_eq!(by_name(n).unwrap().name(), *n);
+        }
+        assert!(by_name(DEFAULT_SOLVER).is_some());
+        assert!(by_name("nonesuch").is_none());
+    }
+
+    #[test]
+    fn every_solver_finds_the_light_of_a_noisy_render(){
+        let labels = LabelMap::grid((720, 600), 3, 3);
+        let arr = render(&labels, (410.0, 260.0), 300.0, &ALBEDO, 0.004, 7);
+        let noise = NoiseModel::init(0.0, 0.004);
+        let loc_params = AccumulatorParams::init();
+        let progress = Progress::init();
+        let obs = Observation{scene_arr: &arr, labels: &labels, noise: &noise, flat: &[false; 9], loc_params: &loc_params, progress: &progress};
+        for solver in registry(){
+            let sol = solver.solve(&obs);
+            assert!((sol.location.0 - 410.0).abs() < 2.0 && (sol.location.1 - 260.0).abs() < 2.0, "{} {:?}", solver.name(), sol.location);
+            assert!((sol.height - 300.0).abs() < 6.0, "{} {}", solver.name(), sol.height);
+            for (a, truth) in sol.albedo.iter().zip(ALBEDO.iter()){
+                assert!((*a as f64 - truth / 0.9).abs() < 0.02, "{} {:?}", solver.name(), sol.albedo);
+            }
+        }
+    }
+
+    #[test]
+    fn pyramid_matches_the_full_resolution_fit(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let noise = NoiseModel::init(0.0, 0.006);
+        let loc_params = AccumulatorParams::init();
+        let progress = Progress::init();
+        //over the picture and outside it
+        for (seed, light, h) in [(8, (610.0, 280.0), 380.0), (9, (-260.0, 610.0), 450.0)]{
+            let arr = render(&labels, light, h, &ALBEDO, 0.006, seed);
+            let obs = Observation{scene_arr: &arr, labels: &labels, noise: &noise, flat: &[false; 9], loc_params: &loc_params, progress: &progress};
+            let full = Joint.solve(&obs);
+            let pyramid = Pyramid.solve(&obs);
+            assert!(crate::eucl_dist_f32(&full.location, &pyramid.location) < 0.5, "{:?} {:?}", full.location, pyramid.location);
+            assert!((full.height - pyramid.height).abs() < 0.5, "{} {}", full.height, pyramid.height);
+            //the residual is the full size picture's, not the smallest level's
+            assert!((pyramid.height_residual - 0.006).abs() < 3e-4, "{}", pyramid.height_residual);
+            assert!((pyramid.location.0 - light.0 as f32).abs() < 3.0 && (pyramid.location.1 - light.1 as f32).abs() < 3.0, "{:?}", pyramid.location);
+            //the boundary graph at the full size answer
+            let graph = albedo_graph::solve_albedo(&arr, &labels, &noise, full.location, full.height).unwrap();
+            for (a, b) in graph.albedo.iter().zip(pyramid.albedo.iter()){
+                assert!((a - b).abs() < 2e-3, "{:?} {:?}", graph.albedo, pyramid.albedo);
+            }
+            //the vote of the smallest picture is put back in full size pixels
+            if light.0 > 0.0{
+                let voted = pyramid.voted.unwrap();
+                assert!((voted.0 - 610.0).abs() < 10.0 && (voted.1 - 280.0).abs() < 10.0, "{:?}", voted);
+            }
+        }
+    }
+
+    #[test]
+    fn the_joint_fit_reports_the_residual_and_graph_of_its_own_answer(){
+        //a light low over the corner, where the separate answer is off by a few pixels
+        let labels = LabelMap::grid((800, 800), 3, 3);
+        let arr = render(&labels, (90.0, 700.0), 160.0, &ALBEDO, 0.006, 12);
+        let noise = NoiseModel::init(0.0, 0.006);
+        let loc_params = AccumulatorParams::init();
+        let progress = Progress::init();
+        let obs = Observation{scene_arr: &arr, labels: &labels, noise: &noise, flat: &[false; 9], loc_params: &loc_params, progress: &progress};
+        let sol = Joint.solve(&obs);
+        let residual = height_fit::residual_at(&arr, &labels, &noise, sol.location, sol.height).unwrap();
+        assert!((sol.height_residual - residual).abs() < 1e-9, "{} {}", sol.height_residual, residual);
+        //at the right light the residual is the noise
+        assert!((sol.height_residual - 0.006).abs() < 3e-4, "{}", sol.height_residual);
+        let graph = albedo_graph::solve_albedo(&arr, &labels, &noise, sol.location, sol.height).unwrap();
+        assert_eq!(sol.albedo_solution.unwrap().log_sigma, graph.log_sigma);
+    }
+
+    #[test]
+    fn a_cancelled_solve_stops_before_fitting(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (410.0, 260.0), 300.0, &ALBEDO, 0.004, 7);
+        let noise = NoiseModel::init(0.0, 0.004);
+        let loc_params = AccumulatorParams::init();
+        let progress = Progress::init();
+        progress.cancel();
+        let obs = Observation{scene_arr: &arr, labels: &labels, noise: &noise, flat: &[false; 9], loc_params: &loc_params, progress: &progress};
+        let sol = Separate.solve(&obs);
+        assert!(sol.fits.is_empty() && sol.voted.is_none());
+        assert_eq!(sol.height, 0.0);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::height_fit;
+use crate::label_map::LabelMap;
+use crate::linalg::invert;
+use crate::lm::{self, NormalEquations, PixelSample};
+use crate::uncertainty::relative_albedo_sigma;
+
+
+//K pictures of one mondrian, each under its own light: v_k(p) = s_k * a(p) * g(light_k, h_k, p) + b_k.
+//every light has a position, a height and a brightness s_k (luminosity times whatever exposure
+//prep_arr chose for that picture), b_k is the picture's noise mean; the albedo is shared and
+//s_0 = 1 fixes the overall scale.
+//a single picture has to tell the albedo from the falloff, here every patch is seen under K
+//different falloffs, which is what pins the albedo down
+static STRIDE: usize = 3;
+static MAX_ITERATIONS: usize = 200;
+//of a light's brightness against the first one's, either way
+static MAX_SCALE: f64 = 1e3;
+
+
+pub struct StereoLight{
+    pub location: (f32, f32),
+    pub height: f32,
+    //brightness relative to the first frame's light in the fit
+    pub scale: f32,
+    //added to every pixel of the picture
+    pub offset: f32,
+    //the light got stuck at a bound of its height or brightness and its frame was left out of the
+    //fit; what is shown is where it got stuck, with no scale or offset when its own answer already was
+    pub degenerate: bool
+}
+
+pub struct StereoSolution{
+    pub lights: Vec<StereoLight>,
+    //albedo / the largest albedo
+    pub albedo: Vec<f32>,
+    //standard deviations of the relative albedo from the joint fit, and from every frame alone,
+    //infinite for frames left out
+    pub albedo_sigma: Vec<f32>,
+    pub single_sigma: Vec<Vec<f32>>,
+    pub cost: f64,
+    pub iterations: usize,
+    pub converged: bool
+}
+
+//where everything sits in the parameter vector: x, y, h of every light, the scales of all lights
+//but the first, the offsets, then the albedo
+struct Layout{
+    frames: usize,
+    patches: usize
+}
+
+impl Layout{
+    fn light(&self, k: usize) -> usize{
+        return 3 * k;
+    }
+
+    fn scale(&self, k: usize) -> Option<usize>{
+        if k == 0{
+            return None;
+        }
+        return Some(3 * self.frames + k - 1);
+    }
+
+    fn offset(&self, k: usize) -> usize{
+        return 4 * self.frames - 1 + k;
+    }
+
+    fn albedo(&self, p: usize) -> usize{
+        return 5 * self.frames - 1 + p;
+    }
+
+    fn len(&self) -> usize{
+        return self.albedo(self.patches);
+    }
+}
+
+fn normal_equations(frames: &[Vec<PixelSample>], layout: &Layout, params: &[f64], with_jacobian: bool) -> NormalEquations{
+    let mut ne = NormalEquations::init(params.len());
+    for (k, samples) in frames.iter().enumerate(){
+        let l = layout.light(k);
+        let s = layout.scale(k).map(|i| params[i]).unwrap_or(1.0);
+        for px in samples{
+            let ai = layout.albedo(px.patch);
+            let a = params[ai];
+            let (g, dg_dx, dg_dy, dg_dh) = lm::light_falloff((params[l], params[l + 1]), params[l + 2], px.pos);
+            let residual = s * a * g + params[layout.offset(k)] - px.value;
+            if !with_jacobian{
+                ne.cost += 0.5 * residual * residual;
+                continue;
+            }
+            let mut row = vec![(l, s * a * dg_dx), (l + 1, s * a * dg_dy), (l + 2, s * a * dg_dh), (ai, s * g), (layout.offset(k), 1.0)];
+            if let Some(si) = layout.scale(k){
+                row.push((si, a * g));
+            }
+            ne.add(residual, &row);
+        }
+    }
+    return ne;
+}
+
+//relative albedo standard deviations, σ² (JᵀJ)⁻¹ with σ² from the residuals
+fn albedo_sigma(frames: &[Vec<PixelSample>], layout: &Layout, params: &[f64]) -> Vec<f32>{
+    let ne = normal_equations(frames, layout, params, true);
+    let pixels: usize = frames.iter().map(|f| f.len()).sum();
+    let sigma2 = 2.0 * ne.cost / pixels.saturating_sub(params.len()).max(1) as f64;
+    return match invert(&ne.jtj){
+        Some(inv) => relative_albedo_sigma(&params[layout.albedo(0)..], &(inv * sigma2), layout.albedo(0)),
+        None => vec![f32::INFINITY; layout.patches]
+    };
+}
+
+//the shared albedo starts as the geometric mean of the frames' answers, every light at its frame's
+//answer with the brightness that fits that albedo best
+fn start_params(scene_arrs: &[ndarray::Array2::<f32>], frames: &[Vec<PixelSample>], kept: &[usize], starts: &[((f32, f32), f32, Vec<f32>)], layout: &Layout) -> Vec<f64>{
+    let albedo: Vec<f32> = (0..layout.patches).map(|p| {
+        let logs: Vec<f64> = kept.iter().filter_map(|k| starts[*k].2.get(p).cloned()).filter(|a| a.is_finite() && *a > 0.0).map(|a| (a as f64).ln()).collect();
+        if logs.is_empty() {1.0} else {(logs.iter().sum::<f64>() / logs.len() as f64).exp() as f32}
+    }).collect();
+    let mut params = vec![0.0; layout.len()];
+    let mut scales = vec!();
+    for (i, k) in kept.iter().enumerate(){
+        let (location, height, _) = &starts[*k];
+        let l = layout.light(i);
+        //solve_height leaves 0 when it found nothing
+        let h = if *height >= 1.0 {*height as f64} else {lm::fallback_height(&scene_arrs[*k])};
+        params[l] = location.0 as f64;
+        params[l + 1] = location.1 as f64;
+        params[l + 2] = h;
+        scales.push(lm::fit_albedo_scale(&frames[*k], (location.0 as f64, location.1 as f64), h, &albedo));
+    }
+    for i in 1..layout.frames{
+        params[layout.scale(i).unwrap()] = scales[i] / scales[0];
+    }
+    for p in 0..layout.patches{
+        params[layout.albedo(p)] = albedo[p] as f64 * scales[0];
+    }
+    return params;
+}
+
+//a light whose height or brightness ran into its bound: the frame's falloff doesn't pin them down,
+//and the fit trades one against the other without end
+fn is_stuck(params: &[f64], layout: &Layout, i: usize, shape: (usize, usize)) -> bool{
+    if height_fit::at_bound(params[layout.light(i) + 2] as f32, shape){
+        return true;
+    }
+    return match layout.scale(i){
+        Some(si) => params[si] * height_fit::BOUND_MARGIN > MAX_SCALE || params[si] < height_fit::BOUND_MARGIN / MAX_SCALE,
+        None => false
+    };
+}
+
+fn light(params: &[f64], layout: &Layout, i: usize, degenerate: bool) -> StereoLight{
+    let l = layout.light(i);
+    return StereoLight{
+        location: (params[l] as f32, params[l + 1] as f32),
+        height: params[l + 2] as f32,
+        scale: layout.scale(i).map(|si| params[si]).unwrap_or(1.0) as f32,
+        offset: params[layout.offset(i)] as f32,
+        degenerate
+    };
+}
+
+//starts are every frame's own answer: location, height and relative albedo; the patches are the
+//same in every picture. frames whose light gets stuck at a bound are left out and the others fitted
+//again, None when fewer than two are left
+pub fn solve(scene_arrs: &[ndarray::Array2::<f32>], labels: &LabelMap, starts: &[((f32, f32), f32, Vec<f32>)]) -> Option<StereoSolution>{
+    //clamped pixels under a low light don't follow the falloff
+    let frames: Vec<Vec<PixelSample>> = scene_arrs.iter().map(|arr| {
+        let saturated = arr.iter().cloned().fold(0.0, f32::max) as f64 * 0.999;
+        lm::sample_pixels(arr, labels, STRIDE).into_iter().filter(|px| px.value < saturated).collect()
+    }).collect();
+    let shape = scene_arrs[0].dim();
+    let max_height = height_fit::max_height(shape);
+    let patches = labels.count();
+    let mut lights: Vec<Option<StereoLight>> = (0..frames.len()).map(|_| None).collect();
+    //the frames in the fit, by their index in scene_arrs. one whose own answer is already stuck
+    //would drag the others with it, the first frame's most of all as it sets the scale. a height of 0
+    //is none found, that one starts from the fallback
+    let mut kept: Vec<usize> = (0..frames.len()).filter(|k| starts[*k].1 <= 0.0 || !height_fit::at_bound(starts[*k].1, shape)).collect();
+    for k in (0..frames.len()).filter(|k| !kept.contains(k)){
+        let (location, height, _) = &starts[k];
+        lights[k] = Some(StereoLight{location: *location, height: *height, scale: f32::NAN, offset: f32::NAN, degenerate: true});
+    }
+    loop{
+        if kept.len() < 2{
+            return None;
+        }
+        let layout = Layout{frames: kept.len(), patches};
+        let fitted: Vec<Vec<PixelSample>> = kept.iter().map(|k| frames[*k].clone()).collect();
+        let params = start_params(scene_arrs, &frames, &kept, starts, &layout);
+        let res = lm::levenberg_marquardt(&params,
+                                          |p, with_jacobian| normal_equations(&fitted, &layout, p, with_jacobian),
+                                          |p| {
+                                              for i in 0..layout.frames{
+                                                  p[layout.light(i) + 2] = p[layout.light(i) + 2].clamp(height_fit::MIN_HEIGHT, max_height);
+                                                  if let Some(si) = layout.scale(i){
+                                                      p[si] = p[si].clamp(1.0 / MAX_SCALE, MAX_SCALE);
+                                                  }
+                                              }
+                                              for a in p[layout.albedo(0)..].iter_mut(){
+                                                  *a = a.max(0.0);
+                                              }
+                                          },
+                                          MAX_ITERATIONS);
+        let p = &res.params;
+        let stuck: Vec<bool> = (0..layout.frames).map(|i| is_stuck(p, &layout, i, shape)).collect();
+        if stuck.iter().any(|s| *s){
+            for i in (0..layout.frames).filter(|i| stuck[*i]){
+                lights[kept[i]] = Some(light(p, &layout, i, true));
+            }
+            kept = (0..layout.frames).filter(|i| !stuck[*i]).map(|i| kept[i]).collect();
+            continue;
+        }
+        //the same estimate from every frame on its own, at the joint answer, to compare against;
+        //a frame left out says nothing about the albedo
+        let single = Layout{frames: 1, patches};
+        let mut single_sigma = vec![vec![f32::INFINITY; patches]; frames.len()];
+        for (i, k) in kept.iter().enumerate(){
+            let l = layout.light(i);
+            let s = layout.scale(i).map(|si| p[si]).unwrap_or(1.0);
+            let mut q = vec![p[l], p[l + 1], p[l + 2], p[layout.offset(i)]];
+            q.extend(p[layout.albedo(0)..].iter().map(|a| s * a));
+            single_sigma[*k] = albedo_sigma(&fitted[i..i + 1], &single, &q);
+            lights[*k] = Some(light(p, &layout, i, false));
+        }
+        let max = p[layout.albedo(0)..].iter().cloned().fold(f64::MIN, f64::max);
+        return Some(StereoSolution{
+            lights: lights.into_iter().map(|l| l.unwrap()).collect(),
+            albedo: p[layout.albedo(0)..].iter().map(|a| if max > 0.0 {(a / max) as f32} else {0.0}).collect(),
+            albedo_sigma: albedo_sigma(&fitted, &layout, p),
+            single_sigma,
+            cost: res.cost,
+            iterations: res.iterations,
+            converged: res.converged
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.5, 0.85, 0.35, 0.65, 0.25, 1.0, 0.75, 0.4, 0.6];
+
+    #[test]
+    fn two_lights_give_the_shared_albedo(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let first = render(&labels, (300.0, 620.0), 400.0, &ALBEDO, 0.003, 11);
+        //the second light is dimmer and its picture carries an offset
+        let second = render(&labels, (640.0, 250.0), 280.0, &ALBEDO, 0.003, 12).mapv(|v| 0.7 * v + 0.02);
+        let start: Vec<f32> = ALBEDO.iter().map(|a| *a as f32 * 1.1).collect();
+        let starts = vec![((305.0, 612.0), 380.0, start.clone()), ((633.0, 258.0), 300.0, start)];
+        let sol = solve(&[first, second], &labels, &starts).unwrap();
+        assert!(sol.converged);
+        let truth = [((300.0, 620.0), 400.0), ((640.0, 250.0), 280.0)];
+        for (light, (location, height)) in sol.lights.iter().zip(truth.iter()){
+            assert!((light.location.0 - location.0).abs() < 1.0 && (light.location.1 - location.1).abs() < 1.0, "{:?}", light.location);
+            assert!((light.height - height).abs() < 1.0, "{}", light.height);
+        }
+        assert!((sol.lights[1].scale - 0.7).abs() < 0.01 && (sol.lights[1].offset - 0.02).abs() < 1e-3, "{} {}", sol.lights[1].scale, sol.lights[1].offset);
+        for (a, truth) in sol.albedo.iter().zip(ALBEDO.iter()){
+            assert!((*a as f64 - truth).abs() < 0.005, "{:?}", sol.albedo);
+        }
+        //both frames together know the albedo better than either alone
+        let joint: f32 = sol.albedo_sigma.iter().sum();
+        for single in sol.single_sigma.iter(){
+            assert!(joint < single.iter().sum::<f32>(), "{:?} {:?}", sol.albedo_sigma, single);
+        }
+    }
+
+    #[test]
+    fn a_frame_whose_light_is_stuck_is_left_out(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let first = render(&labels, (300.0, 620.0), 400.0, &ALBEDO, 0.003, 11);
+        let second = render(&labels, (640.0, 250.0), 280.0, &ALBEDO, 0.003, 12);
+        //its own answer ran into the bottom of the height search, the light being far outside
+        let third = render(&labels, (-1800.0, 450.0), 300.0, &ALBEDO, 0.003, 13);
+        let start: Vec<f32> = ALBEDO.iter().map(|a| *a as f32 * 1.1).collect();
+        let starts = vec![((305.0, 612.0), 380.0, start.clone()), ((633.0, 258.0), 300.0, start.clone()), ((-1750.0, 450.0), 1.0, start)];
+        let sol = solve(&[first, second, third.clone()], &labels, &starts).unwrap();
+        assert!(sol.converged);
+        assert!(!sol.lights[0].degenerate && !sol.lights[1].degenerate && sol.lights[2].degenerate);
+        assert!(sol.single_sigma[2].iter().all(|s| s.is_infinite()));
+        for (a, truth) in sol.albedo.iter().zip(ALBEDO.iter()){
+            assert!((*a as f64 - truth).abs() < 0.005, "{:?}", sol.albedo);
+        }
+        //with only one frame left there is no stereo answer
+        let first = render(&labels, (300.0, 620.0), 400.0, &ALBEDO, 0.003, 11);
+        assert!(solve(&[first, third], &labels, &[starts[0].clone(), starts[2].clone()]).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use probability::source::Source;
+use crate::accumulator;
+use crate::circle_fit::ClusterFit;
+use crate::label_map::LabelMap;
+use crate::linalg::{condition_number, invert};
+use crate::lm;
+use crate::noise_model::NoiseModel;
+
+
+//standard deviations of a single light answer from the jacobian of the x, y, h, albedo model at that
+//answer. the residuals of neighbouring pixels are far from independent (median filter, quantization,
+//any misfit of the model), so instead of σ² (JᵀJ)⁻¹ the sandwich (JᵀJ)⁻¹ (Σ g gᵀ) (JᵀJ)⁻¹ is used
+//with g = Jᵀr of each block of pixels. the voted location is also bootstrapped over the cluster fits
+//and the wider of the two is shown. the exposure prep_arr applied is one more unknown, its
+//correlation with the height and the albedo widens their intervals; the intervals stay centered on
+//the answer though, and a stretch far from none still biases the height by a percent or two
+static STRIDE: usize = 3;
+//in pixels, residuals are taken as correlated within a block and independent between blocks
+static BLOCK: usize = 60;
+static BOOTSTRAP_ROUNDS: usize = 32;
+static BOOTSTRAP_SEED: u64 = 7;
+//two-sided 95%
+static Z95: f32 = 1.96;
+
+
+pub struct Confidence{
+    pub location: (f32, f32),
+    pub height: f32,
+    //of albedo / the largest albedo, 0 for the largest itself
+    pub albedo: Vec<f32>,
+    //spread of the voted location over resampled cluster fits, None when it wasn't voted for
+    pub bootstrap: Option<(f32, f32)>,
+    //of JᵀJ over x, y, h and the albedo, infinite when it is singular
+    pub condition: f64
+}
+
+impl Confidence{
+    pub fn location_sigma(&self) -> (f32, f32){
+        return match self.bootstrap{
+            Some(b) => (self.location.0.max(b.0), self.location.1.max(b.1)),
+            None => self.location
+        };
+    }
+}
+
+//half the width of the 95% interval
+pub fn half_width(sigma: f32) -> f32{
+    return Z95 * sigma;
+}
+
+//95% interval around an estimate
+pub fn interval(value: f32, sigma: f32) -> (f32, f32){
+    return (value - half_width(sigma), value + half_width(sigma));
+}
+
+//the picture decodes to S⁻¹(S(v) e^t), with t = 0 assumed though prep_arr stretched the sRGB values by
+//some unknown factor; this is the derivative over t at t = 0
+fn exposure_derivative(v: f64) -> f64{
+    let s = crate::clinear_to_srgb(v.clamp(0.0, 1.0) as f32) as f64;
+    if s <= 0.04045{
+        return s / 12.92;
+    }
+    return s * 2.4 / 1.055 * ((s + 0.055) / 1.055).powf(1.4);
+}
+
+//albedo may be relative, only the ratios matter and the scale is fitted here
+pub fn single_light(scene_arr: &ndarray::Array2::<f32>, labels: &LabelMap, noise: &NoiseModel, location: (f32, f32), height: f32, albedo: &[f32]) -> Option<Confidence>{
+    if albedo.len() != labels.count(){
+        return None;
+    }
+    let samples = lm::sample_pixels(scene_arr, labels, STRIDE);
+    let light = (location.0 as f64, location.1 as f64);
+    let h = (height as f64).max(1.0);
+    let albedo: Vec<f32> = albedo.iter().map(|a| if a.is_finite() && *a > 0.0 {*a} else {0.0}).collect();
+    let scale = lm::fit_albedo_scale(&samples, light, h, &albedo);
+    let a: Vec<f64> = albedo.iter().map(|a| *a as f64 * scale).collect();
+    //x, y, h, the albedos and the exposure
+    let n = 4 + a.len();
+    //JᵀJ, and Jᵀr summed within every block of pixels
+    let mut jtj = ndarray::Array2::<f64>::zeros((n, n));
+    let mut blocks = std::collections::HashMap::<(usize, usize), ndarray::Array1::<f64>>::new();
+    for px in samples.iter(){
+        let (g, dg_dx, dg_dy, dg_dh) = lm::light_falloff(light, h, px.pos);
+        //in noise standard deviations, the same way the joint fit weighs the pixels
+        let sd = noise.sd();
+        let (expected, slope) = noise.expected(a[px.patch] * g);
+        let residual = (expected - px.value) / sd;
+        let d = slope / sd;
+        let row = [(0, d * a[px.patch] * dg_dx), (1, d * a[px.patch] * dg_dy), (2, d * a[px.patch] * dg_dh), (3 + px.patch, d * g), (n - 1, exposure_derivative(expected) / sd)];
+        let block = blocks.entry((px.pos.0 as usize / BLOCK, px.pos.1 as usize / BLOCK)).or_insert(ndarray::Array1::<f64>::zeros(n));
+        for &(i, di) in row.iter(){
+            block[i] += di * residual;
+            for &(j, dj) in row.iter(){
+                jtj[[i, j]] += di * dj;
+            }
+        }
+    }
+    let mut meat = ndarray::Array2::<f64>::zeros((n, n));
+    for g in blocks.values(){
+        for i in 0..n{
+            for j in 0..n{
+                meat[[i, j]] += g[i] * g[j];
+            }
+        }
+    }
+    let bread = invert(&jtj)?;
+    //small sample correction of the sandwich
+    let k = blocks.len() as f64;
+    let cov = bread.dot(&meat).dot(&bread) * (k / (k - 1.0).max(1.0));
+    let sd = |i: usize| cov[[i, i]].max(0.0).sqrt() as f32;
+    return Some(Confidence{
+        location: (sd(0), sd(1)),
+        height: sd(2),
+        albedo: relative_albedo_sigma(&a, &cov, 3),
+        bootstrap: None,
+        condition: condition_number(&jtj.slice(ndarray::s![..n - 1, ..n - 1]).to_owned()).unwrap_or(f64::INFINITY)
+    });
+}
+
+//standard deviations of a_i / a_max by the delta method; the albedos sit in the covariance from first on
+pub fn relative_albedo_sigma(a: &[f64], cov: &ndarray::Array2::<f64>, first: usize) -> Vec<f32>{
+    let m = match (0..a.len()).max_by(|i, j| a[*i].total_cmp(&a[*j])){
+        Some(m) => m,
+        None => return vec!()
+    };
+    return (0..a.len()).map(|i| {
+        if i == m || a[i] <= 0.0{
+            return 0.0;
+        }
+        let (ci, cm) = (first + i, first + m);
+        let var = cov[[ci, ci]] / (a[i] * a[i]) + cov[[cm, cm]] / (a[m] * a[m]) - 2.0 * cov[[ci, cm]] / (a[i] * a[m]);
+        ((a[i] / a[m]) * var.max(0.0).sqrt()) as f32
+    }).collect();
+}
+
+//standard deviation of the voting peak when the cluster fits are drawn again with replacement
+pub fn bootstrap_location(fits: &[ClusterFit], extent: ((f64, f64), (f64, f64)), params: &accumulator::AccumulatorParams) -> Option<(f32, f32)>{
+    if fits.is_empty(){
+        return None;
+    }
+    let mut source = crate::dataset::seeded_source(BOOTSTRAP_SEED);
+    let mut peaks = vec!();
+    for _ in 0..BOOTSTRAP_ROUNDS{
+        let votes: Vec<((f64, f64), f64)> = (0..fits.len()).map(|_| {
+            let f = &fits[(source.read_u64() % fits.len() as u64) as usize];
+            (f.circle.center, f.weight())
+        }).collect();
+        if let Some(peak) = accumulator::find_peak(&votes, extent, params){
+            peaks.push(peak);
+        }
+    }
+    if peaks.len() < 2{
+        return None;
+    }
+    let n = peaks.len() as f64;
+    let mean = (peaks.iter().map(|p| p.0).sum::<f64>() / n, peaks.iter().map(|p| p.1).sum::<f64>() / n);
+    let var = (peaks.iter().map(|p| (p.0 - mean.0).powi(2)).sum::<f64>() / (n - 1.0),
+               peaks.iter().map(|p| (p.1 - mean.1).powi(2)).sum::<f64>() / (n - 1.0));
+    return Some((var.0.sqrt() as f32, var.1.sqrt() as f32));
+}
+
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::circle_fit::Circle;
+    use crate::lm::render;
+
+    static ALBEDO: [f64; 9] = [0.8, 0.4, 0.6, 0.3, 1.0, 0.5, 0.7, 0.2, 0.9];
+
+    fn relative() -> Vec<f32>{
+        return ALBEDO.iter().map(|a| *a as f32).collect();
+    }
+
+    #[test]
+    fn a_noiseless_answer_is_certain(){
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let arr = render(&labels, (400.0, 500.0), 300.0, &ALBEDO, 0.0, 1);
+        let c = single_light(&arr, &labels, &NoiseModel::clean(), (400.0, 500.0), 300.0, &relative()).unwrap();
+        assert!(c.location.0 < 1e-3 && c.location.1 < 1e-3 && c.height < 1e-3, "{:?} {}", c.location, c.height);
+        assert!(c.albedo.iter().all(|a| *a < 1e-5), "{:?}", c.albedo);
+        assert_eq!(c.albedo[4], 0.0);
+    }
+
+    #[test]
+    fn the_intervals_grow_with_the_noise(){
+        //the same noise twice as strong
+        let labels = LabelMap::grid((900, 900), 3, 3);
+        let quiet = render(&labels, (400.0, 500.0), 300.0, &ALBEDO, 0.004, 9);
+        let loud = render(&labels, (400.0, 500.0), 300.0, &ALBEDO, 0.008, 9);
+        let q = single_light(&quiet, &labels, &NoiseModel::init(0.0, 0.004), (400.0, 500.0), 300.0, &relative()).unwrap();
+        let l = single_light(&loud, &labels, &NoiseModel::init(0.0, 0.008), (400.0, 500.0), 300.0, &relative()).unwrap();
+        for (a, b) in [(q.location.0, l.location.0), (q.location.1, l.location.1), (q.height, l.height), (q.albedo[0], l.albedo[0])]{
+            assert!(a > 0.0 && (b / a - 2.0).abs() < 0.1, "{} {}", a, b);
+        }
+        //a few thousand pixels per parameter pin the light down to a fraction of a pixel
+        assert!(q.location.0 < 0.5 && q.location.1 < 0.5, "{:?}", q.location);
+    }
+
+    #[test]
+    fn bootstrap_of_agreeing_fits_has_no_spread(){
+        let fit = |center: (f64, f64)| ClusterFit{circle: Circle{center, radius: 50.0}, rms: 0.3, arc: 6.0, inliers: 100, points: 100};
+        let extent = ((0.0, 0.0), (900.0, 900.0));
+        let params = accumulator::AccumulatorParams::init();
+        let same = bootstrap_location(&[fit((300.0, 300.0)), fit((300.0, 300.0)), fit((300.0, 300.0))], extent, &params).unwrap();
+        assert!(same.0 < 1e-6 && same.1 < 1e-6, "{:?}", same);
+        let spread = bootstrap_location(&[fit((290.0, 300.0)), fit((310.0, 300.0)), fit((300.0, 300.0))], extent, &params).unwrap();
+        assert!(spread.0 > 1.0 && spread.1 < 1.0, "{:?}", spread);
+        assert!(bootstrap_location(&[], extent, &params).is_none());
+        assert_eq!(interval(10.0, 1.0), (8.04, 11.96));
+    }
+}